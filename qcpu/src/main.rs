@@ -2,15 +2,34 @@ mod ppm;
 
 use std::{
     fs::OpenOptions,
-    io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Write},
+    io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    net::TcpListener,
     path::PathBuf,
 };
 
-use clap::{Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use qcpu_simulator::fpu::{
+    lut::{
+        generate, read_table, write_table, FpuTables, Intercept, SlopeRounding, Table, TableParams,
+        DEFAULT_TABLES,
+    },
+    vectors::{vector_inputs, write_vectors},
+    verify::{verify, VerifyConfig, VerifyOp, VERIFY_OPS},
+    FpuModel,
+};
 use qcpu_simulator::v4::{
-    log::{CACHE_HIT_PENALTY, CACHE_MISS_PENALTY, INW_DELAY},
-    SimulatorV4Builder,
+    bp::{PredictorConfig, PredictorStat},
+    gdb::GdbStub,
+    heatmap::Granularity,
+    machine::Machine,
+    memory::CacheConfig,
+    shadow::UninitCheck,
+    syntax::{get_reg_name, OpName},
+    timing::{TimingConfig, TimingStat},
+    trace::{TraceAccess, TraceFilter, TraceReader},
+    RunLimits, SimulatorV4Builder, SimulatorV4HaltKind,
 };
+use qcpu_syntax::ParsingContext;
 
 /// QCPU Utility
 #[derive(Parser, Debug)]
@@ -20,6 +39,35 @@ struct Cmd {
     command: Commands,
 }
 
+/// FSQRT_TABLE and FINV_TABLE files.
+#[derive(Debug, Args)]
+struct TableFiles {
+    /// FSQRT_TABLE as a .mem or .coe file
+    #[clap(long)]
+    fsqrt_table: Option<PathBuf>,
+
+    /// FINV_TABLE as a .mem or .coe file
+    #[clap(long)]
+    finv_table: Option<PathBuf>,
+}
+
+/// Options of the commands that run the v4 simulator.
+#[derive(Debug, Args)]
+struct RunArgs {
+    /// Halt on out-of-bounds memory accesses (slower)
+    #[clap(long)]
+    checked: bool,
+
+    /// FPU model: `hardware`, `native`, or overrides like `native,fdiv=hardware`
+    /// (ops: fadd, fmul, fdiv, fsqrt, ftoi, itof; defaults follow the build features, and
+    /// tables not given by --fsqrt-table or --finv-table are the compiled-in ones)
+    #[clap(long)]
+    fpu: Option<FpuModel>,
+
+    #[command(flatten)]
+    tables: TableFiles,
+}
+
 #[derive(Debug, Subcommand)]
 enum Commands {
     /// A subcommand for assembling RISC-V assembly code to machine code (original ISA)
@@ -47,7 +95,16 @@ enum Commands {
         output: Option<String>,
     },
 
-    Sim {
+    Sim(Box<SimArgs>),
+
+    /// Record or print execution traces of the v4 simulator
+    Trace {
+        #[command(subcommand)]
+        command: TraceCommands,
+    },
+
+    /// Serve the GDB remote protocol for the v4 simulator
+    Gdbserver {
         /// The input file in machine code
         #[arg(short, long)]
         bin: Option<PathBuf>,
@@ -65,17 +122,79 @@ enum Commands {
         #[clap(short, long)]
         log: Option<PathBuf>,
 
-        /// Verbose mode
-        #[clap(short, long, default_value = "false")]
-        verbose: bool,
+        /// TCP port on localhost
+        #[clap(short, long, default_value = "1234")]
+        port: u16,
+
+        #[command(flatten)]
+        run: RunArgs,
+    },
 
-        /// Clock (MHz)
-        #[clap(long, default_value = "125")]
-        clock: f64,
+    /// Check the hardware FPU model against the required error bounds
+    FpuVerify {
+        /// Operations to check: fadd, fmul, fdiv, finv, fsqrt, ftoi, itof (default: all)
+        #[clap(long = "op")]
+        ops: Vec<VerifyOp>,
 
-        /// JSON
+        /// Sweep all 2^32 inputs of the unary operations
         #[clap(long)]
-        json: Option<PathBuf>,
+        exhaustive: bool,
+
+        /// Samples per stratum (sign and exponent, or pair of exponents for binary ops)
+        #[clap(long, default_value = "64")]
+        samples: u32,
+
+        #[clap(long, default_value = "0")]
+        seed: u64,
+
+        /// The JSON report (defaults to stdout)
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+
+    /// Write $readmemh test vectors computed by the hardware FPU model
+    FpuVectors {
+        /// Operations to write: fadd, fmul, fdiv, finv, fsqrt, ftoi, itof (default: all)
+        #[clap(long = "op")]
+        ops: Vec<VerifyOp>,
+
+        /// Write all 2^32 inputs of the unary operations
+        #[clap(long)]
+        exhaustive: bool,
+
+        /// Samples per stratum (sign and exponent, or pair of exponents for binary ops)
+        #[clap(long, default_value = "4")]
+        samples: u32,
+
+        #[clap(long, default_value = "0")]
+        seed: u64,
+
+        /// The directory for <op>_in.mem and <op>_out.mem
+        #[clap(short, long, default_value = ".")]
+        output: PathBuf,
+    },
+
+    /// Generate FSQRT_TABLE/FINV_TABLE from the approximation parameters and report their error
+    /// (written to --fsqrt-table and --finv-table if given)
+    FpuTable {
+        /// Rounding of the FINV_TABLE slopes: floor or nearest
+        #[clap(long, default_value = "floor")]
+        finv_slope: SlopeRounding,
+
+        /// Rounding of the FSQRT_TABLE slopes: floor or nearest
+        #[clap(long, default_value = "nearest")]
+        fsqrt_slope: SlopeRounding,
+
+        /// Placement of the intercepts: minimax or tangent
+        #[clap(long, default_value = "minimax")]
+        intercept: Intercept,
+
+        #[command(flatten)]
+        tables: TableFiles,
+
+        /// The JSON report (defaults to stdout)
+        #[clap(short, long)]
+        output: Option<String>,
     },
 
     Diff {
@@ -93,6 +212,211 @@ enum Commands {
     },
 }
 
+#[derive(Debug, Args)]
+struct SimArgs {
+    /// The input file in machine code
+    #[arg(short, long)]
+    bin: Option<PathBuf>,
+
+    /// The input file in assembly (This will override the bin)
+    #[arg(short, long)]
+    source: Option<PathBuf>,
+
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    #[clap(short, long)]
+    input: Option<PathBuf>,
+
+    #[clap(short, long)]
+    log: Option<PathBuf>,
+
+    /// Verbose mode
+    #[clap(short, long, default_value = "false")]
+    verbose: bool,
+
+    #[command(flatten)]
+    run: RunArgs,
+
+    /// Clock (MHz), overriding the machine description
+    #[clap(long)]
+    clock: Option<f64>,
+
+    /// Machine description JSON with latencies and penalties, e.g.
+    /// `{ "cache_miss_penalty": 80, "latency": { "fdiv": 8 } }`
+    /// (missing keys keep the compiled-in defaults)
+    #[clap(long)]
+    machine: Option<PathBuf>,
+
+    /// Cache geometry and policies for statistics, e.g.
+    /// `sets=4096,line=4,ways=4,policy=lru,write=through,allocate=false`
+    /// (policy: lru, fifo, sc; write: back, through; defaults to the hardware cache)
+    #[clap(long)]
+    cache: Option<CacheConfig>,
+
+    /// Branch predictor for statistics, e.g. `gshare,size=4096,history=12`
+    /// (static, bimodal, gshare, tournament or perceptron; defaults to the hardware's
+    /// `tournament,pht=1024,selector=256,history=10`)
+    #[clap(long)]
+    bp: Option<PredictorConfig>,
+
+    /// Also simulate this branch predictor and log a comparison (repeatable)
+    #[clap(long)]
+    compare_bp: Vec<PredictorConfig>,
+
+    /// Predict returns with a return address stack of this many entries (0 for none)
+    #[clap(long, default_value = "0")]
+    ras: usize,
+
+    /// Run the in-loop timing model for exact cycle counts, optionally configured, e.g.
+    /// `forward=alu+fpu,regfile-delay=2,type-check=false`
+    /// (forward: all, none or alu/load/fpu joined with +; penalties such as
+    /// `branch_penalty` come from --machine; implies --verbose)
+    #[clap(long, num_args = 0..=1, default_missing_value = "")]
+    timing: Option<TimingConfig>,
+
+    /// JSON
+    #[clap(long)]
+    json: Option<PathBuf>,
+
+    /// Stop at a PC (decimal or 0x-prefixed) or a label (requires --source)
+    #[clap(long = "break")]
+    breakpoints: Vec<String>,
+
+    /// Stop on memory access to a word range, e.g. 0x100:0x110 (append :r or :w to filter)
+    #[clap(long)]
+    watch_mem: Vec<String>,
+
+    /// Stop when a register changes, e.g. a0 or fa1
+    #[clap(long)]
+    watch_reg: Vec<String>,
+
+    /// Save a checkpoint to this file at --checkpoint-at and stop
+    #[clap(long, requires = "checkpoint_at")]
+    checkpoint: Option<PathBuf>,
+
+    /// The PC (decimal or 0x-prefixed) or label (requires --source) to checkpoint at
+    #[clap(long, requires = "checkpoint")]
+    checkpoint_at: Option<String>,
+
+    /// Resume from a checkpoint; the output file is truncated to the checkpoint's offset
+    #[clap(long)]
+    restore: Option<PathBuf>,
+
+    /// Stop after this many instructions
+    #[clap(long)]
+    max_instructions: Option<u64>,
+
+    /// Stop once the estimated cycle count reaches this, checked every 65536 instructions
+    /// (enables statistics collection)
+    #[clap(long)]
+    max_cycles: Option<u64>,
+
+    /// Compare every FP instruction against native IEEE f32 and write a per-PC report
+    #[clap(long)]
+    fpu_report: Option<PathBuf>,
+
+    /// Write inclusive and exclusive costs per function, following `jal ra` calls,
+    /// `jal zero` tail calls and `jalr zero, ra` returns (enables the timing model)
+    #[clap(long)]
+    profile: Option<PathBuf>,
+
+    /// Write the call stacks in folded format for flamegraph tools, weighted by cycles
+    /// (enables the timing model)
+    #[clap(long)]
+    flamegraph: Option<PathBuf>,
+
+    /// Write the hottest loops with their trip counts and the hottest basic blocks
+    /// (enables the timing model)
+    #[clap(long)]
+    loops: Option<PathBuf>,
+
+    /// Report loads from memory words that were never stored to: `report` lists them
+    /// in the log after the run, `halt` also stops at the first one
+    #[clap(long, num_args = 0..=1, default_missing_value = "report")]
+    uninit: Option<UninitCheck>,
+
+    /// Halt once the values `sp` and `gp` have taken overlap, as the stack and the heap
+    /// then overwrite each other
+    #[clap(long)]
+    collision_check: bool,
+
+    /// Write read, write and cache miss counts per memory region as CSV (implies
+    /// --verbose)
+    #[clap(long)]
+    heatmap: Option<PathBuf>,
+
+    /// Write the memory heatmap as a PPM image: red for misses, green for reads and
+    /// blue for writes, regions row by row from address 0, each a square block of
+    /// pixels so that the image is at least 512 pixels wide (implies --verbose)
+    #[clap(long)]
+    heatmap_image: Option<PathBuf>,
+
+    /// Heatmap region size: word, line (of the cache) or page (1 KiB)
+    #[clap(long, default_value = "line")]
+    heatmap_granularity: Granularity,
+}
+
+#[derive(Debug, Args)]
+struct RecordArgs {
+    /// The input file in machine code
+    #[arg(short, long)]
+    bin: Option<PathBuf>,
+
+    /// The input file in assembly (This will override the bin)
+    #[arg(short, long)]
+    source: Option<PathBuf>,
+
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+
+    #[clap(short, long)]
+    input: Option<PathBuf>,
+
+    #[clap(short, long)]
+    log: Option<PathBuf>,
+
+    /// The trace file (defaults to <bin>.trace)
+    #[clap(short, long)]
+    trace: Option<PathBuf>,
+
+    /// Only record PCs in START:END (decimal or 0x-prefixed)
+    #[clap(long)]
+    pc: Vec<String>,
+
+    /// Only record instructions from a label up to the next label (requires --source)
+    #[clap(long)]
+    label: Vec<String>,
+
+    /// Only record retired instructions with index in START:END
+    #[clap(long)]
+    window: Option<String>,
+
+    #[command(flatten)]
+    run: RunArgs,
+}
+
+#[derive(Debug, Subcommand)]
+enum TraceCommands {
+    /// Run a program and record each retired instruction
+    Record(Box<RecordArgs>),
+
+    /// Print a trace as text
+    Dump {
+        /// The trace file
+        #[clap(short, long)]
+        trace: PathBuf,
+
+        /// The program source, for label annotations
+        #[clap(short, long)]
+        source: Option<PathBuf>,
+
+        /// The output file
+        #[clap(short, long)]
+        output: Option<String>,
+    },
+}
+
 fn create_writer(path: &Option<String>) -> BufWriter<Box<dyn Write>> {
     match path {
         Some(file) => BufWriter::new(Box::new(
@@ -116,10 +440,385 @@ fn create_reader(path: &Option<String>) -> Box<dyn BufRead> {
     }
 }
 
+/// Assembles `source` next to itself if given, otherwise uses `bin`.
+fn resolve_program(
+    bin: Option<PathBuf>,
+    source: Option<PathBuf>,
+) -> (PathBuf, Option<ParsingContext>) {
+    let Some(source) = source else {
+        return match bin {
+            Some(bin) => (bin, None),
+            None => {
+                eprintln!("No input file provided");
+                std::process::exit(1);
+            }
+        };
+    };
+
+    let asm = std::fs::read_to_string(&source).unwrap();
+
+    let (mc, ctx) = qcpu_assembler::v2::assemble(&asm, false).unwrap();
+
+    let path = source.with_extension("bin");
+
+    let mut output_file = std::fs::File::options()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(&path)
+        .unwrap();
+
+    let mut writer = std::io::BufWriter::new(&mut output_file);
+
+    for mc in mc {
+        writer.write_all(&mc.to_le_bytes()).unwrap();
+    }
+
+    (path, Some(ctx))
+}
+
+/// Loads the FPU tables given on the command line into `fpu`.
+fn resolve_fpu(fpu: Option<FpuModel>, tables: TableFiles) -> FpuModel {
+    let TableFiles {
+        fsqrt_table,
+        finv_table,
+    } = tables;
+    let mut fpu = fpu.unwrap_or_default();
+    if fsqrt_table.is_none() && finv_table.is_none() {
+        return fpu;
+    }
+
+    let load = |path: Option<PathBuf>, default: &Table| match path {
+        Some(path) => read_table(&path).unwrap_or_else(|e| {
+            eprintln!("Error reading table {:?}: {}", path, e);
+            std::process::exit(1);
+        }),
+        None => *default,
+    };
+    fpu.tables = Box::leak(Box::new(FpuTables {
+        fsqrt: load(fsqrt_table, &DEFAULT_TABLES.fsqrt),
+        finv: load(finv_table, &DEFAULT_TABLES.finv),
+    }));
+    fpu
+}
+
+fn parse_range(s: &str) -> Option<(u32, u32)> {
+    let (start, end) = s.split_once(':')?;
+    Some((parse_number(start)?, parse_number(end)?))
+}
+
+fn parse_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let args = Cmd::parse();
 
     match args.command {
+        Commands::Trace {
+            command: TraceCommands::Record(args),
+        } => {
+            let RecordArgs {
+                bin,
+                source,
+                output,
+                input,
+                log,
+                trace,
+                pc,
+                label,
+                window,
+                run:
+                    RunArgs {
+                        checked,
+                        fpu,
+                        tables,
+                    },
+            } = *args;
+            let (bin, ctx) = resolve_program(bin, source);
+            let fpu = resolve_fpu(fpu, tables);
+
+            let mut filter = TraceFilter::default();
+            for range in pc {
+                match parse_range(&range) {
+                    Some((start, end)) => filter.pc_ranges.push(start..end),
+                    None => {
+                        eprintln!("Invalid PC range: {}", range);
+                        std::process::exit(1);
+                    }
+                }
+            }
+            for label in label {
+                if ctx
+                    .as_ref()
+                    .and_then(|ctx| filter.add_label(ctx, &label))
+                    .is_none()
+                {
+                    eprintln!("Unknown label: {}", label);
+                    std::process::exit(1);
+                }
+            }
+            if let Some(window) = window {
+                match parse_range(&window) {
+                    Some((start, end)) => filter.window = Some(start as u64..end as u64),
+                    None => {
+                        eprintln!("Invalid window: {}", window);
+                        std::process::exit(1);
+                    }
+                }
+            }
+
+            let trace = trace.unwrap_or_else(|| bin.with_extension("trace"));
+
+            let mut sim = (SimulatorV4Builder {
+                bin,
+                input,
+                output,
+                log,
+                ctx,
+                checked,
+                fpu,
+                ..Default::default()
+            })
+            .build();
+
+            sim.start_trace(std::fs::File::create(&trace)?, filter)?;
+
+            eprintln!("Simulation Result: {}", sim.run());
+
+            sim.finish_trace()?;
+            println!("Trace written to: {:?}", trace);
+        }
+        Commands::Trace {
+            command:
+                TraceCommands::Dump {
+                    trace,
+                    source,
+                    output,
+                },
+        } => {
+            let labels = match source {
+                Some(source) => {
+                    let asm = std::fs::read_to_string(&source)?;
+                    let (_, ctx) = qcpu_assembler::v2::assemble(&asm, false)
+                        .map_err(|e| format!("Error parsing assembly code: {:?}", e))?;
+                    let mut labels: Vec<(usize, String)> = ctx.label_map.1.into_iter().collect();
+                    labels.sort();
+                    labels
+                }
+                None => Vec::new(),
+            };
+
+            let reader = TraceReader::new(BufReader::new(std::fs::File::open(&trace)?))?;
+            let mut writer = create_writer(&output);
+
+            for record in reader {
+                let record = record?;
+                let line = (record.pc >> 2) as usize;
+
+                write!(writer, "{:>12} {:05x} ", record.index, record.pc)?;
+
+                let label = labels.partition_point(|(i, _)| *i <= line);
+                if label > 0 {
+                    let (start, name) = &labels[label - 1];
+                    write!(writer, "{:>32} ", format!("<{}+{}>", name, line - start))?;
+                }
+
+                write!(writer, "{:28}", record.op.to_string())?;
+
+                match record.op.opname {
+                    OpName::Beq
+                    | OpName::Bne
+                    | OpName::Blt
+                    | OpName::Bge
+                    | OpName::Outb
+                    | OpName::Sw => {}
+                    #[cfg(feature = "full_ops")]
+                    OpName::Swi => {}
+                    _ => write!(
+                        writer,
+                        " {}=0x{:08x}",
+                        get_reg_name(record.op.rd),
+                        record.rd_value
+                    )?,
+                }
+
+                match record.access {
+                    Some(TraceAccess::Read { addr, value }) => {
+                        write!(writer, " [0x{:05x}] -> 0x{:08x}", addr, value)?
+                    }
+                    Some(TraceAccess::Write { addr, value }) => {
+                        write!(writer, " [0x{:05x}] <- 0x{:08x}", addr, value)?
+                    }
+                    Some(TraceAccess::Input(value)) => write!(writer, " in 0x{:08x}", value)?,
+                    Some(TraceAccess::Output(byte)) => write!(writer, " out 0x{:02x}", byte)?,
+                    None => {}
+                }
+
+                writeln!(writer)?;
+            }
+        }
+        Commands::Gdbserver {
+            bin,
+            source,
+            output,
+            input,
+            log,
+            port,
+            run:
+                RunArgs {
+                    checked,
+                    fpu,
+                    tables,
+                },
+        } => {
+            let (bin, ctx) = resolve_program(bin, source);
+            let fpu = resolve_fpu(fpu, tables);
+
+            let mut sim = (SimulatorV4Builder {
+                bin,
+                input,
+                output,
+                log,
+                ctx,
+                checked,
+                fpu,
+                ..Default::default()
+            })
+            .build();
+
+            let listener = TcpListener::bind(("127.0.0.1", port))?;
+            println!("Listening for GDB on {}", listener.local_addr()?);
+
+            let (stream, addr) = listener.accept()?;
+            println!("GDB connected from {}", addr);
+
+            GdbStub::new(&mut sim).serve(stream)?;
+            sim.output.flush()?;
+        }
+        Commands::FpuVerify {
+            ops,
+            exhaustive,
+            samples,
+            seed,
+            output,
+        } => {
+            let ops = if ops.is_empty() {
+                VERIFY_OPS.to_vec()
+            } else {
+                ops
+            };
+            let config = VerifyConfig {
+                exhaustive,
+                samples,
+                seed,
+                ..Default::default()
+            };
+
+            let mut reports = Vec::new();
+            for op in ops {
+                let s = std::time::Instant::now();
+                let report = verify(op, &config);
+                eprintln!(
+                    "{}: {} checked, {} skipped, {} violations, max relative error {:e} ({:?})",
+                    op.name(),
+                    report.checked,
+                    report.skipped,
+                    report.violations,
+                    report.max_rel_error,
+                    s.elapsed()
+                );
+                reports.push(report);
+            }
+
+            let mut writer = create_writer(&output);
+            serde_json::to_writer_pretty(&mut writer, &reports)?;
+            writeln!(writer)?;
+
+            if reports.iter().any(|r| r.violations != 0) {
+                writer.flush()?;
+                std::process::exit(2);
+            }
+        }
+        Commands::FpuVectors {
+            ops,
+            exhaustive,
+            samples,
+            seed,
+            output,
+        } => {
+            let ops = if ops.is_empty() {
+                VERIFY_OPS.to_vec()
+            } else {
+                ops
+            };
+            let config = VerifyConfig {
+                exhaustive,
+                samples,
+                seed,
+                ..Default::default()
+            };
+
+            std::fs::create_dir_all(&output)?;
+            for op in ops {
+                let input = output.join(format!("{}_in.mem", op.name()));
+                let expected = output.join(format!("{}_out.mem", op.name()));
+                let count = write_vectors(
+                    op,
+                    vector_inputs(op, &config),
+                    BufWriter::new(std::fs::File::create(&input)?),
+                    BufWriter::new(std::fs::File::create(&expected)?),
+                )?;
+                println!(
+                    "{}: {} vectors written to {:?} and {:?}",
+                    op.name(),
+                    count,
+                    input,
+                    expected
+                );
+            }
+        }
+        Commands::FpuTable {
+            finv_slope,
+            fsqrt_slope,
+            intercept,
+            tables:
+                TableFiles {
+                    fsqrt_table,
+                    finv_table,
+                },
+            output,
+        } => {
+            let (tables, report) = generate(&TableParams {
+                finv_slope,
+                fsqrt_slope,
+                intercept,
+            });
+            eprintln!(
+                "finv: max {:.3} ulp, max relative error {:e}",
+                report.finv.max_ulp, report.finv.max_rel_error
+            );
+            eprintln!(
+                "fsqrt: max {:.3} ulp, max relative error {:e}",
+                report.fsqrt.max_ulp, report.fsqrt.max_rel_error
+            );
+
+            if let Some(path) = fsqrt_table {
+                write_table(&path, &tables.fsqrt)?;
+                eprintln!("FSQRT_TABLE written to: {:?}", path);
+            }
+            if let Some(path) = finv_table {
+                write_table(&path, &tables.finv)?;
+                eprintln!("FINV_TABLE written to: {:?}", path);
+            }
+
+            let mut writer = create_writer(&output);
+            serde_json::to_writer_pretty(&mut writer, &report)?;
+            writeln!(writer)?;
+        }
         Commands::Diff {
             file1,
             file2,
@@ -253,62 +952,223 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             }
             println!("Done!");
         }
-        Commands::Sim {
-            mut bin,
-            source,
-            input,
-            output,
-            verbose,
-            clock,
-            log,
-            json,
-        } => {
+        Commands::Sim(args) => {
+            let SimArgs {
+                bin,
+                source,
+                input,
+                output,
+                verbose,
+                run:
+                    RunArgs {
+                        checked,
+                        fpu,
+                        tables,
+                    },
+                clock,
+                machine,
+                cache,
+                bp,
+                compare_bp,
+                ras,
+                timing,
+                log,
+                json,
+                breakpoints,
+                watch_mem,
+                watch_reg,
+                checkpoint,
+                checkpoint_at,
+                restore,
+                max_instructions,
+                max_cycles,
+                fpu_report,
+                profile,
+                flamegraph,
+                loops,
+                uninit,
+                collision_check,
+                heatmap,
+                heatmap_image,
+                heatmap_granularity,
+            } = *args;
             let s = std::time::Instant::now();
+            let profiling = profile.is_some() || flamegraph.is_some();
+            let timing =
+                timing.or_else(|| (profiling || loops.is_some()).then(TimingConfig::default));
+            let mapping = heatmap.is_some() || heatmap_image.is_some();
+            let verbose = verbose || timing.is_some() || mapping;
+
+            let (bin, ctx) = resolve_program(bin, source);
+            let fpu = resolve_fpu(fpu, tables);
+
+            let mut machine = machine.map_or_else(Machine::default, |path| {
+                Machine::load(&path).unwrap_or_else(|e| {
+                    eprintln!("Error reading machine description {:?}: {}", path, e);
+                    std::process::exit(1);
+                })
+            });
+            if let Some(clock) = clock {
+                machine.clock_mhz = clock;
+            }
 
-            let mut ctx = None;
-            if let Some(source) = source {
-                let asm = std::fs::read_to_string(&source).unwrap();
-
-                let (mc, _ctx) = qcpu_assembler::v2::assemble(&asm, false).unwrap();
-
-                ctx = Some(_ctx);
-
-                let path = source.with_extension("bin");
-
-                let mut output_file = std::fs::File::options()
-                    .create(true)
-                    .write(true)
-                    .truncate(true)
-                    .open(&path)
-                    .unwrap();
+            let checkpoint_at = checkpoint_at.map(|at| {
+                parse_number(&at)
+                    .or_else(|| {
+                        let ctx = ctx.as_ref()?;
+                        Some((*ctx.label_map.get(&at)? as u32) << 2)
+                    })
+                    .unwrap_or_else(|| {
+                        eprintln!("Unknown checkpoint location: {}", at);
+                        std::process::exit(1);
+                    })
+            });
 
-                let mut writer = std::io::BufWriter::new(&mut output_file);
+            let mut sim = match &restore {
+                Some(restore) => {
+                    let output = output.unwrap_or_else(|| bin.with_extension("ppm"));
+
+                    let mut sim = (SimulatorV4Builder {
+                        bin,
+                        input,
+                        verbose: verbose || max_cycles.is_some(),
+                        cache: cache.unwrap_or_default(),
+                        branch_predictor: bp.unwrap_or_default(),
+                        compare_predictors: compare_bp,
+                        ras_depth: ras,
+                        timing,
+                        machine,
+                        uninit,
+                        collision_check,
+                        checked,
+                        fpu,
+                        log,
+                        ctx: ctx.clone(),
+                        ..Default::default()
+                    })
+                    .with_output(std::io::sink())
+                    .build();
+
+                    sim.restore_checkpoint(BufReader::new(std::fs::File::open(restore)?))?;
+
+                    let mut file = OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .truncate(false)
+                        .open(&output)?;
+                    file.set_len(sim.output_offset)?;
+                    file.seek(SeekFrom::End(0))?;
+
+                    sim.output = BufWriter::new(Box::new(file));
+                    sim.output_file = Some(output);
+                    sim
+                }
+                None => (SimulatorV4Builder {
+                    bin,
+                    input,
+                    output,
+                    verbose: verbose || max_cycles.is_some(),
+                    cache: cache.unwrap_or_default(),
+                    branch_predictor: bp.unwrap_or_default(),
+                    compare_predictors: compare_bp,
+                    ras_depth: ras,
+                    timing,
+                    machine,
+                    uninit,
+                    collision_check,
+                    checked,
+                    fpu,
+                    log,
+                    ctx: ctx.clone(),
+                    ..Default::default()
+                })
+                .build(),
+            };
 
-                for mc in mc {
-                    writer.write_all(&mc.to_le_bytes()).unwrap();
+            for bp in breakpoints {
+                match parse_number(&bp) {
+                    Some(pc) => sim.add_breakpoint(pc),
+                    None => {
+                        if ctx
+                            .as_ref()
+                            .and_then(|ctx| sim.add_label_breakpoint(ctx, &bp))
+                            .is_none()
+                        {
+                            eprintln!("Unknown breakpoint: {}", bp);
+                            std::process::exit(1);
+                        }
+                    }
                 }
+            }
 
-                bin = Some(path);
+            for watch in watch_mem {
+                let mut parts: Vec<&str> = watch.split(':').collect();
+                let (read, write) = match parts.last() {
+                    Some(&"r") => (true, false),
+                    Some(&"w") => (false, true),
+                    _ => (true, true),
+                };
+                if !(read && write) {
+                    parts.pop();
+                }
+                let range = match parts[..] {
+                    [start] => parse_number(start).map(|start| start..start + 1),
+                    [start, end] => parse_number(start)
+                        .zip(parse_number(end))
+                        .map(|(s, e)| s..e),
+                    _ => None,
+                };
+                match range {
+                    Some(range) => {
+                        sim.watch_memory(range.start as usize..range.end as usize, read, write)
+                    }
+                    None => {
+                        eprintln!("Invalid memory watch: {}", watch);
+                        std::process::exit(1);
+                    }
+                }
             }
 
-            if bin.is_none() {
-                eprintln!("No input file provided");
-                std::process::exit(1);
+            for name in watch_reg {
+                match (0..64).find(|&r| get_reg_name(r) == name) {
+                    Some(reg) => sim.watch_register(reg),
+                    None => {
+                        eprintln!("Unknown register: {}", name);
+                        std::process::exit(1);
+                    }
+                }
             }
 
-            let mut sim = (SimulatorV4Builder {
-                bin: bin.unwrap(),
-                input,
-                output,
-                verbose,
-                log,
-            })
-            .build();
+            if fpu_report.is_some() {
+                sim.start_fpu_accuracy();
+            }
+            if profiling {
+                sim.start_call_profile();
+            }
+            if loops.is_some() {
+                sim.start_block_profile();
+            }
+            if mapping {
+                sim.start_heatmap(heatmap_granularity);
+            }
 
             let e = s.elapsed();
-            if let Err(e) = sim.run() {
-                eprintln!("Simulation Result: {:?}", e);
-            }
+            let halt = sim.run_with(RunLimits {
+                max_instructions,
+                max_cycles,
+                until_pc: checkpoint_at,
+            });
+            let exit_code = match (halt.kind, checkpoint) {
+                (SimulatorV4HaltKind::ReachedPc { .. }, Some(checkpoint)) => {
+                    sim.save_checkpoint(BufWriter::new(std::fs::File::create(&checkpoint)?))?;
+                    println!("Checkpoint written to: {:?}", checkpoint);
+                    0
+                }
+                _ => {
+                    eprintln!("Simulation Result: {}", halt);
+                    halt.kind.exit_code()
+                }
+            };
 
             let e2 = s.elapsed();
 
@@ -321,16 +1181,65 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
             println!("Loaded in: {:?}\nSimulated in: {:?}", e, e2);
 
-            println!("Output written to: {:?}", sim.output_file);
-            println!("Log written to: {:?}", sim.log_file);
+            if let Some(output_file) = &sim.output_file {
+                println!("Output written to: {:?}", output_file);
+            }
+            if let Some((fpu_report, accuracy)) = fpu_report.zip(sim.finish_fpu_accuracy()) {
+                let writer = BufWriter::new(std::fs::File::create(&fpu_report)?);
+                accuracy.write_report(writer, ctx.as_ref(), 20)?;
+                println!("FPU report written to: {:?}", fpu_report);
+            }
+            if let Some(call_profile) = sim.finish_call_profile() {
+                if let Some(profile) = profile {
+                    let writer = BufWriter::new(std::fs::File::create(&profile)?);
+                    call_profile.write_report(writer, ctx.as_ref())?;
+                    println!("Profile written to: {:?}", profile);
+                }
+                if let Some(flamegraph) = flamegraph {
+                    let writer = BufWriter::new(std::fs::File::create(&flamegraph)?);
+                    call_profile.write_folded(writer, ctx.as_ref())?;
+                    println!("Folded stacks written to: {:?}", flamegraph);
+                }
+            }
+            if let Some(shadow) = &sim.memory.shadow {
+                shadow.write_report(&mut sim.log, ctx.as_ref())?;
+                writeln!(sim.log)?;
+            }
+            if let Some(map) = sim.finish_heatmap() {
+                if let Some(heatmap) = heatmap {
+                    let writer = BufWriter::new(std::fs::File::create(&heatmap)?);
+                    map.write_csv(writer)?;
+                    println!("Heatmap written to: {:?}", heatmap);
+                }
+                if let Some(heatmap_image) = heatmap_image {
+                    let (width, height, image) = map.image();
+                    ppm::PPMImage {
+                        width,
+                        height,
+                        image,
+                    }
+                    .export(&heatmap_image);
+                    println!("Heatmap image written to: {:?}", heatmap_image);
+                }
+            }
+            let block_profile = sim.finish_block_profile();
+            if let Some((loops, block_profile)) = loops.zip(block_profile.as_ref()) {
+                let writer = BufWriter::new(std::fs::File::create(&loops)?);
+                block_profile.write_report(writer, ctx.as_ref(), 50)?;
+                println!("Loop report written to: {:?}", loops);
+            }
+            if let Some(log_file) = &sim.log_file {
+                println!("Log written to: {:?}", log_file);
+            }
 
             if verbose {
                 sim.tally();
 
                 sim.log_stat()?;
-                sim.time_optimize_info(clock)?;
+                sim.time_optimize_info()?;
 
                 sim.process_stat(ctx.as_ref())?;
+                sim.process_branch_stat(ctx.as_ref())?;
 
                 #[cfg(feature = "conflict_pair")]
                 sim.process_memory_conflict_pc(ctx.as_ref())?;
@@ -345,23 +1254,37 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 
                     let mut writer = std::io::BufWriter::new(&mut file);
 
+                    let branch_predictors = sim.compare_predictors();
                     let json = JsonOutput {
                         data: sim.per_instruction_stat.clone(),
+                        branch: sim.per_branch_stat.clone(),
+                        loops: block_profile
+                            .as_ref()
+                            .map(|p| p.loops().into_iter().cloned().collect()),
                         label: ctx.as_ref().map(|c| c.label_map.0.clone()),
                         program: sim.instructions.clone(),
                         memory: sim.memory.stat,
                         stat: sim.stat,
+                        timing: sim.timing.as_ref().map(|t| t.stat),
+                        pointers: sim.pointers,
+                        uninit_reads: sim.memory.shadow.as_ref().map(|s| s.reads()),
+                        branch_predictors,
                         const_: Constants {
-                            clock_mhz: clock as u64,
-                            cache_hit_penalty: CACHE_HIT_PENALTY,
-                            cache_miss_penalty: CACHE_MISS_PENALTY,
-                            inw_delay: INW_DELAY,
+                            machine: sim.machine,
+                            cache: sim.memory.config,
+                            branch_predictor: sim.bp.config,
+                            ras_depth: ras,
+                            timing,
                         },
                     };
 
                     serde_json::to_writer_pretty(&mut writer, &json)?;
                 }
             }
+
+            sim.output.flush()?;
+            sim.log.flush()?;
+            std::process::exit(exit_code);
         }
     }
     Ok(())
@@ -371,16 +1294,26 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
 struct JsonOutput {
     const_: Constants,
     stat: qcpu_simulator::v4::stat::Statistics,
+    timing: Option<TimingStat>,
+    pointers: qcpu_simulator::v4::pointers::PointerStat,
+    uninit_reads: Option<Vec<qcpu_simulator::v4::shadow::UninitRead>>,
+    branch_predictors: Vec<PredictorStat>,
     memory: qcpu_simulator::v4::memory::CacheStat,
     data: Vec<qcpu_simulator::v4::Instat>,
+    /// Per-instruction like `data`; non-branches are all zero.
+    branch: Vec<qcpu_simulator::v4::BranchStat>,
+    /// Only with `--loops`, hottest first.
+    loops: Option<Vec<qcpu_simulator::v4::blocks::LoopProfile>>,
     label: Option<std::collections::HashMap<String, usize>>,
     program: Vec<qcpu_simulator::v4::syntax::OpV4>,
 }
 
 #[derive(Debug, serde::Serialize)]
 struct Constants {
-    clock_mhz: u64,
-    cache_hit_penalty: u64,
-    cache_miss_penalty: u64,
-    inw_delay: u64,
+    #[serde(flatten)]
+    machine: Machine,
+    cache: CacheConfig,
+    branch_predictor: PredictorConfig,
+    ras_depth: usize,
+    timing: Option<TimingConfig>,
 }
@@ -0,0 +1,48 @@
+//! Times whole runs of the minrt ray tracer at 32x32, the plain loop and the verbose one.
+//! Run with `cargo bench -p qcpu_simulator`; `QCPU_BENCH_RUNS` sets the repetitions.
+
+use std::{
+    fs::File,
+    time::{Duration, Instant},
+};
+
+use qcpu_simulator::v4::{read_program, SimulatorV4Builder, SimulatorV4HaltKind};
+
+fn time_run(program: &[u32], input: &[u8], verbose: bool) -> Duration {
+    let mut sim = SimulatorV4Builder::default()
+        .with_program(program.to_vec())
+        .with_input(input)
+        .verbose(verbose)
+        .build();
+
+    let start = Instant::now();
+    let halt = sim.run();
+    let elapsed = start.elapsed();
+
+    assert_eq!(halt.kind, SimulatorV4HaltKind::Complete);
+    elapsed
+}
+
+fn main() {
+    let data = concat!(env!("CARGO_MANIFEST_DIR"), "/../test_data");
+    let program = read_program(File::open(format!("{}/minrt_32.bin", data)).unwrap()).unwrap();
+    let input = std::fs::read(format!("{}/contest", data)).unwrap();
+    let runs = std::env::var("QCPU_BENCH_RUNS")
+        .ok()
+        .and_then(|r| r.parse().ok())
+        .unwrap_or(5);
+
+    for verbose in [false, true] {
+        let mut times = (0..runs)
+            .map(|_| time_run(&program, &input, verbose))
+            .collect::<Vec<_>>();
+        times.sort();
+        println!(
+            "minrt_32 {:8} min {:>10.3?} median {:>10.3?} ({} runs)",
+            if verbose { "verbose" } else { "plain" },
+            times[0],
+            times[runs / 2],
+            runs
+        );
+    }
+}
@@ -0,0 +1,456 @@
+//! The `FSQRT_TABLE`/`FINV_TABLE` lookup tables: reading and writing them as BRAM
+//! initialization files, and generating them from the approximation parameters.
+
+use std::{
+    fmt::Debug,
+    io::{self, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::{hardware, table};
+
+pub const TABLE_LEN: usize = 1024;
+/// Every entry is `{a, b}` with a 13-bit slope and a 23-bit intercept.
+pub const ENTRY_BITS: u32 = 36;
+
+pub type Table = [u64; TABLE_LEN];
+
+#[derive(Clone, PartialEq, Eq)]
+pub struct FpuTables {
+    pub fsqrt: Table,
+    pub finv: Table,
+}
+
+/// The tables compiled into the simulator.
+pub static DEFAULT_TABLES: FpuTables = FpuTables {
+    fsqrt: table::FSQRT_TABLE,
+    finv: table::FINV_TABLE,
+};
+
+impl Debug for FpuTables {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FpuTables")
+            .field("default", &(self == &DEFAULT_TABLES))
+            .finish_non_exhaustive()
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn to_table(entries: Vec<u64>) -> io::Result<Table> {
+    if let Some(&entry) = entries.iter().find(|&&e| e >> ENTRY_BITS != 0) {
+        return Err(invalid(format!(
+            "Table entry {:#x} is wider than {} bits",
+            entry, ENTRY_BITS
+        )));
+    }
+
+    let len = entries.len();
+    entries.try_into().map_err(|_| {
+        invalid(format!(
+            "Expected {} table entries, found {}",
+            TABLE_LEN, len
+        ))
+    })
+}
+
+/// Reads a table from a `.coe` file, or from a `$readmemb`/`$readmemh` file otherwise.
+pub fn read_table(path: &Path) -> io::Result<Table> {
+    let text = std::fs::read_to_string(path)?;
+    let entries = match path.extension().and_then(|e| e.to_str()) {
+        Some("coe") => parse_coe(&text),
+        _ => parse_mem(&text),
+    }
+    .map_err(invalid)?;
+    to_table(entries)
+}
+
+/// Writes a table as a `.coe` file, or as a `$readmemb` file otherwise.
+pub fn write_table(path: &Path, table: &Table) -> io::Result<()> {
+    let mut w = io::BufWriter::new(std::fs::File::create(path)?);
+    match path.extension().and_then(|e| e.to_str()) {
+        Some("coe") => write_coe(&mut w, table)?,
+        _ => write_mem(&mut w, table)?,
+    }
+    w.flush()
+}
+
+/// Parses `$readmemb`/`$readmemh` data. Values with at least 32 digits that are all `0`
+/// or `1` are read as binary, everything else as hex. `//` comments, `_` separators and
+/// `@<hex address>` directives are supported.
+pub fn parse_mem(text: &str) -> Result<Vec<u64>, String> {
+    let mut entries = Vec::new();
+    let mut addr = 0;
+
+    for line in text.lines() {
+        let line = line.split("//").next().unwrap_or_default();
+        for token in line.split_whitespace() {
+            if let Some(a) = token.strip_prefix('@') {
+                addr = usize::from_str_radix(a, 16)
+                    .map_err(|_| format!("Invalid address: {}", token))?;
+                continue;
+            }
+
+            let digits = token.replace('_', "");
+            let radix = if digits.len() >= 32 && digits.chars().all(|c| c == '0' || c == '1') {
+                2
+            } else {
+                16
+            };
+            let value = u64::from_str_radix(&digits, radix)
+                .map_err(|_| format!("Invalid value: {}", token))?;
+
+            if addr >= entries.len() {
+                entries.resize(addr + 1, 0);
+            }
+            entries[addr] = value;
+            addr += 1;
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Parses a Xilinx `.coe` file with `memory_initialization_radix` 2, 10 or 16.
+pub fn parse_coe(text: &str) -> Result<Vec<u64>, String> {
+    let text: Vec<&str> = text
+        .lines()
+        .filter(|line| !line.trim_start().starts_with(';'))
+        .collect();
+    let text = text.join("\n");
+
+    let mut radix = 10;
+    let mut entries = None;
+
+    for statement in text.split(';') {
+        let Some((key, value)) = statement.split_once('=') else {
+            continue;
+        };
+        match key.trim().to_lowercase().as_str() {
+            "memory_initialization_radix" => {
+                radix = value
+                    .trim()
+                    .parse()
+                    .map_err(|_| format!("Invalid radix: {}", value.trim()))?;
+            }
+            "memory_initialization_vector" => {
+                entries = Some(
+                    value
+                        .split(|c: char| c == ',' || c.is_whitespace())
+                        .filter(|v| !v.is_empty())
+                        .map(|v| {
+                            u64::from_str_radix(v, radix)
+                                .map_err(|_| format!("Invalid value: {}", v))
+                        })
+                        .collect::<Result<Vec<_>, _>>()?,
+                );
+            }
+            _ => {}
+        }
+    }
+
+    entries.ok_or_else(|| "Missing memory_initialization_vector".to_string())
+}
+
+pub fn write_mem(mut w: impl Write, table: &Table) -> io::Result<()> {
+    for &entry in table {
+        writeln!(w, "{:036b}", entry)?;
+    }
+    Ok(())
+}
+
+pub fn write_coe(mut w: impl Write, table: &Table) -> io::Result<()> {
+    writeln!(w, "memory_initialization_radix=2;")?;
+    writeln!(w, "memory_initialization_vector=")?;
+    for (i, &entry) in table.iter().enumerate() {
+        let end = if i + 1 == table.len() { ';' } else { ',' };
+        writeln!(w, "{:036b}{}", entry, end)?;
+    }
+    Ok(())
+}
+
+/// How the slope `a` of each segment, the secant over the segment, is rounded to an
+/// integer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SlopeRounding {
+    Floor,
+    Nearest,
+}
+
+/// How the intercept `b` of each segment is placed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Intercept {
+    /// Halfway between the largest and smallest error over the segment.
+    Minimax,
+    /// On the curve at the segment's center.
+    Tangent,
+}
+
+impl FromStr for SlopeRounding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "floor" => Ok(SlopeRounding::Floor),
+            "nearest" => Ok(SlopeRounding::Nearest),
+            _ => Err(format!("Unknown slope rounding: {}", s)),
+        }
+    }
+}
+
+impl FromStr for Intercept {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minimax" => Ok(Intercept::Minimax),
+            "tangent" => Ok(Intercept::Tangent),
+            _ => Err(format!("Unknown intercept: {}", s)),
+        }
+    }
+}
+
+/// The approximation parameters. The segment layout is fixed by the datapath in
+/// [`hardware`]. The defaults reproduce the slopes of the compiled-in tables; their
+/// intercepts differ by at most a few units in the last place.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TableParams {
+    pub finv_slope: SlopeRounding,
+    pub fsqrt_slope: SlopeRounding,
+    pub intercept: Intercept,
+}
+
+impl Default for TableParams {
+    fn default() -> Self {
+        Self {
+            finv_slope: SlopeRounding::Floor,
+            fsqrt_slope: SlopeRounding::Nearest,
+            intercept: Intercept::Minimax,
+        }
+    }
+}
+
+fn round_slope(rounding: SlopeRounding, a: f64) -> u64 {
+    match rounding {
+        SlopeRounding::Floor => a.floor() as u64,
+        SlopeRounding::Nearest => a.round() as u64,
+    }
+}
+
+impl TableParams {
+    /// `offset(d)` is the curve minus the datapath's truncated `a * d` term.
+    fn intercept(&self, d_len: u64, offset: impl Fn(u64) -> f64) -> u64 {
+        let b = match self.intercept {
+            Intercept::Minimax => {
+                let (min, max) = (0..d_len)
+                    .map(offset)
+                    .fold((f64::MAX, f64::MIN), |(min, max), v| {
+                        (min.min(v), max.max(v))
+                    });
+                (min + max) / 2.0
+            }
+            Intercept::Tangent => offset(d_len / 2),
+        };
+        b.round().max(0.0) as u64 & 0x7FFFFF
+    }
+}
+
+/// `finv_mantissa` computes `b - (a * d >> 12)` over 1024 segments of `[1, 2)` indexed
+/// by `m[22:13]`, approximating the mantissa of `2 / x`.
+pub fn generate_finv(params: &TableParams) -> Table {
+    let mut table = [0; TABLE_LEN];
+
+    table.par_iter_mut().enumerate().for_each(|(i, entry)| {
+        let x0 = 1.0 + i as f64 / 1024.0;
+        let x1 = x0 + 1.0 / 1024.0;
+        let a = round_slope(params.finv_slope, 8192.0 / (x0 * x1)).min(0x1FFF);
+
+        let b = params.intercept(1 << 13, |d| {
+            let x = x0 + d as f64 / (1 << 23) as f64;
+            (2.0 / x - 1.0) * (1 << 23) as f64 + ((a * d) >> 12) as f64
+        });
+
+        *entry = (a << 23) | b;
+    });
+
+    table
+}
+
+/// `fsqrt` computes `b + (a * d >> s)` over 512 segments of `[1, 2)` indexed by
+/// `m[22:14]` for odd exponents (`s = 15`), and 512 segments of `[2, 4)` for even ones
+/// (`s = 14`). The top bit of the 14-bit `a` is implied and not stored.
+pub fn generate_fsqrt(params: &TableParams) -> Table {
+    let mut table = [0; TABLE_LEN];
+
+    table.par_iter_mut().enumerate().for_each(|(i, entry)| {
+        let (scale, shift) = if i >= 512 { (2.0, 14) } else { (1.0, 15) };
+        let x0 = 1.0 + (i % 512) as f64 / 512.0;
+        let x1 = x0 + 1.0 / 512.0;
+        let secant = ((scale * x1).sqrt() - (scale * x0).sqrt()) * 512.0 * (1 << shift) as f64;
+        let a = round_slope(params.fsqrt_slope, secant).clamp(0x2000, 0x3FFF);
+
+        let b = params.intercept(1 << 14, |d| {
+            let x = x0 + d as f64 / (1 << 23) as f64;
+            ((scale * x).sqrt() - 1.0) * (1 << 23) as f64 - ((a * d) >> shift) as f64
+        });
+        // The 23-bit sum must not wrap at the end of the segment.
+        let b = b.min(0x7FFFFF - ((a * 0x3FFF) >> shift));
+
+        *entry = ((a & 0x1FFF) << 23) | b;
+    });
+
+    table
+}
+
+/// The error of a table over every mantissa, against the exact result.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct TableError {
+    /// In units of the last place of the result.
+    pub max_ulp: f64,
+    pub max_rel_error: f64,
+    /// The input with the largest error.
+    pub worst_input: u32,
+}
+
+impl TableError {
+    fn max(self, other: Self) -> Self {
+        if other.max_ulp > self.max_ulp {
+            Self {
+                max_rel_error: self.max_rel_error.max(other.max_rel_error),
+                ..other
+            }
+        } else {
+            Self {
+                max_rel_error: self.max_rel_error.max(other.max_rel_error),
+                ..self
+            }
+        }
+    }
+}
+
+fn f64_from(x: u32) -> f64 {
+    f32::from_bits(x) as f64
+}
+
+/// Measures `1 / x` for every `x` in `[1, 2)`.
+pub fn finv_error(finv: &Table) -> TableError {
+    (0..1u32 << 23)
+        .into_par_iter()
+        .map(|m| {
+            let x = 0x3F800000 | m;
+            let exact = 1.0 / f64_from(x);
+            let y = 0.5 + hardware::finv_mantissa_with(m, finv) as f64 / (1 << 24) as f64;
+            let error = (y - exact).abs();
+            TableError {
+                max_ulp: error * (1 << 24) as f64,
+                max_rel_error: error / exact,
+                worst_input: x,
+            }
+        })
+        .reduce(TableError::default, TableError::max)
+}
+
+/// Measures `sqrt(x)` for every `x` in `[1, 4)`.
+pub fn fsqrt_error(fsqrt: &Table) -> TableError {
+    (0..1u32 << 24)
+        .into_par_iter()
+        .map(|i| {
+            let x = 0x3F800000 + i;
+            let exact = f64_from(x).sqrt();
+            let y = f64_from(hardware::fsqrt_with(x, fsqrt));
+            let error = (y - exact).abs();
+            TableError {
+                max_ulp: error * (1 << 23) as f64,
+                max_rel_error: error / exact,
+                worst_input: x,
+            }
+        })
+        .reduce(TableError::default, TableError::max)
+}
+
+/// The generated tables' errors, with the parameters that produced them.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct TableReport {
+    pub params: TableParams,
+    pub finv: TableError,
+    pub fsqrt: TableError,
+}
+
+/// Generates both tables and measures them.
+pub fn generate(params: &TableParams) -> (FpuTables, TableReport) {
+    let tables = FpuTables {
+        fsqrt: generate_fsqrt(params),
+        finv: generate_finv(params),
+    };
+    let report = TableReport {
+        params: *params,
+        finv: finv_error(&tables.finv),
+        fsqrt: fsqrt_error(&tables.fsqrt),
+    };
+    (tables, report)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn generate_test() {
+        let params = TableParams::default();
+
+        for (generated, compiled, error) in [
+            (
+                generate_finv(&params),
+                table::FINV_TABLE,
+                finv_error as fn(&Table) -> TableError,
+            ),
+            (generate_fsqrt(&params), table::FSQRT_TABLE, fsqrt_error),
+        ] {
+            for (g, c) in generated.iter().zip(&compiled) {
+                assert_eq!(g >> 23, c >> 23);
+                assert!((g & 0x7FFFFF).abs_diff(c & 0x7FFFFF) <= 2);
+            }
+            assert!(error(&generated).max_ulp <= error(&compiled).max_ulp);
+        }
+    }
+
+    #[test]
+    fn table_file_test() {
+        let mut mem = Vec::new();
+        write_mem(&mut mem, &table::FINV_TABLE).unwrap();
+        let mem = String::from_utf8(mem).unwrap();
+        assert_eq!(
+            to_table(parse_mem(&mem).unwrap()).unwrap(),
+            table::FINV_TABLE
+        );
+
+        let mut coe = Vec::new();
+        write_coe(&mut coe, &table::FSQRT_TABLE).unwrap();
+        let coe = format!("; generated\n{}", String::from_utf8(coe).unwrap());
+        assert_eq!(
+            to_table(parse_coe(&coe).unwrap()).unwrap(),
+            table::FSQRT_TABLE
+        );
+
+        let hex: String = table::FINV_TABLE
+            .iter()
+            .map(|e| format!("{:09x} // entry\n", e))
+            .collect();
+        assert_eq!(
+            to_table(parse_mem(&hex).unwrap()).unwrap(),
+            table::FINV_TABLE
+        );
+
+        assert_eq!(parse_mem("@2 1_0 ff").unwrap(), vec![0, 0, 0x10, 0xFF]);
+        assert!(to_table(vec![0; 3]).is_err());
+        assert!(to_table(vec![1 << 40; TABLE_LEN]).is_err());
+    }
+}
@@ -0,0 +1,206 @@
+//! Models of the FPU. [`hardware`] reproduces the RTL bit for bit, [`native`] uses IEEE
+//! `f32`, and [`FpuModel`] picks one of them per operation.
+
+pub mod hardware;
+pub mod lut;
+pub mod native;
+pub mod table;
+pub mod vectors;
+pub mod verify;
+
+use std::{fmt::Display, str::FromStr};
+
+use lut::{FpuTables, DEFAULT_TABLES};
+
+/// How a single FPU operation is computed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FpuImpl {
+    /// Bit-accurate emulation of the hardware unit.
+    Hardware,
+    /// Native IEEE `f32` arithmetic.
+    Native,
+}
+
+impl FpuImpl {
+    fn from_feature(hardware: bool) -> Self {
+        if hardware {
+            FpuImpl::Hardware
+        } else {
+            FpuImpl::Native
+        }
+    }
+}
+
+impl FromStr for FpuImpl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hardware" | "hw" => Ok(FpuImpl::Hardware),
+            "native" | "ieee" => Ok(FpuImpl::Native),
+            _ => Err(format!("Unknown FPU implementation: {}", s)),
+        }
+    }
+}
+
+impl Display for FpuImpl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FpuImpl::Hardware => write!(f, "hardware"),
+            FpuImpl::Native => write!(f, "native"),
+        }
+    }
+}
+
+/// Selects the implementation of each FPU operation. `fsub` follows `fadd` and `fdiv`
+/// covers `finv`. The default follows the `fadd`, `fmul`, ... cargo features.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FpuModel {
+    /// The lookup tables used by the hardware `fdiv` and `fsqrt`.
+    pub tables: &'static FpuTables,
+    pub fadd: FpuImpl,
+    pub fmul: FpuImpl,
+    pub fdiv: FpuImpl,
+    pub fsqrt: FpuImpl,
+    pub ftoi: FpuImpl,
+    pub itof: FpuImpl,
+}
+
+impl Default for FpuModel {
+    fn default() -> Self {
+        Self {
+            tables: &DEFAULT_TABLES,
+            fadd: FpuImpl::from_feature(cfg!(feature = "fadd")),
+            fmul: FpuImpl::from_feature(cfg!(feature = "fmul")),
+            fdiv: FpuImpl::from_feature(cfg!(feature = "fdiv")),
+            fsqrt: FpuImpl::from_feature(cfg!(feature = "fsqrt")),
+            ftoi: FpuImpl::from_feature(cfg!(feature = "ftoi")),
+            itof: FpuImpl::from_feature(cfg!(feature = "itof")),
+        }
+    }
+}
+
+impl FpuModel {
+    pub fn all(imp: FpuImpl) -> Self {
+        Self {
+            tables: &DEFAULT_TABLES,
+            fadd: imp,
+            fmul: imp,
+            fdiv: imp,
+            fsqrt: imp,
+            ftoi: imp,
+            itof: imp,
+        }
+    }
+
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut FpuImpl> {
+        match name {
+            "fadd" | "fsub" => Some(&mut self.fadd),
+            "fmul" => Some(&mut self.fmul),
+            "fdiv" | "finv" => Some(&mut self.fdiv),
+            "fsqrt" => Some(&mut self.fsqrt),
+            "ftoi" => Some(&mut self.ftoi),
+            "itof" => Some(&mut self.itof),
+            _ => None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn fadd(&self, x1: u32, x2: u32) -> u32 {
+        match self.fadd {
+            FpuImpl::Hardware => hardware::fadd(x1, x2),
+            FpuImpl::Native => native::fadd(x1, x2),
+        }
+    }
+
+    #[inline(always)]
+    pub fn fsub(&self, x1: u32, x2: u32) -> u32 {
+        match self.fadd {
+            FpuImpl::Hardware => hardware::fsub(x1, x2),
+            FpuImpl::Native => native::fsub(x1, x2),
+        }
+    }
+
+    #[inline(always)]
+    pub fn fmul(&self, x1: u32, x2: u32) -> u32 {
+        match self.fmul {
+            FpuImpl::Hardware => hardware::fmul(x1, x2),
+            FpuImpl::Native => native::fmul(x1, x2),
+        }
+    }
+
+    #[inline(always)]
+    pub fn fdiv(&self, x1: u32, x2: u32) -> u32 {
+        match self.fdiv {
+            FpuImpl::Hardware => hardware::fdiv_with(x1, x2, &self.tables.finv),
+            FpuImpl::Native => native::fdiv(x1, x2),
+        }
+    }
+
+    #[inline(always)]
+    pub fn finv(&self, x: u32) -> u32 {
+        match self.fdiv {
+            FpuImpl::Hardware => hardware::fdiv_with(0x3F800000, x, &self.tables.finv),
+            FpuImpl::Native => native::finv(x),
+        }
+    }
+
+    #[inline(always)]
+    pub fn fsqrt(&self, x: u32) -> u32 {
+        match self.fsqrt {
+            FpuImpl::Hardware => hardware::fsqrt_with(x, &self.tables.fsqrt),
+            FpuImpl::Native => native::fsqrt(x),
+        }
+    }
+
+    #[inline(always)]
+    pub fn ftoi(&self, x: u32) -> u32 {
+        match self.ftoi {
+            FpuImpl::Hardware => hardware::ftoi(x),
+            FpuImpl::Native => native::ftoi(x),
+        }
+    }
+
+    #[inline(always)]
+    pub fn itof(&self, x: u32) -> u32 {
+        match self.itof {
+            FpuImpl::Hardware => hardware::itof(x),
+            FpuImpl::Native => native::itof(x),
+        }
+    }
+}
+
+/// Parses `hardware`, `native` or a comma-separated list of `<op>=<impl>` overrides,
+/// optionally starting with one of the former, e.g. `native,fdiv=hardware`. Operations
+/// that are not mentioned keep their default.
+impl FromStr for FpuModel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut model = FpuModel::default();
+
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            match part.split_once('=') {
+                Some((name, imp)) => {
+                    *model
+                        .get_mut(name.trim())
+                        .ok_or_else(|| format!("Unknown FPU operation: {}", name))? =
+                        imp.trim().parse()?;
+                }
+                None => model = FpuModel::all(part.parse()?),
+            }
+        }
+
+        Ok(model)
+    }
+}
+
+impl Display for FpuModel {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "fadd={},fmul={},fdiv={},fsqrt={},ftoi={},itof={}",
+            self.fadd, self.fmul, self.fdiv, self.fsqrt, self.ftoi, self.itof
+        )
+    }
+}
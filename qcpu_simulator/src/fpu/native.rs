@@ -0,0 +1,51 @@
+//! IEEE `f32` counterparts of [`super::hardware`].
+
+#[inline(always)]
+fn f32_from(x: u32) -> f32 {
+    f32::from_bits(x)
+}
+
+#[inline(always)]
+fn f32_to(x: f32) -> u32 {
+    f32::to_bits(x)
+}
+
+#[inline(always)]
+pub fn fadd(x1: u32, x2: u32) -> u32 {
+    f32_to(f32_from(x1) + f32_from(x2))
+}
+
+#[inline(always)]
+pub fn fsub(x1: u32, x2: u32) -> u32 {
+    f32_to(f32_from(x1) - f32_from(x2))
+}
+
+#[inline(always)]
+pub fn fmul(x1: u32, x2: u32) -> u32 {
+    f32_to(f32_from(x1) * f32_from(x2))
+}
+
+#[inline(always)]
+pub fn fdiv(x1: u32, x2: u32) -> u32 {
+    f32_to(f32_from(x1) / f32_from(x2))
+}
+
+#[inline(always)]
+pub fn finv(x: u32) -> u32 {
+    f32_to(1.0 / f32_from(x))
+}
+
+#[inline(always)]
+pub fn fsqrt(x: u32) -> u32 {
+    f32_to(f32_from(x).sqrt())
+}
+
+#[inline(always)]
+pub fn ftoi(x: u32) -> u32 {
+    f32_from(x).round() as i32 as u32
+}
+
+#[inline(always)]
+pub fn itof(x: u32) -> u32 {
+    f32_to(x as i32 as f32)
+}
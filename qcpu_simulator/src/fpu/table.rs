@@ -1,5 +1,3 @@
-#![allow(dead_code)]
-
 pub const FSQRT_TABLE: [u64; 1024] = [
     0b1111_1111_1100_0000_0000_0000_0000_0000_0000,
     0b1111_1111_0100_0000_0000_0001_1111_1111_1101,
@@ -0,0 +1,96 @@
+//! Test vectors for the RTL testbenches, in the format read by `$readmemh`.
+
+use std::io::{self, Write};
+
+use super::{
+    verify::{special_inputs, stratum_samples, VerifyConfig, VerifyOp},
+    FpuImpl, FpuModel,
+};
+
+/// Writes one line per input to `input` and the hardware model's result for it to
+/// `expected`. Binary operations put both operands on one 64-bit line, `{x1, x2}`.
+/// Returns the number of vectors.
+pub fn write_vectors(
+    op: VerifyOp,
+    inputs: impl IntoIterator<Item = (u32, u32)>,
+    mut input: impl Write,
+    mut expected: impl Write,
+) -> io::Result<u64> {
+    let fpu = FpuModel::all(FpuImpl::Hardware);
+
+    if op.is_binary() {
+        writeln!(input, "// {} {{x1, x2}}", op.name())?;
+    } else {
+        writeln!(input, "// {} x", op.name())?;
+    }
+    writeln!(expected, "// {} y", op.name())?;
+
+    let mut count = 0;
+    for (x1, x2) in inputs {
+        if op.is_binary() {
+            writeln!(input, "{:08x}{:08x}", x1, x2)?;
+        } else {
+            writeln!(input, "{:08x}", x1)?;
+        }
+        writeln!(expected, "{:08x}", op.eval(&fpu, x1, x2))?;
+        count += 1;
+    }
+
+    input.flush()?;
+    expected.flush()?;
+    Ok(count)
+}
+
+/// The special values followed by every stratum sample from `config`, or by all 2^32
+/// inputs of a unary operation when `config.exhaustive` is set.
+pub fn vector_inputs(op: VerifyOp, config: &VerifyConfig) -> Box<dyn Iterator<Item = (u32, u32)>> {
+    let special = special_inputs(op).into_iter();
+
+    if config.exhaustive && !op.is_binary() {
+        return Box::new(special.chain((0..=u32::MAX).map(|x| (x, 0))));
+    }
+
+    let config = *config;
+    Box::new(
+        special
+            .chain((0..op.strata()).flat_map(move |stratum| stratum_samples(op, &config, stratum))),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vectors_test() {
+        let config = VerifyConfig {
+            samples: 2,
+            ..Default::default()
+        };
+
+        let (mut input, mut expected) = (Vec::new(), Vec::new());
+        let count = write_vectors(
+            VerifyOp::Fmul,
+            vector_inputs(VerifyOp::Fmul, &config),
+            &mut input,
+            &mut expected,
+        )
+        .unwrap();
+        assert_eq!(count, 12 * 12 + 2 * (1 << 16));
+
+        let input = String::from_utf8(input).unwrap();
+        let expected = String::from_utf8(expected).unwrap();
+        let (input, expected): (Vec<_>, Vec<_>) = (
+            input.lines().skip(1).collect(),
+            expected.lines().skip(1).collect(),
+        );
+        assert_eq!(input.len() as u64, count);
+        assert_eq!(expected.len() as u64, count);
+
+        for (x, y) in input.iter().zip(&expected) {
+            let x = u64::from_str_radix(x, 16).unwrap();
+            let y = u32::from_str_radix(y, 16).unwrap();
+            assert_eq!(super::super::hardware::fmul((x >> 32) as u32, x as u32), y);
+        }
+    }
+}
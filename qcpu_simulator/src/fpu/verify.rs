@@ -0,0 +1,500 @@
+use rayon::prelude::*;
+use serde::Serialize;
+
+use super::{FpuImpl, FpuModel};
+
+/// Operations covered by [`verify`]. `finv` is `fdiv` with a dividend of `1.0`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum VerifyOp {
+    Fadd,
+    Fmul,
+    Fdiv,
+    Finv,
+    Fsqrt,
+    Ftoi,
+    Itof,
+}
+
+pub const VERIFY_OPS: [VerifyOp; 7] = [
+    VerifyOp::Fadd,
+    VerifyOp::Fmul,
+    VerifyOp::Fdiv,
+    VerifyOp::Finv,
+    VerifyOp::Fsqrt,
+    VerifyOp::Ftoi,
+    VerifyOp::Itof,
+];
+
+impl std::str::FromStr for VerifyOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        VERIFY_OPS
+            .into_iter()
+            .find(|op| op.name() == s)
+            .ok_or_else(|| format!("Unknown FPU operation: {}", s))
+    }
+}
+
+impl VerifyOp {
+    pub fn name(&self) -> &'static str {
+        match self {
+            VerifyOp::Fadd => "fadd",
+            VerifyOp::Fmul => "fmul",
+            VerifyOp::Fdiv => "fdiv",
+            VerifyOp::Finv => "finv",
+            VerifyOp::Fsqrt => "fsqrt",
+            VerifyOp::Ftoi => "ftoi",
+            VerifyOp::Itof => "itof",
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        matches!(self, VerifyOp::Fadd | VerifyOp::Fmul | VerifyOp::Fdiv)
+    }
+
+    /// The required error bound, as written in the FPU specification.
+    pub fn bound(&self) -> &'static str {
+        match self {
+            VerifyOp::Fadd => {
+                "|y - (x1 + x2)| < max(|x1| 2^-23, |x2| 2^-23, |x1 + x2| 2^-23, 2^-126)"
+            }
+            VerifyOp::Fmul => "|y - x1 x2| < max(|x1 x2| 2^-22, 2^-126)",
+            VerifyOp::Fdiv => "|y - x1 / x2| < max(|x1 / x2| 2^-20, 2^-126)",
+            VerifyOp::Finv => "|y - 1 / x| < max(|1 / x| 2^-20, 2^-126)",
+            VerifyOp::Fsqrt => "|y - sqrt(x)| < max(|sqrt(x)| 2^-20, 2^-126)",
+            VerifyOp::Ftoi => "|y - x| <= 1/2 for |x| < 2^31",
+            VerifyOp::Itof => "|y - x| < max(|x| 2^-23, 1/2)",
+        }
+    }
+
+    /// The number of strata [`stratum_samples`] splits the inputs into.
+    pub fn strata(&self) -> u64 {
+        match self {
+            VerifyOp::Itof => 64,
+            _ if self.is_binary() => 1 << 16,
+            _ => 512,
+        }
+    }
+
+    pub fn eval(&self, fpu: &FpuModel, x1: u32, x2: u32) -> u32 {
+        match self {
+            VerifyOp::Fadd => fpu.fadd(x1, x2),
+            VerifyOp::Fmul => fpu.fmul(x1, x2),
+            VerifyOp::Fdiv => fpu.fdiv(x1, x2),
+            VerifyOp::Finv => fpu.finv(x1),
+            VerifyOp::Fsqrt => fpu.fsqrt(x1),
+            VerifyOp::Ftoi => fpu.ftoi(x1),
+            VerifyOp::Itof => fpu.itof(x1),
+        }
+    }
+
+    /// Returns the error and the allowed error of `y`, or `None` when the inputs or the
+    /// exact result are outside the range the bound applies to (zero, subnormal, infinite
+    /// or NaN operands, results that overflow or underflow).
+    fn check(&self, x1: u32, x2: u32, y: u32) -> Option<(f64, f64)> {
+        const MIN_NORMAL: f64 = f32::MIN_POSITIVE as f64;
+        const LIMIT: f64 = 3.402823669209385e38; // 2^128
+
+        let f = |x: u32| f32::from_bits(x) as f64;
+        let (a, b, yf) = (f(x1), f(x2), f(y));
+
+        let exact = match self {
+            VerifyOp::Ftoi => {
+                if a.is_nan() || a.abs() >= 2147483648.0 {
+                    return None;
+                }
+                return Some(((y as i32 as f64 - a).abs(), 0.5));
+            }
+            VerifyOp::Itof => {
+                let x = x1 as i32 as f64;
+                return Some(((yf - x).abs(), (x.abs() * 2f64.powi(-23)).max(0.5)));
+            }
+            _ if !is_normal(x1) => return None,
+            _ if self.is_binary() && !is_normal(x2) => return None,
+            VerifyOp::Fadd => a + b,
+            VerifyOp::Fmul => a * b,
+            VerifyOp::Fdiv => a / b,
+            VerifyOp::Finv => 1.0 / a,
+            VerifyOp::Fsqrt if a < 0.0 => return None,
+            VerifyOp::Fsqrt => a.sqrt(),
+        };
+
+        if exact.abs() >= LIMIT || (exact != 0.0 && exact.abs() < MIN_NORMAL) {
+            return None;
+        }
+
+        let allowed = match self {
+            VerifyOp::Fadd => a.abs().max(b.abs()).max(exact.abs()) * 2f64.powi(-23),
+            VerifyOp::Fmul => exact.abs() * 2f64.powi(-22),
+            _ => exact.abs() * 2f64.powi(-20),
+        }
+        .max(MIN_NORMAL);
+
+        Some(((yf - exact).abs(), allowed))
+    }
+}
+
+const ONE: u32 = 0x3F800000;
+
+fn is_normal(x: u32) -> bool {
+    let e = (x >> 23) & 0xFF;
+    e != 0 && e != 255
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct VerifyConfig {
+    /// Sweep all 2^32 inputs of unary operations instead of sampling.
+    pub exhaustive: bool,
+    /// Samples per stratum. Unary operations have one stratum per sign and exponent,
+    /// binary operations one per pair of exponents.
+    pub samples: u32,
+    pub seed: u64,
+    /// Violations kept in the report, besides the worst one.
+    pub max_examples: usize,
+}
+
+impl Default for VerifyConfig {
+    fn default() -> Self {
+        Self {
+            exhaustive: false,
+            samples: 64,
+            seed: 0,
+            max_examples: 16,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct VerifyCase {
+    pub x1: u32,
+    pub x2: u32,
+    pub y: u32,
+    pub error: f64,
+    pub allowed: f64,
+    pub rel_error: f64,
+}
+
+impl VerifyCase {
+    /// `error / allowed`; the bound is violated from `1.0` on.
+    pub fn ratio(&self) -> f64 {
+        self.error / self.allowed
+    }
+}
+
+/// How the hardware model treats an operand outside the checked range, next to IEEE.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct SpecialCase {
+    pub x1: u32,
+    pub x2: u32,
+    pub hardware: u32,
+    pub native: u32,
+    pub hardware_class: &'static str,
+    pub native_class: &'static str,
+    /// Both results fall into the same class with the same sign.
+    pub consistent: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub op: VerifyOp,
+    pub bound: &'static str,
+    pub exhaustive: bool,
+    pub tested: u64,
+    /// Inputs the bound applies to; the rest are counted as `skipped`.
+    pub checked: u64,
+    pub skipped: u64,
+    pub violations: u64,
+    pub max_rel_error: f64,
+    /// The case with the largest `error / allowed`.
+    pub worst: Option<VerifyCase>,
+    pub examples: Vec<VerifyCase>,
+    pub special: Vec<SpecialCase>,
+}
+
+#[derive(Debug, Default, Clone)]
+struct Tally {
+    tested: u64,
+    checked: u64,
+    violations: u64,
+    max_rel_error: f64,
+    worst: Option<VerifyCase>,
+    examples: Vec<VerifyCase>,
+}
+
+impl Tally {
+    fn add(mut self, op: VerifyOp, fpu: &FpuModel, x1: u32, x2: u32, max_examples: usize) -> Self {
+        self.tested += 1;
+
+        let y = op.eval(fpu, x1, x2);
+        let Some((error, allowed)) = op.check(x1, x2, y) else {
+            return self;
+        };
+
+        self.checked += 1;
+
+        let case = VerifyCase {
+            x1,
+            x2,
+            y,
+            error,
+            allowed,
+            rel_error: relative_error(op, x1, x2, error),
+        };
+        self.max_rel_error = self.max_rel_error.max(case.rel_error);
+
+        if self.worst.is_none_or(|worst| case.ratio() > worst.ratio()) {
+            self.worst = Some(case);
+        }
+
+        let violation = match op {
+            VerifyOp::Ftoi => error > allowed,
+            _ => error >= allowed,
+        };
+        if violation {
+            self.violations += 1;
+            if self.examples.len() < max_examples {
+                self.examples.push(case);
+            }
+        }
+
+        self
+    }
+
+    fn merge(mut self, other: Self, max_examples: usize) -> Self {
+        self.tested += other.tested;
+        self.checked += other.checked;
+        self.violations += other.violations;
+        self.max_rel_error = self.max_rel_error.max(other.max_rel_error);
+        if let Some(worst) = other.worst {
+            if self.worst.is_none_or(|w| worst.ratio() > w.ratio()) {
+                self.worst = Some(worst);
+            }
+        }
+        self.examples.extend(other.examples);
+        self.examples.sort_by_key(|c| (c.x1, c.x2));
+        self.examples.truncate(max_examples);
+        self
+    }
+}
+
+fn relative_error(op: VerifyOp, x1: u32, x2: u32, error: f64) -> f64 {
+    let f = |x: u32| f32::from_bits(x) as f64;
+    let exact = match op {
+        VerifyOp::Fadd => f(x1) + f(x2),
+        VerifyOp::Fmul => f(x1) * f(x2),
+        VerifyOp::Fdiv => f(x1) / f(x2),
+        VerifyOp::Finv => 1.0 / f(x1),
+        VerifyOp::Fsqrt => f(x1).sqrt(),
+        VerifyOp::Ftoi => f(x1).round(),
+        VerifyOp::Itof => x1 as i32 as f64,
+    };
+    if exact == 0.0 {
+        error
+    } else {
+        error / exact.abs()
+    }
+}
+
+/// SplitMix64, so that every stratum can be sampled independently and reproducibly.
+fn mix(mut z: u64) -> u64 {
+    z = z.wrapping_add(0x9E3779B97F4A7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+/// The `i`-th sample of a stratum: the first two are the smallest and largest mantissa,
+/// the rest are random.
+fn sample_mantissa(seed: u64, stratum: u64, i: u32) -> u32 {
+    match i {
+        0 => 0,
+        1 => 0x7FFFFF,
+        _ => mix(seed ^ (stratum << 32) ^ i as u64) as u32 & 0x7FFFFF,
+    }
+}
+
+/// The samples of one stratum, `config.samples` of them. Floats are stratified by sign
+/// and exponent (by pair of exponents for binary operations), integers by sign and bit
+/// length.
+pub fn stratum_samples(
+    op: VerifyOp,
+    config: &VerifyConfig,
+    stratum: u64,
+) -> impl Iterator<Item = (u32, u32)> {
+    let seed = config.seed;
+
+    (0..config.samples.max(1)).map(move |i| {
+        if op == VerifyOp::Itof {
+            let (negative, bits) = (stratum >= 32, (stratum % 32) as u32);
+            let m = mix(seed ^ (stratum << 32) ^ i as u64) as u32;
+            let x = if bits == 0 {
+                0
+            } else {
+                (1 << (bits - 1)) | (m & ((1 << (bits - 1)) - 1))
+            };
+            ((if negative { x.wrapping_neg() } else { x }), 0)
+        } else if op.is_binary() {
+            let (e1, e2) = ((stratum >> 8) as u32, (stratum & 0xFF) as u32);
+            let signs = mix(seed ^ !stratum ^ ((i as u64) << 40)) as u32;
+            let x1 = ((signs & 1) << 31) | (e1 << 23) | sample_mantissa(seed, stratum, i);
+            let x2 = ((signs & 2) << 30)
+                | (e2 << 23)
+                | sample_mantissa(seed.rotate_left(17), stratum, i);
+            (x1, x2)
+        } else {
+            (
+                ((stratum as u32) << 23) | sample_mantissa(seed, stratum, i),
+                0,
+            )
+        }
+    })
+}
+
+/// Sweeps `op` of the hardware FPU model and checks every result against its bound.
+pub fn verify(op: VerifyOp, config: &VerifyConfig) -> VerifyReport {
+    let fpu = FpuModel::all(FpuImpl::Hardware);
+    let max_examples = config.max_examples;
+
+    let exhaustive = config.exhaustive && !op.is_binary();
+
+    let tally = if exhaustive {
+        (0..=u32::MAX)
+            .into_par_iter()
+            .fold(Tally::default, |t, x| t.add(op, &fpu, x, 0, max_examples))
+            .reduce(Tally::default, |a, b| a.merge(b, max_examples))
+    } else {
+        (0..op.strata())
+            .into_par_iter()
+            .fold(Tally::default, |t, stratum| {
+                stratum_samples(op, config, stratum)
+                    .fold(t, |t, (x1, x2)| t.add(op, &fpu, x1, x2, max_examples))
+            })
+            .reduce(Tally::default, |a, b| a.merge(b, max_examples))
+    };
+
+    VerifyReport {
+        op,
+        bound: op.bound(),
+        exhaustive,
+        tested: tally.tested,
+        checked: tally.checked,
+        skipped: tally.tested - tally.checked,
+        violations: tally.violations,
+        max_rel_error: tally.max_rel_error,
+        worst: tally.worst,
+        examples: tally.examples,
+        special: special_cases(op),
+    }
+}
+
+const SPECIAL_FLOATS: [u32; 12] = [
+    0x00000000, // +0
+    0x80000000, // -0
+    0x00000001, // smallest positive subnormal
+    0x807FFFFF, // negative subnormal of the largest magnitude
+    0x00800000, // smallest positive normal
+    0x7F7FFFFF, // largest finite
+    0xFF7FFFFF, // most negative finite
+    0x7F800000, // +inf
+    0xFF800000, // -inf
+    0x7FC00000, // quiet NaN
+    ONE,        // 1
+    0xBF800000, // -1
+];
+
+const SPECIAL_INTS: [u32; 7] = [
+    0,
+    1,
+    0xFFFFFFFF,
+    0x7FFFFFFF,
+    0x80000000,
+    (1 << 24) + 1,
+    0xFEFFFFFF,
+];
+
+fn class(x: u32) -> &'static str {
+    let f = f32::from_bits(x);
+    match (f.classify(), f.is_sign_negative()) {
+        (std::num::FpCategory::Nan, _) => "nan",
+        (std::num::FpCategory::Infinite, false) => "+inf",
+        (std::num::FpCategory::Infinite, true) => "-inf",
+        (std::num::FpCategory::Zero, false) => "+zero",
+        (std::num::FpCategory::Zero, true) => "-zero",
+        (std::num::FpCategory::Subnormal, false) => "+subnormal",
+        (std::num::FpCategory::Subnormal, true) => "-subnormal",
+        (std::num::FpCategory::Normal, false) => "+normal",
+        (std::num::FpCategory::Normal, true) => "-normal",
+    }
+}
+
+/// Zeros, subnormals, extremes, infinities and NaN, or extreme integers for `itof`.
+/// Binary operations get every pair.
+pub fn special_inputs(op: VerifyOp) -> Vec<(u32, u32)> {
+    match op {
+        VerifyOp::Itof => SPECIAL_INTS.iter().map(|&x| (x, 0)).collect(),
+        _ if op.is_binary() => SPECIAL_FLOATS
+            .iter()
+            .flat_map(|&x1| SPECIAL_FLOATS.iter().map(move |&x2| (x1, x2)))
+            .collect(),
+        _ => SPECIAL_FLOATS.iter().map(|&x| (x, 0)).collect(),
+    }
+}
+
+fn special_cases(op: VerifyOp) -> Vec<SpecialCase> {
+    let hardware = FpuModel::all(FpuImpl::Hardware);
+    let native = FpuModel::all(FpuImpl::Native);
+
+    special_inputs(op)
+        .into_iter()
+        .map(|(x1, x2)| {
+            let (h, n) = (op.eval(&hardware, x1, x2), op.eval(&native, x1, x2));
+            let (hardware_class, native_class) = match op {
+                VerifyOp::Ftoi => ("int", "int"),
+                _ => (class(h), class(n)),
+            };
+            SpecialCase {
+                x1,
+                x2,
+                hardware: h,
+                native: n,
+                hardware_class,
+                native_class,
+                consistent: match op {
+                    VerifyOp::Ftoi => h == n,
+                    _ => hardware_class == native_class,
+                },
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verify_test() {
+        let config = VerifyConfig {
+            samples: 4,
+            ..Default::default()
+        };
+
+        for op in VERIFY_OPS {
+            let report = verify(op, &config);
+            assert!(report.checked > 0, "{:?}", op);
+            assert_eq!(report.tested, report.checked + report.skipped);
+            assert!(report.examples.len() as u64 <= report.violations);
+            assert!(!report.special.is_empty());
+        }
+
+        assert_eq!(
+            VerifyOp::Finv.check(ONE, 0, ONE),
+            Some((0.0, 2f64.powi(-20)))
+        );
+        assert_eq!(VerifyOp::Fsqrt.check(0xBF800000, 0, 0), None);
+        assert_eq!(VerifyOp::Fadd.check(0x7F800000, ONE, 0), None);
+        assert_eq!(VerifyOp::Ftoi.check(0x3FC00000, 0, 2), Some((0.5, 0.5)));
+    }
+}
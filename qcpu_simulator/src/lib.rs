@@ -1,11 +1,12 @@
 mod execute;
+pub mod fpu;
 pub mod reg;
 mod result;
 pub mod snapshot;
 pub mod v2;
 pub mod v4;
 
-use std::{collections::VecDeque, io::Read as _};
+use std::collections::VecDeque;
 
 use qcpu_syntax::{
     parser::{Op, ParsingContext},
@@ -122,12 +123,8 @@ impl Simulator {
         self.config = config;
         self.ctx.memory = vec![0; self.config.memory_size];
 
-        if self.config.input.is_some() {
-            self.ctx.in_buffer = std::fs::File::open(self.config.input.as_ref().unwrap())
-                .unwrap()
-                .bytes()
-                .map(|x| x.unwrap())
-                .collect();
+        if let Some(input) = self.config.input.as_ref() {
+            self.ctx.in_buffer = std::fs::read(input).unwrap().into();
         }
 
         self
@@ -134,11 +134,11 @@ impl Debug for Execute {
             } else {
                 writeln!(f, "✅ 0x{:05x}", self.predicted_pc)?;
             }
-            if self.memory_access_request.is_some() {
-                write!(f, "{:?}", self.memory_access_request.as_ref().unwrap())?;
+            if let Some(req) = self.memory_access_request.as_ref() {
+                write!(f, "{:?}", req)?;
             }
-            if self.register_write_back_request.is_some() {
-                write!(f, "{:?}", self.register_write_back_request.unwrap())?;
+            if let Some(wb) = self.register_write_back_request {
+                write!(f, "{:?}", wb)?;
             }
             writeln!(f, "{:#?}", self.intr.as_ref().unwrap())?;
         } else {
@@ -182,14 +182,14 @@ pub struct MemoryAccess {
 
 impl Debug for MemoryAccess {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        if self.wb.is_some() {
-            write!(f, "{:?}", self.wb.unwrap())?;
+        if let Some(wb) = self.wb {
+            write!(f, "{:?}", wb)?;
         }
         for (addr, _, val) in self.memory_transition.iter() {
             writeln!(f, "{:05x} → {:04x}", addr, val)?;
         }
-        if self.req.is_some() {
-            writeln!(f, "{:?}", self.req.as_ref().unwrap())?;
+        if let Some(req) = self.req.as_ref() {
+            writeln!(f, "{:?}", req)?;
         }
         if !self.memory_transition.is_empty() {
             writeln!(
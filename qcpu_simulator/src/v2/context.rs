@@ -149,11 +149,11 @@ impl Display for Stat {
             .map(|(k, v)| (OpName::from_repr(k).unwrap(), v))
             .collect::<Vec<_>>();
 
-        iccp.sort_by(|a, b| a.0.optype.cmp(&b.0.optype));
+        iccp.sort_by_key(|a| a.0.optype);
         for (op, count) in iccp {
             if Some(op.optype) != cur {
-                if cur.is_some() {
-                    writeln!(f, "   {}: {}", cur.unwrap(), csum)?;
+                if let Some(cur) = cur {
+                    writeln!(f, "   {}: {}", cur, csum)?;
                 }
                 cur = Some(op.optype);
                 csum = 0;
@@ -161,8 +161,8 @@ impl Display for Stat {
             writeln!(f, "       {:?}: {}", op, count)?;
             csum += count;
         }
-        if cur.is_some() {
-            writeln!(f, "   {}: {}", cur.unwrap(), csum)?;
+        if let Some(cur) = cur {
+            writeln!(f, "   {}: {}", cur, csum)?;
         }
         writeln!(f, "Cycle count: {}", self.cycle_count)?;
         writeln!(
@@ -279,11 +279,7 @@ impl Simulator {
                 .get_label(raw)
                 .unwrap_or(&placeholder);
 
-            let avg = if timer.count == 0 {
-                0
-            } else {
-                timer.sum_for_avg / timer.count
-            };
+            let avg = timer.sum_for_avg.checked_div(timer.count).unwrap_or(0);
 
             println!(
                 "{:<12} {:<12} {:<12} {:<12} {:<12} {:<12}",
@@ -0,0 +1,304 @@
+use std::{collections::HashMap, io};
+
+use qcpu_syntax::ParsingContext;
+
+use super::{
+    syntax::{OpName, OpV4},
+    SimulatorV4,
+};
+use crate::fpu::{lut::FpuTables, FpuImpl, FpuModel};
+
+/// The operations compared by [`FpuAccuracy`], in report order.
+pub const FPU_OPS: [OpName; 7] = [
+    OpName::Fadd,
+    OpName::Fsub,
+    OpName::Fmul,
+    OpName::Fdiv,
+    OpName::Fsqrt,
+    OpName::Ftoi,
+    OpName::Fitof,
+];
+
+/// Histogram buckets: `0`, `1`, `2..4`, `4..8`, ... up to `2^32..`.
+pub const ULP_BUCKETS: usize = 34;
+
+/// One execution of a floating-point instruction under both FPU models.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FpuSample {
+    pub rs1: u32,
+    pub rs2: u32,
+    pub hardware: u32,
+    pub native: u32,
+    pub ulp: u64,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct FpuPcAccuracy {
+    pub op: OpV4,
+    pub count: u64,
+    /// Executions where the two models disagree.
+    pub mismatches: u64,
+    pub total_ulp: u64,
+    pub worst: FpuSample,
+}
+
+#[derive(Debug, Clone)]
+pub struct FpuOpAccuracy {
+    pub count: u64,
+    pub mismatches: u64,
+    pub histogram: [u64; ULP_BUCKETS],
+    pub worst: FpuSample,
+    pub worst_pc: u32,
+}
+
+impl Default for FpuOpAccuracy {
+    fn default() -> Self {
+        Self {
+            count: 0,
+            mismatches: 0,
+            histogram: [0; ULP_BUCKETS],
+            worst: FpuSample::default(),
+            worst_pc: 0,
+        }
+    }
+}
+
+/// Differences between the hardware FPU model and native IEEE `f32`, per PC and per
+/// operation. The simulation itself keeps using the simulator's [`FpuModel`].
+#[derive(Debug, Default, Clone)]
+pub struct FpuAccuracy {
+    pub per_pc: HashMap<u32, FpuPcAccuracy>,
+    pub per_op: [FpuOpAccuracy; FPU_OPS.len()],
+}
+
+fn op_index(opname: OpName) -> Option<usize> {
+    match opname {
+        OpName::Fadd => Some(0),
+        OpName::Fsub => Some(1),
+        OpName::Fmul => Some(2),
+        OpName::Fdiv => Some(3),
+        OpName::Fsqrt => Some(4),
+        OpName::Ftoi => Some(5),
+        OpName::Fitof => Some(6),
+        _ => None,
+    }
+}
+
+/// Maps float bits onto a line where adjacent floats are adjacent integers.
+fn ordered(x: u32) -> i64 {
+    if x & 0x80000000 != 0 {
+        -((x & 0x7FFFFFFF) as i64)
+    } else {
+        x as i64
+    }
+}
+
+fn is_nan(x: u32) -> bool {
+    f32::from_bits(x).is_nan()
+}
+
+/// The distance between two results in units in the last place. `ftoi` results are
+/// integers and are compared as such; a NaN against a number counts as `u32::MAX`.
+pub fn ulp_distance(opname: OpName, a: u32, b: u32) -> u64 {
+    if let OpName::Ftoi = opname {
+        return (a as i32 as i64).abs_diff(b as i32 as i64);
+    }
+
+    match (is_nan(a), is_nan(b)) {
+        (true, true) => 0,
+        (false, false) => ordered(a).abs_diff(ordered(b)),
+        _ => u32::MAX as u64,
+    }
+}
+
+pub fn ulp_bucket(ulp: u64) -> usize {
+    (64 - ulp.leading_zeros() as usize).min(ULP_BUCKETS - 1)
+}
+
+fn bucket_label(bucket: usize) -> String {
+    match bucket {
+        0 => "0".to_string(),
+        1 => "1".to_string(),
+        b if b == ULP_BUCKETS - 1 => format!("{}+", 1u64 << (b - 1)),
+        b => format!("{}..{}", 1u64 << (b - 1), 1u64 << b),
+    }
+}
+
+fn format_operand(opname: OpName, x: u32) -> String {
+    match opname {
+        OpName::Fitof => format!("0x{:08x} ({})", x, x as i32),
+        _ => format!("0x{:08x} ({:e})", x, f32::from_bits(x)),
+    }
+}
+
+fn format_result(opname: OpName, x: u32) -> String {
+    match opname {
+        OpName::Ftoi => format!("0x{:08x} ({})", x, x as i32),
+        _ => format!("0x{:08x} ({:e})", x, f32::from_bits(x)),
+    }
+}
+
+impl FpuAccuracy {
+    /// Records one execution; the hardware model uses `tables`.
+    pub fn record(&mut self, pc: u32, op: OpV4, rs1: u32, rs2: u32, tables: &'static FpuTables) {
+        let Some(index) = op_index(op.opname) else {
+            return;
+        };
+
+        let (hardware, native) = (
+            FpuModel {
+                tables,
+                ..FpuModel::all(FpuImpl::Hardware)
+            },
+            FpuModel::all(FpuImpl::Native),
+        );
+        let (hardware, native) = match op.opname {
+            OpName::Fadd => (hardware.fadd(rs1, rs2), native.fadd(rs1, rs2)),
+            OpName::Fsub => (hardware.fsub(rs1, rs2), native.fsub(rs1, rs2)),
+            OpName::Fmul => (hardware.fmul(rs1, rs2), native.fmul(rs1, rs2)),
+            OpName::Fdiv => (hardware.fdiv(rs1, rs2), native.fdiv(rs1, rs2)),
+            OpName::Fsqrt => (hardware.fsqrt(rs1), native.fsqrt(rs1)),
+            OpName::Ftoi => (hardware.ftoi(rs1), native.ftoi(rs1)),
+            _ => (hardware.itof(rs1), native.itof(rs1)),
+        };
+
+        let sample = FpuSample {
+            rs1,
+            rs2,
+            hardware,
+            native,
+            ulp: ulp_distance(op.opname, hardware, native),
+        };
+        let mismatch = (sample.ulp != 0) as u64;
+
+        let pc_stat = self.per_pc.entry(pc).or_insert_with(|| FpuPcAccuracy {
+            op,
+            ..Default::default()
+        });
+        pc_stat.count += 1;
+        pc_stat.mismatches += mismatch;
+        pc_stat.total_ulp = pc_stat.total_ulp.saturating_add(sample.ulp);
+        if pc_stat.count == 1 || sample.ulp > pc_stat.worst.ulp {
+            pc_stat.worst = sample;
+        }
+
+        let op_stat = &mut self.per_op[index];
+        op_stat.count += 1;
+        op_stat.mismatches += mismatch;
+        op_stat.histogram[ulp_bucket(sample.ulp)] += 1;
+        if op_stat.count == 1 || sample.ulp > op_stat.worst.ulp {
+            op_stat.worst = sample;
+            op_stat.worst_pc = pc;
+        }
+    }
+
+    /// Writes per-operation histograms and the `top` PCs with the largest error.
+    pub fn write_report(
+        &self,
+        mut w: impl io::Write,
+        ctx: Option<&ParsingContext>,
+        top: usize,
+    ) -> io::Result<()> {
+        let label = |pc: u32| {
+            ctx.map(|ctx| format!(" <{}>", ctx.reverse_lookup_floor((pc >> 2) as usize)))
+                .unwrap_or_default()
+        };
+
+        writeln!(w, "FPU accuracy (hardware model vs IEEE f32)")?;
+        for (opname, stat) in FPU_OPS.iter().zip(&self.per_op) {
+            if stat.count == 0 {
+                continue;
+            }
+
+            writeln!(w)?;
+            writeln!(
+                w,
+                "{}: {} executions, {} mismatches ({:.4}%)",
+                opname,
+                stat.count,
+                stat.mismatches,
+                stat.mismatches as f64 / stat.count as f64 * 100.0
+            )?;
+            for (bucket, &n) in stat.histogram.iter().enumerate() {
+                if n != 0 {
+                    writeln!(w, "  {:>12} ulp: {}", bucket_label(bucket), n)?;
+                }
+            }
+            if stat.worst.ulp != 0 {
+                writeln!(
+                    w,
+                    "  worst: {} ulp at 0x{:05x}{}",
+                    stat.worst.ulp,
+                    stat.worst_pc,
+                    label(stat.worst_pc)
+                )?;
+            }
+        }
+
+        let mut worst: Vec<_> = self
+            .per_pc
+            .iter()
+            .filter(|(_, stat)| stat.mismatches != 0)
+            .collect();
+        worst.sort_by(|(pc1, s1), (pc2, s2)| s2.worst.ulp.cmp(&s1.worst.ulp).then(pc1.cmp(pc2)));
+
+        writeln!(w)?;
+        writeln!(w, "Worst instructions")?;
+        for (&pc, stat) in worst.into_iter().take(top) {
+            let opname = stat.op.opname;
+            writeln!(w, "0x{:05x}{} {}", pc, label(pc), stat.op)?;
+            writeln!(
+                w,
+                "  {} executions, {} mismatches, mean {:.3} ulp, max {} ulp",
+                stat.count,
+                stat.mismatches,
+                stat.total_ulp as f64 / stat.count as f64,
+                stat.worst.ulp
+            )?;
+            write!(w, "  rs1 = {}", format_operand(opname, stat.worst.rs1))?;
+            if matches!(
+                opname,
+                OpName::Fadd | OpName::Fsub | OpName::Fmul | OpName::Fdiv
+            ) {
+                write!(w, ", rs2 = {}", format_operand(opname, stat.worst.rs2))?;
+            }
+            writeln!(w)?;
+            writeln!(
+                w,
+                "  hardware = {}, native = {}",
+                format_result(opname, stat.worst.hardware),
+                format_result(opname, stat.worst.native)
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl SimulatorV4<'_> {
+    /// Starts comparing every floating-point instruction against native IEEE `f32`.
+    pub fn start_fpu_accuracy(&mut self) {
+        self.fpu_accuracy = Some(Box::default());
+        self.update_observing();
+    }
+
+    pub fn finish_fpu_accuracy(&mut self) -> Option<FpuAccuracy> {
+        let accuracy = self.fpu_accuracy.take();
+        self.update_observing();
+        accuracy.map(|a| *a)
+    }
+
+    /// Called with the operands of `self.op` before it executes.
+    #[inline(always)]
+    pub(super) fn record_fpu_accuracy(&mut self, line: usize) {
+        let (op, rs1, rs2, tables) = (
+            self.op,
+            self.get_reg(self.op.rs1),
+            self.get_reg(self.op.rs2),
+            self.fpu.tables,
+        );
+        if let Some(accuracy) = self.fpu_accuracy.as_mut() {
+            accuracy.record((line as u32) << 2, op, rs1, rs2, tables);
+        }
+    }
+}
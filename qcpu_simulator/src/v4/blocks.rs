@@ -0,0 +1,500 @@
+//! Basic blocks split at branch and jump targets, and the loops formed by their backward
+//! edges, so hot loops show up without reconstructing them from [`Instat`](super::Instat).
+
+use std::{collections::HashMap, io};
+
+use qcpu_syntax::ParsingContext;
+use serde::Serialize;
+
+use super::{
+    profile::{function_entries, Cost},
+    syntax::{OpName, OpV4, Reg},
+    SimulatorV4,
+};
+
+const RA: Reg = 1;
+const NONE: u32 = u32::MAX;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Block {
+    /// Instruction index of the first instruction.
+    pub start: u32,
+    pub len: u32,
+    pub executions: u64,
+    pub cost: Cost,
+}
+
+/// The instructions from `header` to the last backward branch or `jal zero` jumping to
+/// it from the same function. Every jump from inside to the header is an iteration;
+/// entering the header any other way, including a recursive call, starts a new visit.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoopProfile {
+    pub header: u32,
+    pub end: u32,
+    pub visits: u64,
+    /// Backward jumps taken.
+    pub iterations: u64,
+    pub max_trips: u64,
+    /// Visits by trip count: entry `i` counts trip counts from `2^i` to `2^(i+1) - 1`.
+    pub trips: Vec<u64>,
+    /// Spent in the body, excluding callees.
+    pub cost: Cost,
+    #[serde(skip)]
+    pending: Option<u64>,
+}
+
+impl LoopProfile {
+    pub fn size(&self) -> u32 {
+        self.end - self.header + 1
+    }
+
+    pub fn average_trips(&self) -> f64 {
+        (self.iterations + self.visits) as f64 / self.visits as f64
+    }
+
+    /// Ends the current visit, if any.
+    fn close(&mut self) {
+        if let Some(back) = self.pending.take() {
+            let trips = back + 1;
+            let bucket = trips.ilog2() as usize;
+            if self.trips.len() <= bucket {
+                self.trips.resize(bucket + 1, 0);
+            }
+            self.trips[bucket] += 1;
+            self.visits += 1;
+            self.max_trips = self.max_trips.max(trips);
+        }
+    }
+
+    fn trips_histogram(&self) -> String {
+        self.trips
+            .iter()
+            .enumerate()
+            .filter(|(_, &n)| n > 0)
+            .map(|(i, n)| match i {
+                0 => format!("1:{}", n),
+                _ => format!("{}-{}:{}", 1u64 << i, (1u64 << (i + 1)) - 1, n),
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BlockProfile {
+    blocks: Vec<Block>,
+    loops: Vec<LoopProfile>,
+    /// Per instruction: its block, the loop it is the header of and the header its
+    /// backward jump goes to, the latter two [`NONE`] if there is none.
+    block_of: Vec<u32>,
+    loop_at: Vec<u32>,
+    back_edge: Vec<u32>,
+    last_cycles: u64,
+}
+
+impl BlockProfile {
+    /// Splits `instructions` into blocks. `entry` is the index execution starts at and
+    /// `cycles` the cycle counter there.
+    pub fn new(instructions: &[OpV4], entry: u32, cycles: u64) -> Self {
+        let len = instructions.len();
+        let target = |i: usize, op: &OpV4| (((i as u32) << 2).wrapping_add(op.imm) >> 2) as usize;
+
+        let mut leader = vec![false; len + 1];
+        leader[0] = true;
+        leader[len] = true;
+        if (entry as usize) < len {
+            leader[entry as usize] = true;
+        }
+        let mut back_edge = vec![NONE; len];
+        let mut loop_end = HashMap::<usize, usize>::new();
+        // Function entries up to each instruction, to tell loops from tail calls.
+        let mut entries = vec![0; len + 1];
+        for (i, &entry) in function_entries(instructions).iter().enumerate() {
+            entries[i + 1] = entries[i] + entry as usize;
+        }
+
+        for (i, op) in instructions.iter().enumerate() {
+            match op.opname {
+                OpName::Beq | OpName::Bne | OpName::Blt | OpName::Bge | OpName::Jal => {
+                    leader[i + 1] = true;
+                    let target = target(i, op);
+                    if target < len {
+                        leader[target] = true;
+                        let call = matches!(op.opname, OpName::Jal) && op.rd == RA;
+                        let same_function = entries[i + 1] == entries[target + 1];
+                        if target <= i && !call && same_function {
+                            back_edge[i] = target as u32;
+                            let end = loop_end.entry(target).or_insert(i);
+                            *end = (*end).max(i);
+                        }
+                    }
+                }
+                OpName::Jalr => leader[i + 1] = true,
+                _ => {}
+            }
+        }
+
+        let mut blocks = Vec::new();
+        let mut block_of = vec![0; len];
+        for i in 0..len {
+            if leader[i] {
+                blocks.push(Block {
+                    start: i as u32,
+                    len: 0,
+                    executions: 0,
+                    cost: Cost::default(),
+                });
+            }
+            let last = blocks.len() - 1;
+            blocks[last].len += 1;
+            block_of[i] = last as u32;
+        }
+
+        let mut headers: Vec<_> = loop_end.into_iter().collect();
+        headers.sort_unstable();
+        let mut loop_at = vec![NONE; len + 1];
+        let loops = headers
+            .into_iter()
+            .enumerate()
+            .map(|(l, (header, end))| {
+                loop_at[header] = l as u32;
+                LoopProfile {
+                    header: header as u32,
+                    end: end as u32,
+                    visits: 0,
+                    iterations: 0,
+                    max_trips: 0,
+                    trips: Vec::new(),
+                    cost: Cost::default(),
+                    pending: None,
+                }
+            })
+            .collect();
+
+        Self {
+            blocks,
+            loops,
+            block_of,
+            loop_at,
+            back_edge,
+            last_cycles: cycles,
+        }
+    }
+
+    /// Charges the instruction at `index` that just executed to its block and follows
+    /// the jump to `next_pc`. `cycles` works like in
+    /// [`CallProfile::record`](super::profile::CallProfile::record).
+    #[inline(always)]
+    pub fn record(&mut self, index: usize, next_pc: u32, cycles: Option<u64>, miss: bool) {
+        let cycles = cycles.unwrap_or(self.last_cycles + 1);
+        let block = &mut self.blocks[self.block_of[index] as usize];
+        block.cost.instructions += 1;
+        block.cost.cycles += cycles - self.last_cycles;
+        block.cost.misses += miss as u64;
+        block.executions += (index as u32 == block.start + block.len - 1) as u64;
+        self.last_cycles = cycles;
+
+        let next = (next_pc >> 2) as usize;
+        let l = match self.loop_at.get(next) {
+            Some(&l) if l != NONE => l as usize,
+            _ => return,
+        };
+        let profile = &mut self.loops[l];
+        if self.back_edge[index] == next as u32 {
+            profile.iterations += 1;
+            *profile.pending.get_or_insert(0) += 1;
+        } else {
+            profile.close();
+            profile.pending = Some(0);
+        }
+    }
+
+    /// Ends the visits still in progress and adds up the cost of each loop body.
+    pub fn finish(&mut self) {
+        let mut prefix = vec![Cost::default()];
+        for block in &self.blocks {
+            let last = prefix[prefix.len() - 1];
+            prefix.push(last + block.cost);
+        }
+
+        for profile in self.loops.iter_mut() {
+            profile.close();
+            let first = self.block_of[profile.header as usize] as usize;
+            let last = self.block_of[profile.end as usize] as usize;
+            let (from, to) = (prefix[first], prefix[last + 1]);
+            profile.cost = Cost {
+                instructions: to.instructions - from.instructions,
+                cycles: to.cycles - from.cycles,
+                misses: to.misses - from.misses,
+            };
+        }
+    }
+
+    pub fn blocks(&self) -> &[Block] {
+        &self.blocks
+    }
+
+    /// Loops that were entered, sorted by cycles spent in the body.
+    pub fn loops(&self) -> Vec<&LoopProfile> {
+        let mut loops: Vec<_> = self.loops.iter().filter(|l| l.visits > 0).collect();
+        loops.sort_by(|a, b| {
+            b.cost
+                .cycles
+                .cmp(&a.cost.cycles)
+                .then(a.header.cmp(&b.header))
+        });
+        loops
+    }
+
+    /// Writes the hottest `limit` loops and blocks.
+    pub fn write_report(
+        &self,
+        mut w: impl io::Write,
+        ctx: Option<&ParsingContext>,
+        limit: usize,
+    ) -> io::Result<()> {
+        let total = self
+            .blocks
+            .iter()
+            .fold(Cost::default(), |total, block| total + block.cost);
+        let percent = |n: u64| n as f64 / total.cycles as f64 * 100.0;
+        let executed = self.blocks.iter().filter(|b| b.executions > 0).count();
+        let loops = self.loops();
+
+        writeln!(
+            w,
+            "Basic blocks: {} ({:.02} instructions on average), {} executed",
+            self.blocks.len(),
+            self.block_of.len() as f64 / self.blocks.len() as f64,
+            executed
+        )?;
+        writeln!(
+            w,
+            "Loops: {} ({} entered), {} cycles in total\n",
+            self.loops.len(),
+            loops.len(),
+            total.cycles
+        )?;
+
+        writeln!(w, "Hottest loops")?;
+        writeln!(
+            w,
+            "{:5} {:40} {:>6} {:>10} {:>12} {:>10} {:>10} {:>14} {:>7}  Trip counts",
+            "PC", "Label", "Size", "Visits", "Iterations", "Avg trips", "Max trips", "Cycles", "%",
+        )?;
+        for profile in loops.into_iter().take(limit) {
+            writeln!(
+                w,
+                "{:05} {:40} {:>6} {:>10} {:>12} {:>10.02} {:>10} {:>14} {:>6.02}%  {}",
+                profile.header,
+                location(profile.header, ctx),
+                profile.size(),
+                profile.visits,
+                profile.iterations,
+                profile.average_trips(),
+                profile.max_trips,
+                profile.cost.cycles,
+                percent(profile.cost.cycles),
+                profile.trips_histogram(),
+            )?;
+        }
+
+        let mut blocks: Vec<_> = self.blocks.iter().filter(|b| b.executions > 0).collect();
+        blocks.sort_by(|a, b| {
+            b.cost
+                .cycles
+                .cmp(&a.cost.cycles)
+                .then(a.start.cmp(&b.start))
+        });
+
+        writeln!(w, "\nHottest blocks")?;
+        writeln!(
+            w,
+            "{:5} {:40} {:>6} {:>12} {:>14} {:>7} {:>10}",
+            "PC", "Label", "Size", "Executed", "Cycles", "%", "Misses",
+        )?;
+        for block in blocks.into_iter().take(limit) {
+            writeln!(
+                w,
+                "{:05} {:40} {:>6} {:>12} {:>14} {:>6.02}% {:>10}",
+                block.start,
+                location(block.start, ctx),
+                block.len,
+                block.executions,
+                block.cost.cycles,
+                percent(block.cost.cycles),
+                block.cost.misses,
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The closest label at or before `index`, with the byte offset from it.
+fn location(index: u32, ctx: Option<&ParsingContext>) -> String {
+    let Some(ctx) = ctx else {
+        return format!("0x{:05x}", index << 2);
+    };
+    let label = ctx.reverse_lookup_floor(index as usize);
+    match ctx.label_map.get(label) {
+        Some(&start) if start < index as usize => {
+            format!("{}+0x{:x}", label, (index as usize - start) << 2)
+        }
+        _ => label.to_string(),
+    }
+}
+
+impl SimulatorV4<'_> {
+    /// Starts counting blocks and loop trips from the current PC. Statistics must be
+    /// enabled. Cycles are counted like in [`Self::start_call_profile`].
+    pub fn start_block_profile(&mut self) {
+        let cycles = self.timing.as_ref().map_or(0, |t| t.stat.cycles);
+        self.block_profile = Some(Box::new(BlockProfile::new(
+            &self.instructions,
+            self.pc >> 2,
+            cycles,
+        )));
+    }
+
+    pub fn finish_block_profile(&mut self) -> Option<BlockProfile> {
+        self.block_profile.take().map(|mut p| {
+            p.finish();
+            *p
+        })
+    }
+
+    #[inline(always)]
+    pub(super) fn record_block_profile(&mut self, index: usize, miss: bool) {
+        let cycles = self.timing.as_ref().map(|t| t.stat.cycles);
+        if let Some(profile) = self.block_profile.as_mut() {
+            profile.record(index, self.next_pc, cycles, miss);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A jump from instruction index `from` to `to`.
+    fn jump(opname: OpName, rd: Reg, from: u32, to: u32) -> OpV4 {
+        OpV4 {
+            opname,
+            rd,
+            imm: (to.wrapping_sub(from)) << 2,
+            ..Default::default()
+        }
+    }
+
+    fn addi() -> OpV4 {
+        OpV4 {
+            opname: OpName::Addi,
+            rd: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    pub fn loop_test() {
+        let instructions = [
+            addi(),
+            // outer
+            addi(),
+            // inner
+            addi(),
+            jump(OpName::Blt, 0, 3, 2),
+            addi(),
+            jump(OpName::Blt, 0, 5, 1),
+            // A loop closed by `jal zero`.
+            jump(OpName::Beq, 0, 6, 9),
+            addi(),
+            jump(OpName::Jal, 0, 8, 6),
+            addi(),
+        ];
+        let mut path = vec![0];
+        for _ in 0..3 {
+            path.push(1);
+            for _ in 0..4 {
+                path.extend([2, 3]);
+            }
+            path.extend([4, 5]);
+        }
+        path.extend([6, 7, 8, 6, 7, 8, 6, 9]);
+
+        let mut profile = BlockProfile::new(&instructions, 0, 0);
+        for (i, &index) in path.iter().enumerate() {
+            let next_pc = path.get(i + 1).map_or(0, |next| next << 2);
+            profile.record(index as usize, next_pc, None, false);
+        }
+        profile.finish();
+
+        let blocks: Vec<_> = profile
+            .blocks()
+            .iter()
+            .map(|b| (b.start, b.len, b.executions))
+            .collect();
+        assert_eq!(
+            blocks,
+            [
+                (0, 1, 1),
+                (1, 1, 3),
+                (2, 2, 12),
+                (4, 2, 3),
+                (6, 1, 3),
+                (7, 2, 2),
+                (9, 1, 1),
+            ]
+        );
+
+        let loops: Vec<_> = profile
+            .loops()
+            .into_iter()
+            .map(|l| {
+                (
+                    l.header,
+                    l.size(),
+                    l.visits,
+                    l.iterations,
+                    l.max_trips,
+                    l.trips.clone(),
+                    l.cost.cycles,
+                )
+            })
+            .collect();
+        assert_eq!(
+            loops,
+            [
+                (1, 5, 1, 2, 3, vec![0, 1], 33),
+                (2, 2, 3, 9, 4, vec![0, 0, 3], 24),
+                (6, 3, 1, 2, 3, vec![0, 1], 7),
+            ]
+        );
+
+        let mut report = Vec::new();
+        profile.write_report(&mut report, None, 10).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("Basic blocks: 7 (1.43 instructions on average), 7 executed"));
+        assert!(report.contains("2-3:1"));
+    }
+
+    #[test]
+    pub fn tail_call_test() {
+        // A backward tail call to another function is not a loop.
+        let instructions = [
+            jump(OpName::Jal, RA, 0, 3),
+            jump(OpName::Jal, RA, 1, 5),
+            jump(OpName::Jal, 0, 2, 6),
+            // f
+            addi(),
+            OpV4 {
+                opname: OpName::Jalr,
+                rs1: RA,
+                ..Default::default()
+            },
+            // h
+            jump(OpName::Jal, 0, 5, 3),
+            addi(),
+        ];
+        assert!(BlockProfile::new(&instructions, 0, 0).loops.is_empty());
+    }
+}
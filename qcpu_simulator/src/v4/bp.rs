@@ -1,54 +1,607 @@
-use std::fmt::Display;
+use std::{
+    fmt::{Debug, Display},
+    io::{self, Read, Write},
+    str::FromStr,
+};
 
-use super::syntax::{OpName, OpV4};
+use serde::Serialize;
 
-#[derive(Debug, Clone, Copy)]
+use super::{
+    log::percent,
+    syntax::{OpName, OpV4, Reg},
+};
+
+/// A branch direction predictor.
+pub trait Predictor: Debug {
+    /// Predicts whether the branch at `pc` to `target` is taken, then trains on the
+    /// actual outcome. Returns the prediction.
+    fn predict_update(&mut self, pc: usize, target: usize, taken: bool) -> bool;
+
+    /// Writes the predictor tables for a checkpoint.
+    fn save(&self, w: &mut dyn Write) -> io::Result<()>;
+
+    /// Reads tables written by `save` from a predictor with the same configuration.
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()>;
+}
+
+fn read_u64(r: &mut dyn Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Increments or decrements a 2-bit saturating counter.
+#[inline(always)]
+fn train(counter: &mut u8, taken: bool) {
+    *counter = (*counter + taken as u8).saturating_sub(!taken as u8).min(3);
+}
+
+/// Backward taken, forward not taken.
+#[derive(Debug, Default)]
+pub struct Static;
+
+impl Predictor for Static {
+    fn predict_update(&mut self, pc: usize, target: usize, _taken: bool) -> bool {
+        target < pc
+    }
+
+    fn save(&self, _w: &mut dyn Write) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn restore(&mut self, _r: &mut dyn Read) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// 2-bit counters indexed by PC.
+#[derive(Debug)]
+pub struct Bimodal {
+    pht: Vec<u8>,
+}
+
+impl Bimodal {
+    pub fn new(size: usize) -> Self {
+        Self { pht: vec![1; size] }
+    }
+}
+
+impl Predictor for Bimodal {
+    fn predict_update(&mut self, pc: usize, _target: usize, taken: bool) -> bool {
+        let mask = self.pht.len() - 1;
+        let counter = &mut self.pht[(pc >> 2) & mask];
+        let predicted = *counter >= 2;
+        train(counter, taken);
+        predicted
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.pht)
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        r.read_exact(&mut self.pht)
+    }
+}
+
+/// 2-bit counters indexed by PC xor global history.
+#[derive(Debug)]
+pub struct Gshare {
+    pht: Vec<u8>,
+    gh: usize,
+    gh_mask: usize,
+}
+
+impl Gshare {
+    pub fn new(size: usize, history: u32) -> Self {
+        Self {
+            pht: vec![1; size],
+            gh: 0,
+            gh_mask: (1 << history) - 1,
+        }
+    }
+}
+
+impl Predictor for Gshare {
+    fn predict_update(&mut self, pc: usize, _target: usize, taken: bool) -> bool {
+        let mask = self.pht.len() - 1;
+        let counter = &mut self.pht[(self.gh ^ (pc >> 2)) & mask];
+        let predicted = *counter >= 2;
+        train(counter, taken);
+        self.gh = ((self.gh << 1) | taken as usize) & self.gh_mask;
+        predicted
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.pht)?;
+        w.write_all(&(self.gh as u64).to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        r.read_exact(&mut self.pht)?;
+        self.gh = read_u64(r)? as usize & self.gh_mask;
+        Ok(())
+    }
+}
+
+/// Two gshare tables with differently biased initial states and a selector between them.
+/// This is the predictor on the FPGA.
+#[derive(Debug)]
+pub struct Tournament {
+    taken_pht: Vec<u8>,
+    untaken_pht: Vec<u8>,
+    selector_pht: Vec<u8>,
+    gh: usize,
+    gh_mask: usize,
+}
+
+impl Tournament {
+    pub fn new(pht: usize, selector: usize, history: u32) -> Self {
+        Self {
+            taken_pht: vec![2; pht],
+            untaken_pht: vec![1; pht],
+            selector_pht: vec![2; selector],
+            gh: 0,
+            gh_mask: (1 << history) - 1,
+        }
+    }
+}
+
+impl Predictor for Tournament {
+    #[inline(always)]
+    fn predict_update(&mut self, pc: usize, _target: usize, taken: bool) -> bool {
+        let xor = self.gh ^ (pc >> 2);
+        let taken_idx = xor & (self.taken_pht.len() - 1);
+        let selector_idx = xor & (self.selector_pht.len() - 1);
+
+        let taken_counter = self.taken_pht[taken_idx];
+        let untaken_counter = self.untaken_pht[taken_idx];
+        let selector = self.selector_pht[selector_idx];
+        // Selected by index rather than by a branch, which the host would mispredict as
+        // often as the selector changes its mind.
+        let predicted = [untaken_counter, taken_counter][(selector >= 2) as usize] >= 2;
+
+        train(&mut self.taken_pht[taken_idx], taken);
+        train(&mut self.untaken_pht[taken_idx], taken);
+        train(&mut self.selector_pht[selector_idx], taken);
+        self.gh = ((self.gh << 1) | taken as usize) & self.gh_mask;
+
+        predicted
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        w.write_all(&self.taken_pht)?;
+        w.write_all(&self.untaken_pht)?;
+        w.write_all(&self.selector_pht)?;
+        w.write_all(&(self.gh as u64).to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        r.read_exact(&mut self.taken_pht)?;
+        r.read_exact(&mut self.untaken_pht)?;
+        r.read_exact(&mut self.selector_pht)?;
+        self.gh = read_u64(r)? as usize & self.gh_mask;
+        Ok(())
+    }
+}
+
+/// A perceptron per PC over the global history (Jiménez and Lin).
+#[derive(Debug)]
+pub struct Perceptron {
+    /// `size` rows of a bias weight followed by one weight per history bit.
+    weights: Vec<i16>,
+    history_len: usize,
+    history: u64,
+    theta: i32,
+}
+
+const PERCEPTRON_WEIGHT_MAX: i32 = 127;
+
+impl Perceptron {
+    pub fn new(size: usize, history: u32) -> Self {
+        Self {
+            weights: vec![0; size * (history as usize + 1)],
+            history_len: history as usize,
+            history: 0,
+            theta: (1.93 * history as f64 + 14.0) as i32,
+        }
+    }
+}
+
+impl Predictor for Perceptron {
+    fn predict_update(&mut self, pc: usize, _target: usize, taken: bool) -> bool {
+        let row_len = self.history_len + 1;
+        let row = (pc >> 2) % (self.weights.len() / row_len) * row_len;
+        let weights = &mut self.weights[row..row + row_len];
+        let history = self.history;
+        let bit = |i: usize| history >> i & 1 != 0;
+
+        let y = weights[1..]
+            .iter()
+            .enumerate()
+            .fold(weights[0] as i32, |y, (i, &w)| {
+                if bit(i) {
+                    y + w as i32
+                } else {
+                    y - w as i32
+                }
+            });
+        let predicted = y >= 0;
+
+        if predicted != taken || y.abs() <= self.theta {
+            let adjust = |w: &mut i16, up: bool| {
+                let delta = if up { 1 } else { -1 };
+                *w =
+                    (*w as i32 + delta).clamp(-PERCEPTRON_WEIGHT_MAX, PERCEPTRON_WEIGHT_MAX) as i16;
+            };
+            adjust(&mut weights[0], taken);
+            for (i, w) in weights[1..].iter_mut().enumerate() {
+                adjust(w, bit(i) == taken);
+            }
+        }
+
+        self.history = ((self.history << 1) | taken as u64) & ((1 << self.history_len) - 1);
+        predicted
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        for weight in &self.weights {
+            w.write_all(&weight.to_le_bytes())?;
+        }
+        w.write_all(&self.history.to_le_bytes())
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        for weight in self.weights.iter_mut() {
+            let mut buf = [0; 2];
+            r.read_exact(&mut buf)?;
+            *weight = i16::from_le_bytes(buf);
+        }
+        self.history = read_u64(r)?;
+        Ok(())
+    }
+}
+
+/// A circular return address stack. Pushing onto a full stack overwrites the oldest
+/// entry.
+#[derive(Debug, Clone)]
+pub struct ReturnAddressStack {
+    pub entries: Vec<usize>,
+    /// Where the next push goes.
+    pub top: usize,
+    pub len: usize,
+
+    pub overflow_count: usize,
+    pub underflow_count: usize,
+}
+
+impl ReturnAddressStack {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            entries: vec![0; depth],
+            top: 0,
+            len: 0,
+            overflow_count: 0,
+            underflow_count: 0,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn push(&mut self, addr: usize) {
+        self.entries[self.top] = addr;
+        self.top = (self.top + 1) % self.depth();
+        if self.len == self.depth() {
+            self.overflow_count += 1;
+        } else {
+            self.len += 1;
+        }
+    }
+
+    pub fn pop(&mut self) -> Option<usize> {
+        if self.len == 0 {
+            self.underflow_count += 1;
+            return None;
+        }
+        self.top = (self.top + self.depth() - 1) % self.depth();
+        self.len -= 1;
+        Some(self.entries[self.top])
+    }
+}
+
+const RA: Reg = 1;
+
+const TAKEN_PHT_SIZE: usize = 1024;
+const SELECTOR_PHT_SIZE: usize = 256;
+const GH_BITS: u32 = 10;
+const JALR_ADDR_SIZE: usize = 2048;
+const JALR_ADDR_MASK: usize = JALR_ADDR_SIZE - 1;
+
+/// The predictor a [`PredictorConfig`] builds. An enum rather than a trait object so
+/// that the simulator loop can inline the predictor.
+#[derive(Debug)]
+pub enum Direction {
+    Static(Static),
+    Bimodal(Bimodal),
+    Gshare(Gshare),
+    Tournament(Tournament),
+    Perceptron(Perceptron),
+}
+
+impl Predictor for Direction {
+    #[inline(always)]
+    fn predict_update(&mut self, pc: usize, target: usize, taken: bool) -> bool {
+        match self {
+            Self::Static(p) => p.predict_update(pc, target, taken),
+            Self::Bimodal(p) => p.predict_update(pc, target, taken),
+            Self::Gshare(p) => p.predict_update(pc, target, taken),
+            Self::Tournament(p) => p.predict_update(pc, target, taken),
+            Self::Perceptron(p) => p.predict_update(pc, target, taken),
+        }
+    }
+
+    fn save(&self, w: &mut dyn Write) -> io::Result<()> {
+        match self {
+            Self::Static(p) => p.save(w),
+            Self::Bimodal(p) => p.save(w),
+            Self::Gshare(p) => p.save(w),
+            Self::Tournament(p) => p.save(w),
+            Self::Perceptron(p) => p.save(w),
+        }
+    }
+
+    fn restore(&mut self, r: &mut dyn Read) -> io::Result<()> {
+        match self {
+            Self::Static(p) => p.restore(r),
+            Self::Bimodal(p) => p.restore(r),
+            Self::Gshare(p) => p.restore(r),
+            Self::Tournament(p) => p.restore(r),
+            Self::Perceptron(p) => p.restore(r),
+        }
+    }
+}
+
+/// Which branch direction predictor to simulate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum PredictorConfig {
+    Static,
+    Bimodal {
+        size: usize,
+    },
+    Gshare {
+        size: usize,
+        history: u32,
+    },
+    Tournament {
+        pht: usize,
+        selector: usize,
+        history: u32,
+    },
+    Perceptron {
+        size: usize,
+        history: u32,
+    },
+}
+
+impl Default for PredictorConfig {
+    fn default() -> Self {
+        Self::Tournament {
+            pht: TAKEN_PHT_SIZE,
+            selector: SELECTOR_PHT_SIZE,
+            history: GH_BITS,
+        }
+    }
+}
+
+impl PredictorConfig {
+    pub fn build(&self) -> Direction {
+        match *self {
+            Self::Static => Direction::Static(Static),
+            Self::Bimodal { size } => Direction::Bimodal(Bimodal::new(size)),
+            Self::Gshare { size, history } => Direction::Gshare(Gshare::new(size, history)),
+            Self::Tournament {
+                pht,
+                selector,
+                history,
+            } => Direction::Tournament(Tournament::new(pht, selector, history)),
+            Self::Perceptron { size, history } => {
+                Direction::Perceptron(Perceptron::new(size, history))
+            }
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let power_of_two = |name: &str, n: usize| {
+            if n.is_power_of_two() {
+                Ok(())
+            } else {
+                Err(format!("{} must be a power of two: {}", name, n))
+            }
+        };
+
+        match *self {
+            Self::Static => Ok(()),
+            Self::Bimodal { size } => power_of_two("size", size),
+            Self::Gshare { size, history } => {
+                power_of_two("size", size)?;
+                (history < usize::BITS)
+                    .then_some(())
+                    .ok_or_else(|| format!("history is too long: {}", history))
+            }
+            Self::Tournament {
+                pht,
+                selector,
+                history,
+            } => {
+                power_of_two("pht", pht)?;
+                power_of_two("selector", selector)?;
+                (history < usize::BITS)
+                    .then_some(())
+                    .ok_or_else(|| format!("history is too long: {}", history))
+            }
+            Self::Perceptron { size, history } => {
+                if size == 0 {
+                    return Err("size must not be zero".to_string());
+                }
+                (1..64)
+                    .contains(&history)
+                    .then_some(())
+                    .ok_or_else(|| format!("history must be 1 to 63 bits: {}", history))
+            }
+        }
+    }
+}
+
+/// Parses a predictor kind (`static`, `bimodal`, `gshare`, `tournament` or `perceptron`)
+/// followed by comma-separated sizes, e.g. `gshare,size=4096,history=12` or
+/// `tournament,pht=1024,selector=256,history=10`. Sizes that are not mentioned keep
+/// their default.
+impl FromStr for PredictorConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',').map(str::trim);
+        let mut config = match parts.next().unwrap_or_default() {
+            "static" => Self::Static,
+            "bimodal" => Self::Bimodal { size: 2048 },
+            "gshare" => Self::Gshare {
+                size: 2048,
+                history: GH_BITS,
+            },
+            "tournament" => Self::default(),
+            "perceptron" => Self::Perceptron {
+                size: 256,
+                history: 16,
+            },
+            kind => return Err(format!("Unknown branch predictor: {}", kind)),
+        };
+
+        for part in parts.filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Expected <key>=<value>: {}", part))?;
+            let (key, value) = (key.trim(), value.trim());
+            let n: usize = value
+                .parse()
+                .map_err(|_| format!("Invalid {}: {}", key, value))?;
+
+            match (&mut config, key) {
+                (Self::Bimodal { size }, "size")
+                | (Self::Gshare { size, .. }, "size")
+                | (Self::Perceptron { size, .. }, "size")
+                | (Self::Tournament { pht: size, .. }, "pht")
+                | (Self::Tournament { selector: size, .. }, "selector") => *size = n,
+                (Self::Gshare { history, .. }, "history")
+                | (Self::Tournament { history, .. }, "history")
+                | (Self::Perceptron { history, .. }, "history") => *history = n as u32,
+                _ => return Err(format!("Unknown branch predictor setting: {}", key)),
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Display for PredictorConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Static => write!(f, "static"),
+            Self::Bimodal { size } => write!(f, "bimodal,size={}", size),
+            Self::Gshare { size, history } => {
+                write!(f, "gshare,size={},history={}", size, history)
+            }
+            Self::Tournament {
+                pht,
+                selector,
+                history,
+            } => write!(
+                f,
+                "tournament,pht={},selector={},history={}",
+                pht, selector, history
+            ),
+            Self::Perceptron { size, history } => {
+                write!(f, "perceptron,size={},history={}", size, history)
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
 pub struct BranchPredictor {
-    pub taken_pht: [u8; TAKEN_PHT_SIZE],
-    pub untaken_pht: [u8; TAKEN_PHT_SIZE],
-    pub selector_pht: [u8; SELECTOR_PHT_SIZE],
+    pub config: PredictorConfig,
+    pub direction: Direction,
     pub jalr_addr: [usize; JALR_ADDR_SIZE],
-    pub gh: usize,
+    /// Predicts returns when present. Returns fall back to `jalr_addr` while it is empty.
+    pub ras: Option<ReturnAddressStack>,
 
     pub flush_count_jalr: usize,
     pub total_count_jalr: usize,
+    /// The part of the `jalr` counts that are returns (`jalr zero, ra`).
+    pub flush_count_return: usize,
+    pub total_count_return: usize,
     pub flush_count_branch: usize,
     pub total_count_branch: usize,
 }
 
-const TAKEN_PHT_SIZE: usize = 1024;
-const TAKEN_PHT_MASK: usize = TAKEN_PHT_SIZE - 1;
-const SELECTOR_PHT_SIZE: usize = 256;
-const SELECTOR_PHT_MASK: usize = SELECTOR_PHT_SIZE - 1;
-const JALR_ADDR_SIZE: usize = 2048;
-const JALR_ADDR_MASK: usize = JALR_ADDR_SIZE - 1;
-const GH_MASK: usize = 1023;
-
 impl BranchPredictor {
     pub fn new() -> Self {
+        Self::with_config(PredictorConfig::default())
+    }
+
+    pub fn with_config(config: PredictorConfig) -> Self {
         Self {
-            taken_pht: [2; TAKEN_PHT_SIZE],
-            untaken_pht: [1; TAKEN_PHT_SIZE],
-            selector_pht: [2; SELECTOR_PHT_SIZE],
+            config,
+            direction: config.build(),
             jalr_addr: [0; JALR_ADDR_SIZE],
-            gh: 0,
+            ras: None,
 
             flush_count_jalr: 0,
             total_count_jalr: 0,
+            flush_count_return: 0,
+            total_count_return: 0,
             flush_count_branch: 0,
             total_count_branch: 0,
         }
     }
 
+    /// Adds a return address stack of `depth` entries, or none for 0.
+    pub fn with_ras(mut self, depth: usize) -> Self {
+        self.ras = (depth > 0).then(|| ReturnAddressStack::new(depth));
+        self
+    }
+
+    #[inline(always)]
     pub fn update_taken(&mut self, op: &OpV4, pc: usize, next_pc: usize) -> bool {
         let pci = pc >> 2;
 
         match op.opname {
+            OpName::Jal => {
+                if let (Some(ras), RA) = (&mut self.ras, op.rd) {
+                    ras.push(pc + 4);
+                }
+                false
+            }
             OpName::Jalr => {
                 self.total_count_jalr += 1;
                 let idx = pci & JALR_ADDR_MASK;
-                let predicted_pc = self.jalr_addr[idx];
+                let is_return = op.rd == 0 && op.rs1 == RA;
+                let predicted_pc = match &mut self.ras {
+                    Some(ras) if is_return => ras.pop().unwrap_or(self.jalr_addr[idx]),
+                    _ => self.jalr_addr[idx],
+                };
+                if let (Some(ras), RA) = (&mut self.ras, op.rd) {
+                    ras.push(pc + 4);
+                }
+
                 let update = next_pc != predicted_pc;
+                if is_return {
+                    self.total_count_return += 1;
+                    self.flush_count_return += update as usize;
+                }
                 if update {
                     self.flush_count_jalr += 1;
                     self.jalr_addr[idx] = next_pc;
@@ -58,46 +611,13 @@ impl BranchPredictor {
             OpName::Beq | OpName::Bne | OpName::Blt | OpName::Bge => {
                 let untaken = pc.wrapping_add(4);
                 self.total_count_branch += 1;
-                let xor = self.gh ^ pci;
-                let taken = pc.wrapping_add(op.imm as usize);
-                let taken_idx = xor & TAKEN_PHT_MASK;
-                let selector_idx = xor & SELECTOR_PHT_MASK;
-
-                let taken_val = self.taken_pht[taken_idx];
-                let untaken_val = self.untaken_pht[taken_idx];
-                let selector_val = self.selector_pht[selector_idx];
-
-                let predicted_pc = if selector_val >= 2 {
-                    if taken_val >= 2 {
-                        taken
-                    } else {
-                        untaken
-                    }
-                } else if untaken_val >= 2 {
-                    taken
-                } else {
-                    untaken
-                };
-
-                let update = next_pc != untaken;
-                self.gh = ((self.gh << 1) | update as usize) & GH_MASK;
-
-                if update {
-                    self.taken_pht[taken_idx] = (taken_val + 1).min(3);
-                    self.untaken_pht[taken_idx] = (untaken_val + 1).min(3);
-                    self.selector_pht[selector_idx] = (selector_val + 1).min(3);
-                } else {
-                    self.taken_pht[taken_idx] = taken_val.saturating_sub(1);
-                    self.untaken_pht[taken_idx] = untaken_val.saturating_sub(1);
-                    self.selector_pht[selector_idx] = selector_val.saturating_sub(1);
-                }
+                // Wrap like execution does, or backward targets end up above 4 GiB.
+                let taken = (pc as u32).wrapping_add(op.imm) as usize;
 
-                if next_pc != predicted_pc {
-                    self.flush_count_branch += 1;
-                    true
-                } else {
-                    false
-                }
+                let predicted = self.direction.predict_update(pc, taken, next_pc != untaken);
+                let mispredicted = next_pc != if predicted { taken } else { untaken };
+                self.flush_count_branch += mispredicted as usize;
+                mispredicted
             }
             _ => false,
         }
@@ -112,18 +632,164 @@ impl Default for BranchPredictor {
 
 impl Display for BranchPredictor {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Branch predictor: {}", self.config)?;
         writeln!(
             f,
             "JALR Flush Count: {} ({:.02}%)",
             self.flush_count_jalr,
-            self.flush_count_jalr as f64 / self.total_count_jalr as f64 * 100.0
+            percent(self.flush_count_jalr as f64, self.total_count_jalr as f64)
         )?;
+        let indirect_flush = self.flush_count_jalr - self.flush_count_return;
+        let indirect = self.total_count_jalr - self.total_count_return;
+        writeln!(
+            f,
+            "  Returns: {} of {} ({:.02}%), indirect jumps: {} of {} ({:.02}%)",
+            self.flush_count_return,
+            self.total_count_return,
+            percent(
+                self.flush_count_return as f64,
+                self.total_count_return as f64
+            ),
+            indirect_flush,
+            indirect,
+            percent(indirect_flush as f64, indirect as f64)
+        )?;
+        match &self.ras {
+            Some(ras) => writeln!(
+                f,
+                "  Return address stack: {} entries, {} overflows, {} underflows",
+                ras.depth(),
+                ras.overflow_count,
+                ras.underflow_count
+            )?,
+            None => writeln!(f, "  Return address stack: none")?,
+        }
         write!(
             f,
             "Branch Flush Count: {} ({:.02}%)",
             self.flush_count_branch,
-            self.flush_count_branch as f64 / self.total_count_branch as f64 * 100.0
+            percent(
+                self.flush_count_branch as f64,
+                self.total_count_branch as f64
+            )
         )?;
         Ok(())
     }
 }
+
+/// The outcome of one predictor in a comparison run.
+#[derive(Debug, Clone, Serialize)]
+pub struct PredictorStat {
+    pub predictor: String,
+    pub branch: usize,
+    pub branch_flush: usize,
+    pub jalr: usize,
+    pub jalr_flush: usize,
+    pub returns: usize,
+    pub return_flush: usize,
+    /// The estimated cycle count had this predictor been used.
+    pub cycles: u64,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backward_branch_test() {
+        let op = OpV4 {
+            opname: OpName::Bne,
+            imm: -8i32 as u32,
+            ..Default::default()
+        };
+
+        let mut bp = BranchPredictor::with_config(PredictorConfig::Static);
+        assert!(!bp.update_taken(&op, 0x10, 0x08));
+        assert!(bp.update_taken(&op, 0x10, 0x14));
+        assert_eq!(bp.flush_count_branch, 1);
+    }
+
+    #[test]
+    fn config_test() {
+        for config in [
+            "static",
+            "bimodal,size=512",
+            "gshare,size=4096,history=12",
+            "tournament,pht=1024,selector=256,history=10",
+            "perceptron,size=64,history=8",
+        ] {
+            assert_eq!(
+                config.parse::<PredictorConfig>().unwrap().to_string(),
+                config
+            );
+        }
+        assert_eq!(
+            "tournament".parse::<PredictorConfig>().unwrap(),
+            PredictorConfig::default()
+        );
+        assert!("gshare,size=1000".parse::<PredictorConfig>().is_err());
+        assert!("static,size=4".parse::<PredictorConfig>().is_err());
+        assert!("twolevel".parse::<PredictorConfig>().is_err());
+    }
+
+    #[test]
+    fn loop_branch_test() {
+        // A loop branch at 0x8 taken 49 times, then falling through.
+        let mispredictions = |config: &str| {
+            let mut direction = config.parse::<PredictorConfig>().unwrap().build();
+            (1..=50)
+                .filter(|&i| {
+                    let taken = i < 50;
+                    direction.predict_update(0x8, 0x4, taken) != taken
+                })
+                .count()
+        };
+
+        // Only the loop exit goes against backward-taken, and gshare warms up a counter
+        // for each history pattern until the history is all taken.
+        assert_eq!(
+            ["tournament", "static", "bimodal", "gshare", "perceptron"].map(mispredictions),
+            [1, 1, 2, 12, 1]
+        );
+    }
+
+    #[test]
+    fn return_address_stack_test() {
+        let mut ras = ReturnAddressStack::new(2);
+        for addr in [4, 8, 12] {
+            ras.push(addr);
+        }
+        assert_eq!([ras.pop(), ras.pop(), ras.pop()], [Some(12), Some(8), None]);
+        assert_eq!((ras.overflow_count, ras.underflow_count), (1, 1));
+
+        // One function at 0x40 returning to four call sites.
+        let returns = |depth| {
+            let mut bp = BranchPredictor::new().with_ras(depth);
+            let call = OpV4 {
+                opname: OpName::Jal,
+                rd: RA,
+                ..Default::default()
+            };
+            let ret = OpV4 {
+                opname: OpName::Jalr,
+                rs1: RA,
+                ..Default::default()
+            };
+            for pc in [0x0, 0x4, 0x8, 0xc] {
+                bp.update_taken(&call, pc, 0x40);
+                bp.update_taken(&ret, 0x40, pc + 4);
+            }
+            (bp.total_count_return, bp.flush_count_return)
+        };
+        assert_eq!(returns(0), (4, 4));
+        assert_eq!(returns(2), (4, 0));
+    }
+
+    #[test]
+    fn empty_display_test() {
+        let bp = BranchPredictor::new();
+        let text = bp.to_string();
+        assert!(!text.contains("NaN"), "{}", text);
+        assert!(text.contains("Returns: 0 of 0 (0.00%), indirect jumps: 0 of 0 (0.00%)"));
+    }
+}
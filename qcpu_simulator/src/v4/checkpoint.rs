@@ -0,0 +1,728 @@
+use std::io::{self, Read, Write};
+
+use super::{
+    bp::Predictor,
+    memory::{CacheConfig, CacheLine, CacheStat, Replacement, WritePolicy},
+    stat::Statistics,
+    syntax::{OpName, OpV4},
+    BranchStat, Instat, SimulatorV4,
+};
+
+pub const CHECKPOINT_MAGIC: &[u8; 4] = b"QCKP";
+const CHECKPOINT_VERSION: u8 = 1;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn write_u32(w: &mut impl Write, v: u32) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn write_u64(w: &mut impl Write, v: u64) -> io::Result<()> {
+    w.write_all(&v.to_le_bytes())
+}
+
+fn read_u8(r: &mut impl Read) -> io::Result<u8> {
+    let mut buf = [0; 1];
+    r.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u32(r: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0; 4];
+    r.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64(r: &mut impl Read) -> io::Result<u64> {
+    let mut buf = [0; 8];
+    r.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+/// Reads a section of items that the simulator keeps only when statistics are enabled,
+/// `expected` of them. A section missing on either side is skipped and yields `None`.
+fn read_optional<R: Read, T>(
+    r: &mut R,
+    expected: usize,
+    mut read: impl FnMut(&mut R) -> io::Result<T>,
+) -> io::Result<Option<Vec<T>>> {
+    let len = read_u64(r)? as usize;
+
+    if expected == 0 {
+        for _ in 0..len {
+            read(r)?;
+        }
+        return Ok(None);
+    }
+
+    if len == 0 {
+        return Ok(None);
+    }
+
+    if len != expected {
+        return Err(invalid("Checkpoint section has a different size"));
+    }
+
+    (0..len)
+        .map(|_| read(r))
+        .collect::<io::Result<_>>()
+        .map(Some)
+}
+
+impl SimulatorV4<'_> {
+    /// FNV-1a over the decoded instructions, to tell programs of the same length apart.
+    fn program_hash(&self) -> u64 {
+        let mut hash = 0xcbf2_9ce4_8422_2325u64;
+        for op in &self.instructions {
+            let mut bytes = [0u8; 8];
+            bytes[0..4].copy_from_slice(&op.imm.to_le_bytes());
+            bytes[4..8].copy_from_slice(&[op.opname as u8, op.rd, op.rs1, op.rs2]);
+            for b in bytes {
+                hash = (hash ^ b as u64).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+
+    /// Writes the complete machine state to `w`. The output stream is flushed first so
+    /// that `output_offset` matches what has reached the output file.
+    ///
+    /// Per-instruction statistics and cache tags are only present when the simulator is
+    /// verbose; `restore_checkpoint` accepts checkpoints with or without them.
+    pub fn save_checkpoint(&mut self, mut w: impl Write) -> io::Result<()> {
+        self.output.flush()?;
+
+        w.write_all(CHECKPOINT_MAGIC)?;
+        w.write_all(&[CHECKPOINT_VERSION, cfg!(feature = "full_ops") as u8])?;
+        write_u64(&mut w, self.decoded_len as u64)?;
+        write_u64(&mut w, self.program_hash())?;
+
+        // Configurations come first, so a mismatch is found before anything is restored.
+        let config = &self.memory.config;
+        for v in [config.sets, config.line_words, config.ways] {
+            write_u64(&mut w, v as u64)?;
+        }
+        w.write_all(&[
+            config.replacement as u8,
+            config.write as u8,
+            config.write_allocate as u8,
+        ])?;
+        let config = self.bp.config.to_string();
+        write_u64(&mut w, config.len() as u64)?;
+        w.write_all(config.as_bytes())?;
+        write_u64(
+            &mut w,
+            self.bp.ras.as_ref().map_or(0, |ras| ras.depth()) as u64,
+        )?;
+
+        write_u32(&mut w, self.pc)?;
+        write_u32(&mut w, self.next_pc)?;
+        for &r in &self.reg {
+            write_u32(&mut w, r)?;
+        }
+        write_u32(&mut w, self.op.imm)?;
+        w.write_all(&[self.op.opname as u8, self.op.rd, self.op.rs1, self.op.rs2])?;
+
+        write_u64(&mut w, self.input_offset)?;
+        write_u64(&mut w, self.output_offset)?;
+
+        let stat = &self.stat;
+        for v in [
+            stat.instr_count,
+            stat.cycle_count,
+            stat.hazard_count,
+            stat.fpu_stall,
+            stat.forwarding_stall,
+        ] {
+            write_u64(&mut w, v)?;
+        }
+
+        write_u64(&mut w, self.per_instruction_stat.len() as u64)?;
+        for s in &self.per_instruction_stat {
+            write_u64(&mut w, s.hit)?;
+            write_u64(&mut w, s.call)?;
+            write_u64(&mut w, s.prev_ma)?;
+        }
+
+        write_u64(&mut w, self.per_branch_stat.len() as u64)?;
+        for s in &self.per_branch_stat {
+            write_u64(&mut w, s.call)?;
+            write_u64(&mut w, s.taken)?;
+            write_u64(&mut w, s.flush)?;
+        }
+
+        let memory = &self.memory;
+        write_u64(&mut w, memory.m.len() as u64)?;
+        for &word in &memory.m {
+            write_u32(&mut w, word)?;
+        }
+        let written = memory.shadow.as_ref().map_or(&[][..], |s| &s.written);
+        write_u64(&mut w, written.len() as u64)?;
+        for &bits in written {
+            write_u64(&mut w, bits)?;
+        }
+
+        write_u64(&mut w, memory.cache.len() as u64)?;
+        for line in &memory.cache {
+            write_u32(&mut w, line.tag())?;
+            w.write_all(&[line.referenced() as u8 | (line.dirty() as u8) << 1])?;
+        }
+
+        let cache_stat = &memory.stat;
+        for v in [
+            cache_stat.hit,
+            cache_stat.read,
+            cache_stat.write,
+            cache_stat.write_hit,
+            cache_stat.first_miss,
+            cache_stat.write_back,
+            cache_stat.memory_read,
+            cache_stat.memory_write,
+        ] {
+            write_u64(&mut w, v)?;
+        }
+
+        #[cfg(feature = "conflict_pair")]
+        {
+            write_u64(&mut w, cache_stat.conflict_pair.len() as u64)?;
+            for (&pair, &count) in &cache_stat.conflict_pair {
+                write_u32(&mut w, pair)?;
+                write_u64(&mut w, count)?;
+            }
+        }
+        #[cfg(not(feature = "conflict_pair"))]
+        write_u64(&mut w, 0)?;
+
+        let bp = &self.bp;
+        bp.direction.save(&mut w)?;
+        for &addr in &bp.jalr_addr {
+            write_u64(&mut w, addr as u64)?;
+        }
+        for v in [
+            bp.flush_count_jalr,
+            bp.total_count_jalr,
+            bp.flush_count_return,
+            bp.total_count_return,
+            bp.flush_count_branch,
+            bp.total_count_branch,
+        ] {
+            write_u64(&mut w, v as u64)?;
+        }
+
+        if let Some(ras) = &bp.ras {
+            for &addr in &ras.entries {
+                write_u64(&mut w, addr as u64)?;
+            }
+            for v in [ras.top, ras.len, ras.overflow_count, ras.underflow_count] {
+                write_u64(&mut w, v as u64)?;
+            }
+        }
+
+        w.flush()
+    }
+
+    /// Restores a state written by `save_checkpoint` into a simulator built for the same
+    /// program, then skips the already consumed part of the input stream.
+    ///
+    /// The output stream is left alone; the caller is expected to reopen the output at
+    /// `output_offset`.
+    pub fn restore_checkpoint(&mut self, mut r: impl Read) -> io::Result<()> {
+        let mut header = [0u8; 6];
+        r.read_exact(&mut header)?;
+
+        if &header[0..4] != CHECKPOINT_MAGIC || header[4] != CHECKPOINT_VERSION {
+            return Err(invalid("Not a qcpu checkpoint"));
+        }
+
+        if header[5] != cfg!(feature = "full_ops") as u8 {
+            return Err(invalid(
+                "Checkpoint was saved with a different `full_ops` setting",
+            ));
+        }
+
+        let len = read_u64(&mut r)?;
+        if len != self.decoded_len as u64 || read_u64(&mut r)? != self.program_hash() {
+            return Err(invalid("Checkpoint was saved for a different program"));
+        }
+
+        let config = CacheConfig {
+            sets: read_u64(&mut r)? as usize,
+            line_words: read_u64(&mut r)? as usize,
+            ways: read_u64(&mut r)? as usize,
+            replacement: match read_u8(&mut r)? {
+                0 => Replacement::Lru,
+                1 => Replacement::Fifo,
+                2 => Replacement::Sc,
+                _ => return Err(invalid("Unknown cache replacement policy")),
+            },
+            write: match read_u8(&mut r)? {
+                0 => WritePolicy::Back,
+                1 => WritePolicy::Through,
+                _ => return Err(invalid("Unknown cache write policy")),
+            },
+            write_allocate: read_u8(&mut r)? != 0,
+        };
+        if config != self.memory.config {
+            return Err(invalid(
+                "Checkpoint was saved with a different cache configuration",
+            ));
+        }
+
+        let len = read_u64(&mut r)?;
+        if len > 256 {
+            return Err(invalid("Checkpoint has an invalid branch predictor"));
+        }
+        let mut config = vec![0; len as usize];
+        r.read_exact(&mut config)?;
+        if config != self.bp.config.to_string().as_bytes() {
+            return Err(invalid(
+                "Checkpoint was saved with a different branch predictor",
+            ));
+        }
+
+        let depth = read_u64(&mut r)? as usize;
+        if depth != self.bp.ras.as_ref().map_or(0, |ras| ras.depth()) {
+            return Err(invalid(
+                "Checkpoint was saved with a different return address stack",
+            ));
+        }
+
+        // Everything is read before any state changes, so a failed restore leaves the
+        // simulator as it was.
+        let pc = read_u32(&mut r)?;
+        let next_pc = read_u32(&mut r)?;
+        let mut reg = self.reg;
+        for reg in reg.iter_mut() {
+            *reg = read_u32(&mut r)?;
+        }
+
+        let imm = read_u32(&mut r)?;
+        let mut fields = [0u8; 4];
+        r.read_exact(&mut fields)?;
+        let opname = OpName::from_repr(fields[0]).ok_or_else(|| invalid("Unknown opname"))?;
+
+        // The `debug` feature adds fields that are not part of the checkpoint.
+        #[allow(clippy::needless_update)]
+        let op = OpV4 {
+            imm,
+            opname,
+            rd: fields[1],
+            rs1: fields[2],
+            rs2: fields[3],
+            ..Default::default()
+        };
+
+        let input_offset = read_u64(&mut r)?;
+        let output_offset = read_u64(&mut r)?;
+
+        let stat = Statistics {
+            instr_count: read_u64(&mut r)?,
+            cycle_count: read_u64(&mut r)?,
+            hazard_count: read_u64(&mut r)?,
+            fpu_stall: read_u64(&mut r)?,
+            forwarding_stall: read_u64(&mut r)?,
+        };
+
+        let per_instruction_stat = read_optional(&mut r, self.per_instruction_stat.len(), |r| {
+            Ok(Instat {
+                hit: read_u64(r)?,
+                call: read_u64(r)?,
+                prev_ma: read_u64(r)?,
+            })
+        })?;
+        let per_branch_stat = read_optional(&mut r, self.per_branch_stat.len(), |r| {
+            Ok(BranchStat {
+                call: read_u64(r)?,
+                taken: read_u64(r)?,
+                flush: read_u64(r)?,
+            })
+        })?;
+
+        if read_u64(&mut r)? != self.memory.m.len() as u64 {
+            return Err(invalid("Checkpoint has a different memory size"));
+        }
+        let m = (0..self.memory.m.len())
+            .map(|_| read_u32(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+        // Without a bitmap in the checkpoint, every word counts as written.
+        let len = read_u64(&mut r)? as usize;
+        let written = (0..len)
+            .map(|_| read_u64(&mut r))
+            .collect::<io::Result<Vec<_>>>()?;
+        if let Some(shadow) = &self.memory.shadow {
+            if len != 0 && len != shadow.written.len() {
+                return Err(invalid("Checkpoint has a different memory size"));
+            }
+        }
+
+        let cache = read_optional(&mut r, self.memory.cache.len(), |r| {
+            let tag = read_u32(r)?;
+            let flags = read_u8(r)?;
+            Ok(CacheLine::with_tag(tag, flags & 1 != 0, flags & 2 != 0))
+        })?;
+
+        // Only `conflict_pair` needs the defaults and the `mut`.
+        #[allow(clippy::needless_update, unused_mut)]
+        let mut cache_stat = CacheStat {
+            hit: read_u64(&mut r)?,
+            read: read_u64(&mut r)?,
+            write: read_u64(&mut r)?,
+            write_hit: read_u64(&mut r)?,
+            first_miss: read_u64(&mut r)?,
+            write_back: read_u64(&mut r)?,
+            memory_read: read_u64(&mut r)?,
+            memory_write: read_u64(&mut r)?,
+            ..Default::default()
+        };
+        for _ in 0..read_u64(&mut r)? {
+            let _pair = read_u32(&mut r)?;
+            let _count = read_u64(&mut r)?;
+            #[cfg(feature = "conflict_pair")]
+            cache_stat.conflict_pair.insert(_pair, _count);
+        }
+
+        let mut direction = self.bp.config.build();
+        direction.restore(&mut r)?;
+        let mut jalr_addr = self.bp.jalr_addr;
+        for addr in jalr_addr.iter_mut() {
+            *addr = read_u64(&mut r)? as usize;
+        }
+        let mut counts = [0; 6];
+        for count in counts.iter_mut() {
+            *count = read_u64(&mut r)? as usize;
+        }
+
+        let ras = match &self.bp.ras {
+            Some(_) => {
+                let entries = (0..depth)
+                    .map(|_| Ok(read_u64(&mut r)? as usize))
+                    .collect::<io::Result<Vec<_>>>()?;
+                let mut fields = [0; 4];
+                for field in fields.iter_mut() {
+                    *field = read_u64(&mut r)? as usize;
+                }
+                let [top, len, _, _] = fields;
+                if top >= depth || len > depth {
+                    return Err(invalid("Checkpoint has an invalid return address stack"));
+                }
+                Some((entries, fields))
+            }
+            None => None,
+        };
+
+        let skipped = io::copy(&mut (&mut self.input).take(input_offset), &mut io::sink())?;
+        if skipped != input_offset {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Input is shorter than the checkpoint's input offset",
+            ));
+        }
+
+        self.pc = pc;
+        self.next_pc = next_pc;
+        self.reg = reg;
+        self.op = op;
+        self.input_offset = input_offset;
+        self.output_offset = output_offset;
+        self.stat = stat;
+        if let Some(s) = per_instruction_stat {
+            self.per_instruction_stat = s;
+        }
+        if let Some(s) = per_branch_stat {
+            self.per_branch_stat = s;
+        }
+
+        let memory = &mut self.memory;
+        memory.m = m;
+        if let Some(shadow) = memory.shadow.as_mut() {
+            if written.is_empty() {
+                shadow.mark_all();
+            } else {
+                shadow.written = written;
+            }
+        }
+        if let Some(cache) = cache {
+            memory.cache = cache;
+        }
+        memory.stat = cache_stat;
+
+        let bp = &mut self.bp;
+        bp.direction = direction;
+        bp.jalr_addr = jalr_addr;
+        [
+            bp.flush_count_jalr,
+            bp.total_count_jalr,
+            bp.flush_count_return,
+            bp.total_count_return,
+            bp.flush_count_branch,
+            bp.total_count_branch,
+        ] = counts;
+        if let (Some(ras), Some((entries, fields))) = (bp.ras.as_mut(), ras) {
+            ras.entries = entries;
+            [ras.top, ras.len, ras.overflow_count, ras.underflow_count] = fields;
+        }
+
+        self.cache_hit = false;
+        self.memory.watch_hit = None;
+        self.memory.last_access = None;
+        self.skip_breakpoint = None;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{shadow::UninitCheck, SimulatorV4, SimulatorV4Builder};
+
+    #[test]
+    pub fn checkpoint_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a1, zero, 4
+loop:
+    inw     a0
+    sw      a0, 32(a1)
+    lw      a2, 32(a1)
+    outb    a2
+    addi    a1, a1, -1
+    blt     zero, a1, loop
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let input = [b'a', b'b', b'c', b'd']
+            .iter()
+            .flat_map(|&b| (b as u32).to_le_bytes())
+            .collect::<Vec<_>>();
+
+        let mut checkpoint = Vec::new();
+        let mut output = Vec::new();
+        let expected = {
+            let mut sim = SimulatorV4Builder::default()
+                .with_program(mc.clone())
+                .with_input(input.as_slice())
+                .with_output(&mut output)
+                .verbose(true)
+                .build();
+
+            sim.run_for(13);
+            sim.save_checkpoint(&mut checkpoint).unwrap();
+            assert_eq!((sim.input_offset, sim.output_offset), (8, 2));
+
+            sim.run();
+            (sim.reg, sim.memory.stat.read, sim.bp.total_count_branch)
+        };
+        assert_eq!(output, b"abcd");
+
+        let mut resumed = b"ab".to_vec();
+        for verbose in [true, false] {
+            resumed.truncate(2);
+            let mut sim = SimulatorV4Builder::default()
+                .with_program(mc.clone())
+                .with_input(input.as_slice())
+                .with_output(&mut resumed)
+                .verbose(verbose)
+                .build();
+
+            sim.restore_checkpoint(checkpoint.as_slice()).unwrap();
+            sim.run();
+
+            assert_eq!(sim.reg, expected.0);
+            if verbose {
+                assert_eq!(sim.memory.stat.read, expected.1);
+                assert_eq!(sim.bp.total_count_branch, expected.2);
+            }
+            drop(sim);
+            assert_eq!(resumed, b"abcd");
+        }
+
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc[1..].to_vec())
+            .build();
+        assert!(sim.restore_checkpoint(checkpoint.as_slice()).is_err());
+
+        let (other, _ctx) =
+            qcpu_assembler::v2::assemble(&code.replace("zero, 4", "zero, 5"), false).unwrap();
+        assert_eq!(other.len(), mc.len());
+        let mut sim = SimulatorV4Builder::default().with_program(other).build();
+        assert!(sim.restore_checkpoint(checkpoint.as_slice()).is_err());
+    }
+
+    #[test]
+    pub fn truncated_checkpoint_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a1, zero, 4
+loop:
+    sw      a1, 32(a1)
+    addi    a1, a1, -1
+    blt     zero, a1, loop
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let mut checkpoint = Vec::new();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .verbose(true)
+            .build();
+        sim.run_for(8);
+        sim.save_checkpoint(&mut checkpoint).unwrap();
+        let saved = (sim.pc, sim.reg);
+
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .verbose(true)
+            .build();
+        sim.run_for(2);
+        let before = (
+            sim.pc,
+            sim.reg,
+            sim.memory.m.clone(),
+            sim.bp.total_count_branch,
+        );
+
+        // Cut off in the last field and in the middle of memory.
+        for len in [checkpoint.len() - 1, checkpoint.len() / 2] {
+            assert!(sim.restore_checkpoint(&checkpoint[..len]).is_err());
+            assert_eq!(
+                (
+                    sim.pc,
+                    sim.reg,
+                    sim.memory.m.clone(),
+                    sim.bp.total_count_branch
+                ),
+                before
+            );
+        }
+
+        sim.restore_checkpoint(checkpoint.as_slice()).unwrap();
+        assert_eq!((sim.pc, sim.reg), saved);
+    }
+
+    #[test]
+    pub fn cache_config_mismatch_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a1, zero, 4
+loop:
+    lw      a0, 0(zero)
+    addi    a1, a1, -1
+    blt     zero, a1, loop
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let mut checkpoint = Vec::new();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .verbose(true)
+            .build();
+        sim.run_for(5);
+        sim.save_checkpoint(&mut checkpoint).unwrap();
+
+        // A mismatch leaves the simulator untouched.
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .with_cache("ways=2".parse().unwrap())
+            .verbose(true)
+            .build();
+        assert!(sim.restore_checkpoint(checkpoint.as_slice()).is_err());
+        assert_eq!((sim.pc, sim.reg[11], sim.stat.instr_count), (0, 0, 0));
+    }
+
+    #[test]
+    pub fn return_address_stack_test() {
+        // One function returning to four call sites.
+        let code = r#"
+_min_caml_start:
+    jal     ra, f
+    jal     ra, f
+    jal     ra, f
+    jal     ra, f
+    jal     zero, end
+f:
+    jalr    zero, ra, 0
+end:
+    addi    a0, zero, 0
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let build = |depth| {
+            SimulatorV4Builder::default()
+                .with_program(mc.clone())
+                .with_ras(depth)
+                .verbose(true)
+                .build()
+        };
+
+        let mut sim = build(2);
+        sim.run_for(2);
+        let mut checkpoint = Vec::new();
+        sim.save_checkpoint(&mut checkpoint).unwrap();
+        assert!(build(4).restore_checkpoint(checkpoint.as_slice()).is_err());
+
+        // The pending return address survives the checkpoint.
+        let mut sim = build(2);
+        sim.restore_checkpoint(checkpoint.as_slice()).unwrap();
+        sim.run();
+        assert_eq!(
+            (sim.bp.total_count_return, sim.bp.flush_count_return),
+            (4, 0)
+        );
+    }
+
+    #[test]
+    pub fn shadow_memory_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 7
+    sw      a0, 8(zero)
+    lw      a1, 8(zero)
+    lw      a2, 12(zero)
+    lw      a3, 16(zero)
+    addi    a4, zero, 2
+loop:
+    lw      a5, 20(zero)
+    addi    a4, a4, -1
+    blt     zero, a4, loop
+        "#;
+        let (mc, ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let build = |check| {
+            SimulatorV4Builder::default()
+                .with_program(mc.clone())
+                .with_context(ctx.clone())
+                .with_uninit_check(check)
+                .build()
+        };
+        let reads = |sim: &SimulatorV4| {
+            let shadow = sim.memory.shadow.as_ref().unwrap();
+            shadow
+                .reads()
+                .iter()
+                .map(|r| (r.line, r.count, r.addr))
+                .collect::<Vec<_>>()
+        };
+
+        // Checkpoints keep the written words.
+        let mut sim = build(UninitCheck::Report);
+        sim.run_for(2);
+        let mut checkpoint = Vec::new();
+        sim.save_checkpoint(&mut checkpoint).unwrap();
+        let mut sim = build(UninitCheck::Report);
+        sim.restore_checkpoint(checkpoint.as_slice()).unwrap();
+        sim.run();
+        assert_eq!(reads(&sim), [(3, 1, 12), (4, 1, 16), (6, 2, 20)]);
+
+        // Memory from a checkpoint without them counts as initial data.
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .build();
+        sim.run_for(2);
+        let mut checkpoint = Vec::new();
+        sim.save_checkpoint(&mut checkpoint).unwrap();
+        let mut sim = build(UninitCheck::Report);
+        sim.restore_checkpoint(checkpoint.as_slice()).unwrap();
+        sim.run();
+        assert_eq!(reads(&sim), []);
+    }
+}
@@ -2,7 +2,30 @@ use bitvec::{field::BitField, order::Lsb0, view::BitView};
 
 use super::syntax::{OpCode, OpName, OpV4, Reg};
 
+/// Decodes one instruction word. Words that do not encode a supported instruction
+/// become an illegal `Raw` op holding the word in `imm`, which halts when executed.
 pub fn decode(mc: u32) -> OpV4 {
+    try_decode(mc).unwrap_or_else(|| illegal(mc))
+}
+
+fn illegal(mc: u32) -> OpV4 {
+    cfg_if::cfg_if! {
+        if #[cfg(feature = "debug")] {
+            OpV4 {
+                imm: mc,
+                mc,
+                ..Default::default()
+            }
+        } else {
+            OpV4 {
+                imm: mc,
+                ..Default::default()
+            }
+        }
+    }
+}
+
+fn try_decode(mc: u32) -> Option<OpV4> {
     let bits = mc.view_bits::<Lsb0>();
     let opcode = match bits[0..4].load::<u32>() {
         super::syntax::R_CODE => OpCode::R,
@@ -21,7 +44,7 @@ pub fn decode(mc: u32) -> OpV4 {
         super::syntax::LU_CODE => OpCode::LU,
         #[cfg(feature = "full_ops")]
         super::syntax::SU_CODE => OpCode::SU,
-        opcode => unimplemented!("Not supported for {opcode:04b}"),
+        _ => return None,
     };
 
     let mut imm: u32 = 0;
@@ -75,7 +98,7 @@ pub fn decode(mc: u32) -> OpV4 {
             super::syntax::ADDI_FUNC3 => OpName::Addi,
             super::syntax::SLLI_FUNC3 => OpName::Slli,
             super::syntax::SRLI_FUNC3 => OpName::Srli,
-            _ => unimplemented!("{funct3} not supported for {opcode:?}"),
+            _ => return None,
         },
         OpCode::R => match funct3 {
             super::syntax::ADDSUB_FUNC3 => {
@@ -93,7 +116,7 @@ pub fn decode(mc: u32) -> OpV4 {
             super::syntax::OR_FUNC3 => OpName::Or,
             #[cfg(feature = "full_ops")]
             super::syntax::AND_FUNC3 => OpName::And,
-            _ => unimplemented!("{funct3} not supported for {opcode:?} {mc:032b}"),
+            _ => return None,
         },
         OpCode::L => super::syntax::OpName::Lw,
         OpCode::S => super::syntax::OpName::Sw,
@@ -107,7 +130,7 @@ pub fn decode(mc: u32) -> OpV4 {
             super::syntax::BNE_FUNC3 => OpName::Bne,
             super::syntax::BLT_FUNC3 => OpName::Blt,
             super::syntax::BGE_FUNC3 => OpName::Bge,
-            _ => unimplemented!("{funct3} not supported for {opcode:?}"),
+            _ => return None,
         },
         OpCode::A => OpName::Jalr,
         OpCode::J => OpName::Jal,
@@ -126,15 +149,15 @@ pub fn decode(mc: u32) -> OpV4 {
                 super::syntax::FSGNJ_FUNC3 => OpName::Fsgnj,
                 super::syntax::FSGNJN_FUNC3 => OpName::Fsgnjn,
                 super::syntax::FSGNJX_FUNC3 => OpName::Fsgnjx,
-                _ => unimplemented!("{funct3} not supported for {opcode:?}"),
+                _ => return None,
             },
             super::syntax::FCMP_FUNC7 => match funct3 {
                 super::syntax::FEQ_FUNC3 => OpName::Feq,
                 super::syntax::FLT_FUNC3 => OpName::Flt,
                 super::syntax::FLE_FUNC3 => OpName::Fle,
-                _ => unimplemented!("{funct3} not supported for {opcode:?}"),
+                _ => return None,
             },
-            _ => unimplemented!("{mc} not supported for {opcode:?}"),
+            _ => return None,
         },
     };
 
@@ -187,7 +210,7 @@ pub fn decode(mc: u32) -> OpV4 {
 
     cfg_if::cfg_if! {
         if #[cfg(feature = "debug")] {
-            OpV4 {
+            Some(OpV4 {
                 opcode,
                 opname,
                 rd,
@@ -195,15 +218,15 @@ pub fn decode(mc: u32) -> OpV4 {
                 rs2,
                 imm,
                 mc
-            }
+            })
         } else {
-            OpV4 {
+            Some(OpV4 {
                 opname,
                 rd,
                 rs1,
                 rs2,
                 imm,
-            }
+            })
         }
     }
 }
@@ -0,0 +1,461 @@
+use std::{
+    fmt::Write as _,
+    io::{self, Read, Write},
+    net::TcpStream,
+};
+
+use super::{
+    memory::MEMORY_SIZE,
+    syntax::{get_reg_name, Reg},
+    SimulatorV4, SimulatorV4HaltKind,
+};
+
+/// Instructions run between checks for a GDB interrupt (Ctrl-C) while continuing.
+const RESUME_CHUNK: u64 = 1 << 16;
+
+/// GDB register numbers: x0-x31, pc, f0-f31, then the FP CSRs, which read as zero.
+const PC_REGNUM: usize = 32;
+const FIRST_FREG_REGNUM: usize = 33;
+const FREG_END_REGNUM: usize = FIRST_FREG_REGNUM + 32;
+const FFLAGS_REGNUM: usize = FREG_END_REGNUM;
+const FCSR_REGNUM: usize = FFLAGS_REGNUM + 2;
+
+pub enum GdbAction {
+    Reply(String),
+    Resume { step: bool },
+    Detach,
+    Kill,
+}
+
+/// Serves the GDB remote serial protocol for a [`SimulatorV4`].
+///
+/// Data memory is word-addressed in the simulator, so word `n` of `MemoryV4::m` is exposed
+/// to GDB at byte address `4 * n`. Breakpoints are handled by the simulator (`Z0`/`z0`)
+/// rather than by patching memory, since the program is not stored in data memory.
+pub struct GdbStub<'s, 'a> {
+    pub sim: &'s mut SimulatorV4<'a>,
+}
+
+impl<'s, 'a> GdbStub<'s, 'a> {
+    pub fn new(sim: &'s mut SimulatorV4<'a>) -> Self {
+        Self { sim }
+    }
+
+    /// Handles one connection until GDB detaches, kills the target or disconnects.
+    pub fn serve(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_nodelay(true)?;
+
+        while let Some(packet) = read_packet(&mut stream)? {
+            stream.write_all(b"+")?;
+
+            let reply = match self.handle_packet(&packet) {
+                GdbAction::Reply(reply) => reply,
+                GdbAction::Resume { step } => self.resume(step, || poll_interrupt(&mut stream)),
+                GdbAction::Detach => {
+                    write_packet(&mut stream, "OK")?;
+                    return Ok(());
+                }
+                GdbAction::Kill => return Ok(()),
+            };
+
+            write_packet(&mut stream, &reply)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn handle_packet(&mut self, packet: &str) -> GdbAction {
+        let reply = match packet.as_bytes().first() {
+            Some(b'?') => "S05".to_string(),
+            Some(b'g') => self.read_registers(),
+            Some(b'G') => self.write_registers(&packet[1..]),
+            Some(b'p') => usize::from_str_radix(&packet[1..], 16)
+                .ok()
+                .and_then(|n| self.read_register(n))
+                .map(hex_word)
+                .unwrap_or_else(|| "E01".to_string()),
+            Some(b'P') => self.write_register(&packet[1..]),
+            Some(b'm') => self.read_memory(&packet[1..]),
+            Some(b'M') => self.write_memory(&packet[1..]),
+            Some(b'Z') | Some(b'z') => self.breakpoint(packet),
+            Some(b's') | Some(b'c') => {
+                if let Some(pc) = packet.get(1..).filter(|a| !a.is_empty()) {
+                    match u32::from_str_radix(pc, 16) {
+                        Ok(pc) => self.sim.pc = pc,
+                        Err(_) => return GdbAction::Reply("E01".to_string()),
+                    }
+                }
+                return GdbAction::Resume {
+                    step: packet.starts_with('s'),
+                };
+            }
+            Some(b'D') => return GdbAction::Detach,
+            Some(b'k') => return GdbAction::Kill,
+            Some(b'H') => "OK".to_string(),
+            _ => self.query(packet),
+        };
+
+        GdbAction::Reply(reply)
+    }
+
+    /// Runs the simulator and returns the stop reply. `interrupted` is polled between
+    /// chunks of instructions while continuing.
+    pub fn resume(&mut self, step: bool, mut interrupted: impl FnMut() -> bool) -> String {
+        let halt = if step {
+            match self.sim.step() {
+                Ok(()) => return "S05".to_string(),
+                Err(halt) => halt,
+            }
+        } else {
+            loop {
+                let halt = self.sim.run_for(RESUME_CHUNK);
+                if !matches!(halt.kind, SimulatorV4HaltKind::InstructionLimit { .. }) {
+                    break halt;
+                }
+                if interrupted() {
+                    return "S02".to_string();
+                }
+            }
+        };
+
+        match halt.kind {
+            SimulatorV4HaltKind::Complete => "W00".to_string(),
+            SimulatorV4HaltKind::IllegalInstruction { .. } => "S04".to_string(),
+            SimulatorV4HaltKind::MemoryAccess { .. }
+            | SimulatorV4HaltKind::JumpOutOfBounds { .. } => "S0b".to_string(),
+            _ => "S05".to_string(),
+        }
+    }
+
+    fn query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=4000;qXfer:features:read+;swbreak+".to_string()
+        } else if let Some(annex) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = annex.split_once(',').and_then(|(o, l)| {
+                Some((
+                    usize::from_str_radix(o, 16).ok()?,
+                    usize::from_str_radix(l, 16).ok()?,
+                ))
+            }) else {
+                return "E01".to_string();
+            };
+            let xml = target_xml();
+            let chunk = &xml[offset.min(xml.len())..(offset + length).min(xml.len())];
+            if offset + length >= xml.len() {
+                format!("l{}", chunk)
+            } else {
+                format!("m{}", chunk)
+            }
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn read_register(&self, n: usize) -> Option<u32> {
+        match n {
+            0..PC_REGNUM => Some(self.sim.reg[n]),
+            PC_REGNUM => Some(self.sim.pc),
+            FIRST_FREG_REGNUM..FREG_END_REGNUM => Some(self.sim.reg[n - 1]),
+            FFLAGS_REGNUM..=FCSR_REGNUM => Some(0),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, n: usize, val: u32) -> bool {
+        match n {
+            0..PC_REGNUM => self.sim.set_reg(n as Reg, val),
+            PC_REGNUM => self.sim.pc = val,
+            FIRST_FREG_REGNUM..FREG_END_REGNUM => self.sim.set_reg((n - 1) as Reg, val),
+            FFLAGS_REGNUM..=FCSR_REGNUM => {}
+            _ => return false,
+        }
+        true
+    }
+
+    fn read_registers(&self) -> String {
+        (0..FREG_END_REGNUM)
+            .filter_map(|n| self.read_register(n))
+            .map(hex_word)
+            .collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        let Some(values) = parse_hex_bytes(data) else {
+            return "E01".to_string();
+        };
+        for (n, word) in values.chunks_exact(4).enumerate() {
+            self.set_register(n, u32::from_le_bytes([word[0], word[1], word[2], word[3]]));
+        }
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(n, v)| {
+            let bytes = parse_hex_bytes(v).filter(|b| b.len() == 4)?;
+            Some((
+                usize::from_str_radix(n, 16).ok()?,
+                u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            ))
+        });
+        match parsed {
+            Some((n, val)) if self.set_register(n, val) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let Some((addr, len)) = parse_addr_len(args) else {
+            return "E01".to_string();
+        };
+        if !in_memory(addr, len) {
+            return "E14".to_string();
+        }
+        (addr..addr + len).fold(String::with_capacity(len * 2), |mut s, a| {
+            let byte = self.sim.memory.m[a >> 2].to_le_bytes()[a & 3];
+            let _ = write!(s, "{:02x}", byte);
+            s
+        })
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((target, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let (Some((addr, len)), Some(data)) = (parse_addr_len(target), parse_hex_bytes(data))
+        else {
+            return "E01".to_string();
+        };
+        if data.len() != len || !in_memory(addr, len) {
+            return "E14".to_string();
+        }
+        for (a, byte) in (addr..).zip(data) {
+            let mut word = self.sim.memory.m[a >> 2].to_le_bytes();
+            word[a & 3] = byte;
+            self.sim.memory.m[a >> 2] = u32::from_le_bytes(word);
+            if let Some(shadow) = self.sim.memory.shadow.as_mut() {
+                shadow.mark(a >> 2);
+            }
+        }
+        "OK".to_string()
+    }
+
+    fn breakpoint(&mut self, packet: &str) -> String {
+        let mut fields = packet[1..].split(',');
+        let (Some(kind), Some(addr)) = (fields.next(), fields.next()) else {
+            return "E01".to_string();
+        };
+        // Only software and hardware execution breakpoints are supported.
+        if kind != "0" && kind != "1" {
+            return String::new();
+        }
+        let Ok(pc) = u32::from_str_radix(addr, 16) else {
+            return "E01".to_string();
+        };
+        if packet.starts_with('Z') {
+            self.sim.add_breakpoint(pc);
+        } else {
+            self.sim.remove_breakpoint(pc);
+        }
+        "OK".to_string()
+    }
+}
+
+fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\
+        <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\
+        <target version=\"1.0\">\
+        <architecture>riscv:rv32</architecture>\
+        <feature name=\"org.gnu.gdb.riscv.cpu\">",
+    );
+    for reg in 0..32 {
+        let _ = write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>",
+            get_reg_name(reg),
+            reg
+        );
+    }
+    let _ = write!(
+        xml,
+        "<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"{}\"/>\
+        </feature>\
+        <feature name=\"org.gnu.gdb.riscv.fpu\">",
+        PC_REGNUM
+    );
+    for reg in 32..64 {
+        let _ = write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"32\" type=\"ieee_single\" regnum=\"{}\"/>",
+            get_reg_name(reg),
+            reg as usize + 1
+        );
+    }
+    for (i, name) in ["fflags", "frm", "fcsr"].iter().enumerate() {
+        let _ = write!(
+            xml,
+            "<reg name=\"{}\" bitsize=\"32\" type=\"int\" regnum=\"{}\"/>",
+            name,
+            FFLAGS_REGNUM + i
+        );
+    }
+    xml.push_str("</feature></target>");
+    xml
+}
+
+fn hex_word(v: u32) -> String {
+    v.to_le_bytes()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+fn parse_hex_bytes(data: &str) -> Option<Vec<u8>> {
+    if !data.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(data.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn parse_addr_len(args: &str) -> Option<(usize, usize)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((
+        usize::from_str_radix(addr, 16).ok()?,
+        usize::from_str_radix(len, 16).ok()?,
+    ))
+}
+
+/// Whether the `len` bytes from byte address `addr` lie in the simulated memory.
+fn in_memory(addr: usize, len: usize) -> bool {
+    matches!(addr.checked_add(len), Some(end) if end <= MEMORY_SIZE * 4)
+}
+
+/// Reads the next `$...#xx` packet, skipping acks. Returns `None` when the client hangs up.
+fn read_packet(stream: &mut TcpStream) -> io::Result<Option<String>> {
+    let mut byte = [0u8; 1];
+
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'$' {
+            break;
+        }
+    }
+
+    let mut packet = Vec::new();
+    loop {
+        if stream.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        if byte[0] == b'#' {
+            break;
+        }
+        packet.push(byte[0]);
+    }
+
+    let mut checksum = [0u8; 2];
+    stream.read_exact(&mut checksum)?;
+
+    Ok(Some(String::from_utf8_lossy(&packet).into_owned()))
+}
+
+fn write_packet(stream: &mut TcpStream, data: &str) -> io::Result<()> {
+    let checksum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+    stream.write_all(format!("${}#{:02x}", data, checksum).as_bytes())?;
+    stream.flush()
+}
+
+/// Returns whether GDB sent an interrupt byte (0x03), without blocking.
+fn poll_interrupt(stream: &mut TcpStream) -> bool {
+    let mut byte = [0u8; 1];
+    if stream.set_nonblocking(true).is_err() {
+        return false;
+    }
+    let interrupted = matches!(stream.peek(&mut byte), Ok(1) if byte[0] == 0x03);
+    if interrupted {
+        let _ = stream.read_exact(&mut byte);
+    }
+    let _ = stream.set_nonblocking(false);
+    interrupted
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::SimulatorV4Builder;
+
+    fn reply(stub: &mut GdbStub, packet: &str) -> String {
+        match stub.handle_packet(packet) {
+            GdbAction::Reply(reply) => reply,
+            _ => panic!("expected a reply to {}", packet),
+        }
+    }
+
+    #[test]
+    fn gdb_packets() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 7
+    sw      a0, 4(zero)
+done:
+    addi    a1, a0, 1
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let mut sim = SimulatorV4Builder::default().with_program(mc).build();
+        let mut stub = GdbStub::new(&mut sim);
+
+        assert_eq!(reply(&mut stub, "Z0,8,4"), "OK");
+        assert!(matches!(
+            stub.handle_packet("c"),
+            GdbAction::Resume { step: false }
+        ));
+        assert_eq!(stub.resume(false, || false), "S05");
+
+        assert_eq!(reply(&mut stub, "p20"), "08000000");
+        assert_eq!(reply(&mut stub, "pa"), "07000000");
+        assert_eq!(reply(&mut stub, "m10,4"), "07000000");
+
+        let regs = reply(&mut stub, "g");
+        assert_eq!(regs.len(), 65 * 8);
+        assert_eq!(&regs[10 * 8..11 * 8], "07000000");
+
+        assert_eq!(reply(&mut stub, "P2b=0000803f"), "OK");
+        assert_eq!(stub.sim.reg[42], 1.0f32.to_bits());
+        assert_eq!(reply(&mut stub, "M11,2:3412"), "OK");
+        assert_eq!(stub.sim.memory.m[4], 0x00123407);
+
+        assert_eq!(reply(&mut stub, "z0,8,4"), "OK");
+        assert_eq!(stub.resume(true, || false), "S05");
+        assert_eq!(stub.sim.get_reg(11), 8);
+        assert_eq!(stub.resume(false, || false), "W00");
+
+        assert!(reply(&mut stub, "qXfer:features:read:target.xml:0,1000").starts_with("l<?xml"));
+    }
+
+    #[test]
+    fn gdb_memory_bounds() {
+        let (mc, _ctx) = qcpu_assembler::v2::assemble("_min_caml_start:\n", false).unwrap();
+        let mut sim = SimulatorV4Builder::default().with_program(mc).build();
+        let mut stub = GdbStub::new(&mut sim);
+        let end = MEMORY_SIZE * 4;
+
+        assert_eq!(reply(&mut stub, &format!("m{:x},4", end - 4)), "00000000");
+        assert_eq!(reply(&mut stub, &format!("m{:x},4", end - 3)), "E14");
+        assert_eq!(reply(&mut stub, &format!("m{:x},2", usize::MAX)), "E14");
+        assert_eq!(reply(&mut stub, &format!("m4,{:x}", usize::MAX)), "E14");
+        assert_eq!(reply(&mut stub, &format!("M{:x},1:ff", usize::MAX)), "E14");
+        assert_eq!(reply(&mut stub, &format!("M{:x},1:ff", end - 1)), "OK");
+    }
+}
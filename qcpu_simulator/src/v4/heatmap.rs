@@ -0,0 +1,220 @@
+//! Read, write and cache miss counts per memory region, to see where the stack, the
+//! heap and the static data of a program lie and where misses cluster.
+
+use std::io;
+
+use serde::Serialize;
+use strum_macros::{Display, EnumString};
+
+use super::{memory::MEMORY_SIZE, SimulatorV4};
+
+/// Words per 1 KiB page.
+const PAGE_WORDS: usize = 256;
+
+/// The size of a heatmap region.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Granularity {
+    Word,
+    /// A cache line of the simulated cache.
+    Line,
+    /// 1 KiB.
+    Page,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct RegionStat {
+    pub reads: u64,
+    pub writes: u64,
+    pub misses: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Heatmap {
+    pub granularity: Granularity,
+    /// log2 of the words per region.
+    shift: u32,
+    pub regions: Vec<RegionStat>,
+}
+
+impl Heatmap {
+    /// `line_words` is the cache line size `Granularity::Line` uses.
+    pub fn new(granularity: Granularity, line_words: usize) -> Self {
+        let shift = match granularity {
+            Granularity::Word => 0,
+            Granularity::Line => line_words.trailing_zeros(),
+            Granularity::Page => PAGE_WORDS.trailing_zeros(),
+        };
+        Self {
+            granularity,
+            shift,
+            regions: vec![RegionStat::default(); MEMORY_SIZE >> shift],
+        }
+    }
+
+    pub fn region_words(&self) -> usize {
+        1 << self.shift
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, addr: usize, write: bool, hit: bool) {
+        if let Some(region) = self.regions.get_mut(addr >> self.shift) {
+            region.reads += !write as u64;
+            region.writes += write as u64;
+            region.misses += !hit as u64;
+        }
+    }
+
+    /// Writes one line per accessed region, with word addresses.
+    pub fn write_csv(&self, mut w: impl io::Write) -> io::Result<()> {
+        writeln!(w, "start,words,reads,writes,misses")?;
+        for (i, region) in self.regions.iter().enumerate() {
+            if *region != RegionStat::default() {
+                writeln!(
+                    w,
+                    "{},{},{},{},{}",
+                    i << self.shift,
+                    self.region_words(),
+                    region.reads,
+                    region.writes,
+                    region.misses
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Renders the regions row by row from address 0 and returns the width, the height
+    /// and the RGB bytes of the image. The rows hold a power of two regions, about the
+    /// square root of their count, and each region is a square block of pixels scaled up
+    /// so that rows of fewer than 512 regions are 512 pixels wide. Red is misses, green
+    /// reads and blue writes, each on a log scale relative to the busiest region.
+    pub fn image(&self) -> (usize, usize, Vec<u8>) {
+        let count = self.regions.len();
+        let columns = 1 << count.trailing_zeros().div_ceil(2);
+        let rows = count / columns;
+        let scale = (512 / columns).max(1);
+
+        let max = self
+            .regions
+            .iter()
+            .fold(RegionStat::default(), |m, r| RegionStat {
+                reads: m.reads.max(r.reads),
+                writes: m.writes.max(r.writes),
+                misses: m.misses.max(r.misses),
+            });
+        let level = |n: u64, max: u64| match n {
+            0 => 0,
+            _ => (((n as f64).ln_1p() / (max as f64).ln_1p()) * 223.0) as u8 + 32,
+        };
+
+        let (width, height) = (columns * scale, rows * scale);
+        let mut image = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let region = &self.regions[y / scale * columns + x / scale];
+                image.extend([
+                    level(region.misses, max.misses),
+                    level(region.reads, max.reads),
+                    level(region.writes, max.writes),
+                ]);
+            }
+        }
+        (width, height, image)
+    }
+}
+
+impl SimulatorV4<'_> {
+    /// Starts counting accesses per region. Statistics must be enabled, since misses
+    /// come from the cache simulation.
+    pub fn start_heatmap(&mut self, granularity: Granularity) {
+        self.memory.heatmap = Some(Box::new(Heatmap::new(
+            granularity,
+            self.memory.config.line_words,
+        )));
+    }
+
+    pub fn finish_heatmap(&mut self) -> Option<Heatmap> {
+        self.memory.heatmap.take().map(|h| *h)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::SimulatorV4Builder;
+
+    fn region(reads: u64, writes: u64, misses: u64) -> RegionStat {
+        RegionStat {
+            reads,
+            writes,
+            misses,
+        }
+    }
+
+    /// The accesses of `sw 0`, `sw 300`, `lw 0` and `lw 4` on an empty cache.
+    fn heatmap(granularity: Granularity) -> Heatmap {
+        let mut heatmap = Heatmap::new(granularity, 4);
+        for (addr, write, hit) in [(0, true, false), (300, true, false), (0, false, true)] {
+            heatmap.record(addr, write, hit);
+        }
+        heatmap.record(4, false, false);
+        heatmap
+    }
+
+    #[test]
+    pub fn region_test() {
+        let lines = heatmap(Granularity::Line);
+        assert_eq!(lines.region_words(), 4);
+        assert_eq!(lines.regions[0], region(1, 1, 1));
+        assert_eq!(lines.regions[1], region(1, 0, 1));
+        assert_eq!(lines.regions[75], region(0, 1, 1));
+
+        let mut csv = Vec::new();
+        heatmap(Granularity::Page).write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "start,words,reads,writes,misses\n0,256,2,1,2\n256,256,0,1,1\n"
+        );
+    }
+
+    #[test]
+    pub fn image_test() {
+        // 2048 pages in 64 columns, 8 pixels each.
+        let (width, height, image) = heatmap(Granularity::Page).image();
+        assert_eq!((width, height, image.len()), (512, 256, 512 * 256 * 3));
+        assert_eq!(image[0..3], [255, 255, 255]);
+        assert_eq!(image[8 * 3..9 * 3], [172, 0, 255]);
+        assert_eq!(image[16 * 3..17 * 3], [0, 0, 0]);
+    }
+
+    #[test]
+    pub fn simulator_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 7
+    sw      a0, 0(zero)
+    sw      a0, 300(zero)
+    lw      a1, 0(zero)
+    lw      a1, 4(zero)
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .verbose(true)
+            .build();
+        sim.start_heatmap(Granularity::Line);
+        sim.run();
+
+        let csv = |heatmap: Heatmap| {
+            let mut csv = Vec::new();
+            heatmap.write_csv(&mut csv).unwrap();
+            String::from_utf8(csv).unwrap()
+        };
+        assert_eq!(
+            csv(sim.finish_heatmap().unwrap()),
+            csv(heatmap(Granularity::Line))
+        );
+    }
+}
@@ -7,13 +7,22 @@ use qcpu_syntax::ParsingContext;
 
 use crate::v4::syntax::{get_reg_name, Reg};
 
+// Defaults of `Machine`.
 pub const CLOCK_MHZ: u64 = 125;
 pub const CACHE_HIT_PENALTY: u64 = 2;
 pub const CACHE_MISS_PENALTY: u64 = 72;
 pub const INW_DELAY: u64 = 107 * 4 * 10;
 pub const FIRST_MISS_PENALTY: u64 = 2730;
+/// Cycles for writing a dirty line back to memory.
+pub const WRITE_BACK_PENALTY: u64 = CACHE_MISS_PENALTY;
+/// Cycles for writing a single word through to memory.
+pub const WRITE_THROUGH_PENALTY: u64 = 18;
+pub const BRANCH_PENALTY: u64 = 2;
 
 use super::{
+    bp::PredictorStat,
+    memory::CacheStat,
+    stat::Statistics,
     syntax::{OpName, OpV4},
     Instat, SimulatorV4,
 };
@@ -31,10 +40,73 @@ pub fn get_delay(opname: OpName) -> u64 {
     }
 }
 
-impl SimulatorV4 {
+impl SimulatorV4<'_> {
     pub fn log_stat(&mut self) -> std::result::Result<(), std::io::Error> {
-        self.log
-            .write_all(format!("{}\n{}\n{}\n", self.stat, self.memory.stat, self.bp).as_bytes())
+        self.log.write_all(
+            format!(
+                "{}\n{}\n{}\n{}\n",
+                self.stat, self.memory.config, self.memory.stat, self.bp
+            )
+            .as_bytes(),
+        )?;
+        if let Some(timing) = &self.timing {
+            self.log
+                .write_fmt(format_args!("Timing model\n{}\n", timing.stat))?;
+        }
+        self.pointers
+            .write_report(&mut self.log, self.ctx.as_ref())?;
+        self.log.write_all(b"\n")?;
+        if !self.compare_bp.is_empty() {
+            self.log_predictor_comparison()?;
+        }
+        Ok(())
+    }
+
+    /// The flush counts of the branch predictor and those it is compared against, with
+    /// the cycle count adjusted for each predictor's flushes.
+    pub fn compare_predictors(&self) -> Vec<PredictorStat> {
+        let own_flushes = (self.bp.flush_count_branch + self.bp.flush_count_jalr) as u64;
+        let base = self
+            .stat
+            .cycle_count
+            .saturating_sub(own_flushes * self.machine.branch_penalty);
+
+        std::iter::once(&self.bp)
+            .chain(&self.compare_bp)
+            .map(|bp| PredictorStat {
+                predictor: bp.config.to_string(),
+                branch: bp.total_count_branch,
+                branch_flush: bp.flush_count_branch,
+                jalr: bp.total_count_jalr,
+                jalr_flush: bp.flush_count_jalr,
+                returns: bp.total_count_return,
+                return_flush: bp.flush_count_return,
+                cycles: base
+                    + (bp.flush_count_branch + bp.flush_count_jalr) as u64
+                        * self.machine.branch_penalty,
+            })
+            .collect()
+    }
+
+    fn log_predictor_comparison(&mut self) -> std::result::Result<(), std::io::Error> {
+        let stats = self.compare_predictors();
+
+        self.log.write_fmt(format_args!(
+            "\nBranch predictor comparison\n{:48} {:>20} {:>20} {:>14}\n",
+            "Predictor", "Branch flush", "JALR flush", "Cycles"
+        ))?;
+        for stat in stats {
+            self.log.write_fmt(format_args!(
+                "{:48} {:>11} ({:5.02}%) {:>11} ({:5.02}%) {:>14}\n",
+                stat.predictor,
+                stat.branch_flush,
+                percent(stat.branch_flush as f64, stat.branch as f64),
+                stat.jalr_flush,
+                percent(stat.jalr_flush as f64, stat.jalr as f64),
+                stat.cycles,
+            ))?;
+        }
+        writeln!(self.log)
     }
 
     pub fn process_stat(
@@ -117,6 +189,47 @@ impl SimulatorV4 {
         Ok(())
     }
 
+    /// Logs the branches and `jalr`s that lost the most cycles to mispredictions.
+    pub fn process_branch_stat(
+        &mut self,
+        ctx: Option<&ParsingContext>,
+    ) -> std::result::Result<(), std::io::Error> {
+        let penalty = self.machine.branch_penalty;
+        let total_cycles = self.stat.cycle_count as f64;
+
+        let mut branches: Vec<_> = self
+            .per_branch_stat
+            .iter()
+            .zip(self.instructions.iter())
+            .enumerate()
+            .filter(|(_, (s, _))| s.flush > 0)
+            .collect();
+        branches.sort_by(|a, b| b.1 .0.flush.cmp(&a.1 .0.flush).then(a.0.cmp(&b.0)));
+
+        self.log.write_fmt(format_args!(
+            "\nPer-branch Stat\n{:5} {:32} {:5} {:>12} {:>8} {:>12} {:>8} {:>12} {:>7}\n",
+            "PC", "Label", "Op", "Executed", "Taken", "Mispredict", "Rate", "Lost cycles", "Share",
+        ))?;
+
+        for (i, (s, op)) in branches.into_iter().take(100) {
+            let lost = s.flush * penalty;
+            self.log.write_fmt(format_args!(
+                "{:05} {:32} {:5} {:>12} {:>7.02}% {:>12} {:>7.02}% {:>12} {:>6.02}%\n",
+                i,
+                ctx.map_or(String::new(), |ctx| ctx.reverse_lookup_floor(i).to_string()),
+                format!("{:?}", op.opname).to_lowercase(),
+                s.call,
+                percent(s.taken as f64, s.call as f64),
+                s.flush,
+                percent(s.flush as f64, s.call as f64),
+                lost,
+                percent(lost as f64, total_cycles),
+            ))?;
+        }
+
+        Ok(())
+    }
+
     #[cfg(feature = "conflict_pair")]
     pub fn process_memory_conflict_pc(
         &mut self,
@@ -157,13 +270,26 @@ impl SimulatorV4 {
     }
 
     pub fn tally(&mut self) {
-        self.stat.cycle_count = 0;
-        self.stat.instr_count = 0;
-        self.stat.hazard_count = 0;
-        self.memory.stat.hit = 0;
-        self.memory.stat.read = 0;
-        self.memory.stat.write = 0;
-        self.memory.stat.write_hit = 0;
+        let (stat, memory_stat) = self.estimate();
+        self.stat = stat;
+        self.memory.stat.hit = memory_stat.hit;
+        self.memory.stat.read = memory_stat.read;
+        self.memory.stat.write = memory_stat.write;
+        self.memory.stat.write_hit = memory_stat.write_hit;
+    }
+
+    /// The cycle count `tally` would report right now.
+    pub fn estimate_cycles(&self) -> u64 {
+        self.estimate().0.cycle_count
+    }
+
+    /// Reconstructs `Statistics` and the read/write counters of `CacheStat` from the
+    /// per-instruction statistics. The cycle and stall counts come from the timing model
+    /// when it runs.
+    fn estimate(&self) -> (Statistics, CacheStat) {
+        let machine = &self.machine;
+        let mut total = Statistics::default();
+        let mut memory_stat = CacheStat::default();
 
         let mut prev_op = &OpV4::default();
         let mut prev_stat = &Instat::default();
@@ -174,78 +300,78 @@ impl SimulatorV4 {
             .zip(self.instructions.iter())
             .chain(std::iter::once((&Instat::default(), &OpV4::default())))
         {
-            self.stat.instr_count += stat.call;
-            let delay = get_delay(op.opname);
-            self.stat.fpu_stall += (delay - 1) * stat.call;
+            total.instr_count += stat.call;
+            let delay = machine.delay(op.opname);
+            total.fpu_stall += (delay - 1) * stat.call;
             let hazard = (op.rs1 == prev_op.rd || op.rs2 == prev_op.rd) && prev_op.rd != 0;
 
-            self.stat.cycle_count += {
+            total.cycle_count += {
                 if stat.prev_ma > 0 {
                     (match prev_op.opname {
                         #[cfg(feature = "full_ops")]
                         OpName::Lw | OpName::Lwr | OpName::Lwi | OpName::Sw | OpName::Swi => {
+                            let (hit, miss) = self.stall_outcome(prev_op.opname, prev_stat);
                             if hazard {
-                                self.stat.hazard_count += prev_stat.call;
-                                prev_stat.hit * (CACHE_HIT_PENALTY + delay)
-                                    + (prev_stat.call - prev_stat.hit)
-                                        * (CACHE_MISS_PENALTY + delay)
+                                total.hazard_count += prev_stat.call;
+                                hit * (machine.cache_hit_penalty + delay)
+                                    + miss * (machine.cache_miss_penalty + delay)
                             } else {
-                                prev_stat.hit * delay.max(CACHE_HIT_PENALTY)
-                                    + (prev_stat.call - prev_stat.hit) * CACHE_MISS_PENALTY
+                                hit * delay.max(machine.cache_hit_penalty)
+                                    + miss * machine.cache_miss_penalty
                             }
                         }
                         #[cfg(not(feature = "full_ops"))]
                         OpName::Lw | OpName::Lwr | OpName::Sw => {
+                            let (hit, miss) = self.stall_outcome(prev_op.opname, prev_stat);
                             if hazard {
-                                self.stat.hazard_count += prev_stat.call;
-                                prev_stat.hit * (CACHE_HIT_PENALTY + delay)
-                                    + (prev_stat.call - prev_stat.hit)
-                                        * (CACHE_MISS_PENALTY + delay)
+                                total.hazard_count += prev_stat.call;
+                                hit * (machine.cache_hit_penalty + delay)
+                                    + miss * (machine.cache_miss_penalty + delay)
                             } else {
-                                prev_stat.hit * delay.max(CACHE_HIT_PENALTY)
-                                    + (prev_stat.call - prev_stat.hit) * CACHE_MISS_PENALTY
+                                hit * delay.max(machine.cache_hit_penalty)
+                                    + miss * machine.cache_miss_penalty
                             }
                         }
 
                         OpName::Inw => {
                             if hazard {
-                                prev_stat.call * (INW_DELAY + delay)
+                                prev_stat.call * (machine.inw_delay + delay)
                             } else {
-                                prev_stat.call * INW_DELAY
+                                prev_stat.call * machine.inw_delay
                             }
                         }
                         _ => unreachable!(),
                     }) + {
                         let count = stat.call - stat.prev_ma;
-                        self.stat.forwarding_stall += count;
-                        count * (delay.max(CACHE_HIT_PENALTY) + 1)
+                        total.forwarding_stall += count;
+                        count * (delay.max(machine.cache_hit_penalty) + 1)
                     }
                 } else {
-                    self.stat.forwarding_stall += stat.call;
-                    stat.call * (delay.max(CACHE_HIT_PENALTY) + 1)
+                    total.forwarding_stall += stat.call;
+                    stat.call * (delay.max(machine.cache_hit_penalty) + 1)
                 }
             };
 
             match op.opname {
                 #[cfg(feature = "full_ops")]
                 OpName::Lw | OpName::Lwr | OpName::Lwi => {
-                    self.memory.stat.read += stat.call;
-                    self.memory.stat.hit += stat.hit;
+                    memory_stat.read += stat.call;
+                    memory_stat.hit += stat.hit;
                 }
                 #[cfg(not(feature = "full_ops"))]
                 OpName::Lw | OpName::Lwr => {
-                    self.memory.stat.read += stat.call;
-                    self.memory.stat.hit += stat.hit;
+                    memory_stat.read += stat.call;
+                    memory_stat.hit += stat.hit;
                 }
                 #[cfg(feature = "full_ops")]
                 OpName::Sw | OpName::Swi => {
-                    self.memory.stat.write += stat.call;
-                    self.memory.stat.write_hit += stat.hit;
+                    memory_stat.write += stat.call;
+                    memory_stat.write_hit += stat.hit;
                 }
                 #[cfg(not(feature = "full_ops"))]
                 OpName::Sw => {
-                    self.memory.stat.write += stat.call;
-                    self.memory.stat.write_hit += stat.hit;
+                    memory_stat.write += stat.call;
+                    memory_stat.write_hit += stat.hit;
                 }
                 _ => {}
             }
@@ -254,8 +380,48 @@ impl SimulatorV4 {
             prev_stat = stat;
         }
 
-        self.stat.cycle_count += FIRST_MISS_PENALTY * self.memory.stat.first_miss;
-        self.stat.cycle_count += (self.bp.flush_count_branch + self.bp.flush_count_jalr) as u64 * 2;
+        total.cycle_count += machine.first_miss_penalty * self.memory.stat.first_miss;
+        total.cycle_count += self.memory_write_cycles();
+        total.cycle_count +=
+            (self.bp.flush_count_branch + self.bp.flush_count_jalr) as u64 * machine.branch_penalty;
+
+        if let Some(timing) = &self.timing {
+            let stat = &timing.stat;
+            total.cycle_count = stat.cycles;
+            total.hazard_count = stat.hazards;
+            total.fpu_stall = stat.fpu_stall;
+            total.forwarding_stall = stat.forwarding_stall;
+        }
+
+        (total, memory_stat)
+    }
+
+    /// Hits and misses of a memory instruction as far as stalls go. Store misses without
+    /// write allocation go straight to memory and stall like hits.
+    fn stall_outcome(&self, opname: OpName, stat: &Instat) -> (u64, u64) {
+        #[cfg(feature = "full_ops")]
+        let store = matches!(opname, OpName::Sw | OpName::Swi);
+        #[cfg(not(feature = "full_ops"))]
+        let store = matches!(opname, OpName::Sw);
+
+        if store && !self.memory.config.write_allocate {
+            (stat.call, 0)
+        } else {
+            (stat.hit, stat.call - stat.hit)
+        }
+    }
+
+    /// Cycles spent on write-backs and written-through words, or none under the
+    /// hardware's write policy.
+    fn memory_write_cycles(&self) -> u64 {
+        if self.memory.config.hardware_write_policy() {
+            return 0;
+        }
+
+        let stat = &self.memory.stat;
+        let write_back_words = stat.write_back * self.memory.config.line_words as u64;
+        stat.write_back * self.machine.write_back_penalty
+            + (stat.memory_write - write_back_words) * self.machine.write_through_penalty
     }
 
     pub fn log_registers(&self) {
@@ -280,27 +446,39 @@ impl SimulatorV4 {
     }
 }
 
-fn percent(n: f64, total: f64) -> f64 {
-    n / total * 100.0
+/// `n` as a percentage of `total`, or 0 when there is no total.
+pub(super) fn percent(n: f64, total: f64) -> f64 {
+    if total == 0.0 {
+        0.0
+    } else {
+        n / total * 100.0
+    }
 }
 
-impl SimulatorV4 {
-    pub fn time_optimize_info(&mut self, clock: f64) -> Result<(), std::io::Error> {
+impl SimulatorV4<'_> {
+    pub fn time_optimize_info(&mut self) -> Result<(), std::io::Error> {
+        let machine = self.machine;
+        let clock = machine.clock_mhz;
         let cache_miss = self.memory.stat.read - self.memory.stat.hit;
-        let cache_write_miss = self.memory.stat.write - self.memory.stat.write_hit;
+        let cache_write_miss = if self.memory.config.write_allocate {
+            self.memory.stat.write - self.memory.stat.write_hit
+        } else {
+            0
+        };
 
-        let [total_time, hazard_time, cache_miss_time, cache_write_miss_time, jalr_flush_time, branch_flush_time, cache_first_miss_time] =
+        let [total_time, hazard_time, cache_miss_time, cache_write_miss_time, memory_write_time, jalr_flush_time, branch_flush_time, cache_first_miss_time] =
             [
                 self.stat.cycle_count as f64 / clock,
                 self.stat.hazard_count as f64 * self.memory.stat.hit as f64
                     / self.memory.stat.read as f64
-                    * 2.0
+                    * machine.cache_hit_penalty as f64
                     / clock,
-                cache_miss as f64 * CACHE_MISS_PENALTY as f64 / clock,
-                cache_write_miss as f64 * CACHE_MISS_PENALTY as f64 / clock,
-                self.bp.flush_count_jalr as f64 * 2.0 / clock,
-                self.bp.flush_count_branch as f64 * 2.0 / clock,
-                self.memory.stat.first_miss as f64 * FIRST_MISS_PENALTY as f64 / clock,
+                cache_miss as f64 * machine.cache_miss_penalty as f64 / clock,
+                cache_write_miss as f64 * machine.cache_miss_penalty as f64 / clock,
+                self.memory_write_cycles() as f64 / clock,
+                self.bp.flush_count_jalr as f64 * machine.branch_penalty as f64 / clock,
+                self.bp.flush_count_branch as f64 * machine.branch_penalty as f64 / clock,
+                self.memory.stat.first_miss as f64 * machine.first_miss_penalty as f64 / clock,
             ]
             .map(|s| Duration::from_micros(s as u64));
 
@@ -309,6 +487,7 @@ impl SimulatorV4 {
             Should complete in: {:?} for a clock of {} MHz\n\
             Hazard time with cache hit: {:?} ({:.02}%)\n\
             Cache miss time: read: {:?} ({:.02}%), write: {:?} ({:.02}%)\n\
+            Memory write time: {:?} ({:.02}%)\n\
             JALR flush time: {:?} ({:.02}%)\n\
             Branch flush time: {:?} ({:.02}%)\n\
             First miss time: {:?} ({:.02}%)\n",
@@ -329,6 +508,11 @@ impl SimulatorV4 {
                 cache_write_miss_time.as_micros() as f64,
                 total_time.as_micros() as f64
             ),
+            memory_write_time,
+            percent(
+                memory_write_time.as_micros() as f64,
+                total_time.as_micros() as f64
+            ),
             jalr_flush_time,
             percent(
                 jalr_flush_time.as_micros() as f64,
@@ -349,3 +533,111 @@ impl SimulatorV4 {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::super::{machine::Machine, SimulatorV4Builder};
+
+    #[test]
+    pub fn memory_write_cycles_test() {
+        let code = r#"
+_min_caml_start:
+    sw      a0, 0(zero)
+    sw      a0, 8(zero)
+    lw      a0, 0(zero)
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        // Writing to memory only costs extra cycles under a policy other than the
+        // hardware's.
+        let cycles = |cache: &str, write_penalty: u64| {
+            let mut sim = SimulatorV4Builder::default()
+                .with_program(mc.clone())
+                .with_cache(format!("sets=4,line=2,{}", cache).parse().unwrap())
+                .with_machine(Machine {
+                    write_back_penalty: write_penalty,
+                    write_through_penalty: write_penalty,
+                    ..Machine::default()
+                })
+                .verbose(true)
+                .build();
+            sim.run();
+            sim.estimate_cycles()
+        };
+        assert_eq!(cycles("write=back", 0), cycles("write=back", 1000));
+        assert!(cycles("write=through", 0) < cycles("write=through", 1000));
+    }
+
+    #[test]
+    pub fn branch_stat_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 50
+loop:
+    addi    a0, a0, -1
+    bne     a0, zero, loop
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let mut log = Vec::new();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .with_log(&mut log)
+            .verbose(true)
+            .build();
+        sim.run();
+        sim.tally();
+
+        // `bne` is the third instruction.
+        let branch = &sim.per_branch_stat[2];
+        assert_eq!((branch.call, branch.taken, branch.flush), (50, 49, 1));
+        assert_eq!(sim.per_branch_stat.iter().map(|s| s.call).sum::<u64>(), 50);
+
+        sim.process_branch_stat(None).unwrap();
+        drop(sim);
+        let log = String::from_utf8(log).unwrap();
+        let rows: Vec<_> = log
+            .lines()
+            .skip_while(|l| *l != "Per-branch Stat")
+            .collect();
+        assert_eq!(rows.len(), 3, "{}", log);
+        assert!(rows[2].starts_with("00002"), "{}", rows[2]);
+        assert!(rows[2].contains("bne"), "{}", rows[2]);
+    }
+
+    #[test]
+    pub fn compare_predictors_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 50
+loop:
+    addi    a0, a0, -1
+    bne     a0, zero, loop
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let compared = ["static", "bimodal", "gshare", "perceptron"]
+            .map(|c| c.parse().unwrap())
+            .to_vec();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .with_compare_predictors(compared)
+            .verbose(true)
+            .build();
+        sim.run();
+        sim.tally();
+
+        let stats = sim.compare_predictors();
+        assert_eq!(stats.len(), 5);
+        assert!(stats.iter().all(|s| s.branch == 50));
+        assert_eq!(stats[0].cycles, sim.stat.cycle_count);
+        // Each predictor's cycles differ by its extra flushes.
+        for stat in &stats {
+            assert_eq!(
+                stat.cycles as i64 - stats[0].cycles as i64,
+                (stat.branch_flush as i64 - stats[0].branch_flush as i64)
+                    * sim.machine.branch_penalty as i64
+            );
+        }
+    }
+}
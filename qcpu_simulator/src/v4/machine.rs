@@ -0,0 +1,139 @@
+//! Pipeline timing parameters, loaded from a JSON machine description so that changes to
+//! the hardware don't require rebuilding the simulator.
+
+use std::{io, path::Path};
+
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::{
+    log::{
+        get_delay, BRANCH_PENALTY, CACHE_HIT_PENALTY, CACHE_MISS_PENALTY, CLOCK_MHZ,
+        FIRST_MISS_PENALTY, INW_DELAY, WRITE_BACK_PENALTY, WRITE_THROUGH_PENALTY,
+    },
+    syntax::OpName,
+};
+
+/// Cycles until an FPU result is available. Every other instruction takes 1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Latency {
+    pub fadd: u64,
+    pub fsub: u64,
+    pub fmul: u64,
+    pub fdiv: u64,
+    pub fsqrt: u64,
+    pub ftoi: u64,
+    pub fitof: u64,
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Self {
+            fadd: get_delay(OpName::Fadd),
+            fsub: get_delay(OpName::Fsub),
+            fmul: get_delay(OpName::Fmul),
+            fdiv: get_delay(OpName::Fdiv),
+            fsqrt: get_delay(OpName::Fsqrt),
+            ftoi: get_delay(OpName::Ftoi),
+            fitof: get_delay(OpName::Fitof),
+        }
+    }
+}
+
+/// A machine description. Missing keys keep the compiled-in defaults, e.g.
+/// `{ "cache_miss_penalty": 80, "latency": { "fdiv": 8 } }`.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Machine {
+    /// Written as an integer when whole, as it was before it could be fractional.
+    #[serde(serialize_with = "serialize_clock")]
+    pub clock_mhz: f64,
+    pub cache_hit_penalty: u64,
+    pub cache_miss_penalty: u64,
+    pub first_miss_penalty: u64,
+    pub write_back_penalty: u64,
+    pub write_through_penalty: u64,
+    pub inw_delay: u64,
+    /// Cycles flushed on a mispredicted branch or `jalr`.
+    pub branch_penalty: u64,
+    pub latency: Latency,
+}
+
+impl Default for Machine {
+    fn default() -> Self {
+        Self {
+            clock_mhz: CLOCK_MHZ as f64,
+            cache_hit_penalty: CACHE_HIT_PENALTY,
+            cache_miss_penalty: CACHE_MISS_PENALTY,
+            first_miss_penalty: FIRST_MISS_PENALTY,
+            write_back_penalty: WRITE_BACK_PENALTY,
+            write_through_penalty: WRITE_THROUGH_PENALTY,
+            inw_delay: INW_DELAY,
+            branch_penalty: BRANCH_PENALTY,
+            latency: Latency::default(),
+        }
+    }
+}
+
+fn serialize_clock<S: Serializer>(clock: &f64, s: S) -> Result<S::Ok, S::Error> {
+    if clock.fract() == 0.0 && (0.0..=u64::MAX as f64).contains(clock) {
+        s.serialize_u64(*clock as u64)
+    } else {
+        s.serialize_f64(*clock)
+    }
+}
+
+impl Machine {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        serde_json::from_str(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// The latency of `opname`, like [`get_delay`] for this machine.
+    #[inline(always)]
+    pub fn delay(&self, opname: OpName) -> u64 {
+        let latency = &self.latency;
+        match opname {
+            OpName::Fadd => latency.fadd,
+            OpName::Fsub => latency.fsub,
+            OpName::Fmul => latency.fmul,
+            OpName::Ftoi => latency.ftoi,
+            OpName::Fitof => latency.fitof,
+            OpName::Fdiv => latency.fdiv,
+            OpName::Fsqrt => latency.fsqrt,
+            _ => 1,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    pub fn serde_test() {
+        let machine: Machine =
+            serde_json::from_str(r#"{ "latency": { "fadd": 6 }, "inw_delay": 10 }"#).unwrap();
+        assert_eq!(
+            (machine.delay(OpName::Fadd), machine.delay(OpName::Fmul)),
+            (6, 3)
+        );
+        assert_eq!(
+            serde_json::from_str::<Machine>("{}").unwrap(),
+            Machine::default()
+        );
+        assert!(serde_json::from_str::<Machine>(r#"{ "cache_penalty": 1 }"#).is_err());
+        assert_eq!(
+            serde_json::to_value(Machine::default()).unwrap()["clock_mhz"],
+            serde_json::json!(CLOCK_MHZ)
+        );
+        let fractional = Machine {
+            clock_mhz: 12.5,
+            ..Machine::default()
+        };
+        assert_eq!(
+            serde_json::to_value(fractional).unwrap()["clock_mhz"],
+            serde_json::json!(12.5)
+        );
+    }
+}
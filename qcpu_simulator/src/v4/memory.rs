@@ -1,24 +1,207 @@
-use std::fmt::Display;
+use std::{fmt::Display, ops::Range, str::FromStr};
 
 #[cfg(feature = "conflict_pair")]
 use std::collections::BTreeMap;
 
 use serde::Serialize;
+use strum_macros::{Display, EnumString};
 
-#[cfg(feature = "safe")]
-use super::SimulatorV4HaltKind;
+use super::{heatmap::Heatmap, shadow::ShadowMemory, SimulatorV4HaltKind};
 
 #[derive(Debug, Clone)]
 pub struct MemoryV4 {
     pub m: Vec<u32>,
+    /// `config.ways` consecutive lines per set, newest first.
     pub cache: Vec<CacheLine>,
+    pub config: CacheConfig,
+    line_bits: u32,
+    set_mask: usize,
+    tag_shift: u32,
     pub stat: CacheStat,
     pub verbose: bool,
+    /// Set while watches, tracing or the shadow memory need to see individual accesses.
+    pub observed: bool,
+    pub watches: Vec<MemoryWatch>,
+    pub watch_hit: Option<MemoryAccessRecord>,
+    pub last_access: Option<MemoryAccessRecord>,
+    pub shadow: Option<Box<ShadowMemory>>,
+    /// Only recorded when `verbose` is set.
+    pub heatmap: Option<Box<Heatmap>>,
+}
+
+/// Stops the simulator when a word in `range` is read and/or written.
+#[derive(Debug, Clone)]
+pub struct MemoryWatch {
+    pub range: Range<usize>,
+    pub read: bool,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryAccessRecord {
+    pub addr: usize,
+    pub value: u32,
+    pub write: bool,
+}
+
+/// Which line of a full set is evicted on a miss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum Replacement {
+    /// Least recently used.
+    Lru,
+    /// Oldest installed.
+    Fifo,
+    /// Oldest installed that has not been hit since it was last given a second chance.
+    Sc,
+}
+
+/// When stores reach the memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum WritePolicy {
+    /// Stores mark the line dirty; it is written back when evicted.
+    Back,
+    /// Every store is also written to memory.
+    Through,
+}
+
+/// The data cache geometry and policies. The default is the direct-mapped write-back
+/// cache of the hardware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct CacheConfig {
+    pub sets: usize,
+    pub line_words: usize,
+    pub ways: usize,
+    pub replacement: Replacement,
+    pub write: WritePolicy,
+    /// Whether a store miss installs the line. Without it the store only goes to memory.
+    pub write_allocate: bool,
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            sets: CACHE_LINE,
+            line_words: 4,
+            ways: 1,
+            replacement: Replacement::Lru,
+            write: WritePolicy::Back,
+            write_allocate: true,
+        }
+    }
+}
+
+impl CacheConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.sets.is_power_of_two() {
+            return Err(format!("Cache sets must be a power of two: {}", self.sets));
+        }
+        if !self.line_words.is_power_of_two() {
+            return Err(format!(
+                "Cache line words must be a power of two: {}",
+                self.line_words
+            ));
+        }
+        if self.ways == 0 {
+            return Err("Cache ways must be at least 1".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn lines(&self) -> usize {
+        self.sets * self.ways
+    }
+
+    /// Whether stores go through the write-back, write-allocate policy of the hardware.
+    /// Its write-backs are already part of the measured miss penalty, so only other
+    /// policies cost extra cycles for writing to memory.
+    pub fn hardware_write_policy(&self) -> bool {
+        self.write == WritePolicy::Back && self.write_allocate
+    }
+}
+
+/// Parses comma-separated `sets=`, `line=` (words), `ways=`, `policy=` (`lru`, `fifo`
+/// or `sc`), `write=` (`back` or `through`) and `allocate=` (`true` or `false`)
+/// settings, e.g. `sets=4096,ways=4,write=through,allocate=false`. Settings that are
+/// not mentioned keep their default.
+impl FromStr for CacheConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = CacheConfig::default();
+
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Expected <key>=<value>: {}", part))?;
+            let (key, value) = (key.trim(), value.trim());
+            let number = || {
+                value
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid {}: {}", key, value))
+            };
+            match key {
+                "sets" => config.sets = number()?,
+                "line" => config.line_words = number()?,
+                "ways" => config.ways = number()?,
+                "policy" => {
+                    config.replacement = value
+                        .parse()
+                        .map_err(|_| format!("Unknown replacement policy: {}", value))?
+                }
+                "write" => {
+                    config.write = value
+                        .parse()
+                        .map_err(|_| format!("Unknown write policy: {}", value))?
+                }
+                "allocate" => {
+                    config.write_allocate = value
+                        .parse()
+                        .map_err(|_| format!("Invalid allocate: {}", value))?
+                }
+                _ => return Err(format!("Unknown cache setting: {}", key)),
+            }
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+impl Display for CacheConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.ways == 1 {
+            write!(
+                f,
+                "{} lines x {} words direct-mapped",
+                self.sets, self.line_words
+            )?;
+        } else {
+            write!(
+                f,
+                "{} sets x {} ways x {} words {}",
+                self.sets,
+                self.ways,
+                self.line_words,
+                self.replacement.to_string().to_uppercase()
+            )?;
+        }
+        write!(f, " write-{} cache", self.write)?;
+        if !self.write_allocate {
+            write!(f, " (no write allocate)")?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
 pub struct CacheLine {
-    tag: u8,
+    tag: u32,
+    referenced: bool,
+    dirty: bool,
     #[cfg(feature = "conflict_pair")]
     pc: u32,
 }
@@ -26,7 +209,9 @@ pub struct CacheLine {
 impl Default for CacheLine {
     fn default() -> Self {
         Self {
-            tag: u8::MAX,
+            tag: u32::MAX,
+            referenced: false,
+            dirty: false,
             #[cfg(feature = "conflict_pair")]
             pc: u32::MAX,
         }
@@ -38,23 +223,31 @@ impl CacheLine {
         Self::default()
     }
 
-    #[cfg(feature = "conflict_pair")]
-    pub fn replace(&mut self, addr: usize, pc: u32) -> (bool, u32) {
-        let tag = (addr >> CACHE_LINE_BITS >> 2) as u8;
-        let hit = self.tag == tag;
-        self.tag = tag;
-        let prev = self.pc;
-        self.pc = pc;
-        (hit, prev)
+    /// Restores a line holding `tag`; any `conflict_pair` bookkeeping starts over.
+    #[allow(clippy::needless_update)]
+    pub fn with_tag(tag: u32, referenced: bool, dirty: bool) -> Self {
+        Self {
+            tag,
+            referenced,
+            dirty,
+            ..Self::default()
+        }
     }
 
-    #[cfg(not(feature = "conflict_pair"))]
-    #[inline(always)]
-    pub fn replace(&mut self, addr: usize) -> bool {
-        let tag = (addr >> CACHE_LINE_BITS >> 2) as u8;
-        let hit = self.tag == tag;
-        self.tag = tag;
-        hit
+    pub fn tag(&self) -> u32 {
+        self.tag
+    }
+
+    pub fn referenced(&self) -> bool {
+        self.referenced
+    }
+
+    pub fn dirty(&self) -> bool {
+        self.dirty
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.tag != u32::MAX
     }
 }
 
@@ -67,11 +260,15 @@ pub struct CacheStat {
     #[cfg(feature = "conflict_pair")]
     pub conflict_pair: BTreeMap<u32, u64>,
     pub first_miss: u64,
+    /// Dirty lines written back on eviction.
+    pub write_back: u64,
+    /// Words transferred between the cache and memory.
+    pub memory_read: u64,
+    pub memory_write: u64,
 }
 
 impl Display for CacheStat {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        writeln!(f, "{} lines x 4 words direct-mapped cache", CACHE_LINE)?;
         writeln!(
             f,
             "Read: {}, Hit: {} ({:.02}%), Miss: {} ({:.02}%)",
@@ -91,6 +288,11 @@ impl Display for CacheStat {
             (self.write - self.write_hit) as f64 / self.write as f64 * 100.0
         )?;
         writeln!(f, "First miss: {}", self.first_miss)?;
+        writeln!(
+            f,
+            "Write-back: {}, Memory traffic: read {} words, write {} words",
+            self.write_back, self.memory_read, self.memory_write
+        )?;
 
         Ok(())
     }
@@ -99,30 +301,74 @@ impl Display for CacheStat {
 pub const CACHE_LINE_BITS: usize = 14;
 pub const CACHE_LINE: usize = 1 << CACHE_LINE_BITS;
 pub const MEMORY_SIZE: usize = 1 << 19;
-pub const CACHE_MASK: usize = CACHE_LINE - 1;
 
 impl MemoryV4 {
     pub fn new(verbose: bool) -> Self {
+        Self::with_cache(verbose, CacheConfig::default())
+    }
+
+    /// Panics if `config` is invalid.
+    pub fn with_cache(verbose: bool, config: CacheConfig) -> Self {
+        if let Err(e) = config.validate() {
+            panic!("{}", e);
+        }
+
+        let line_bits = config.line_words.trailing_zeros();
         Self {
             m: vec![0; MEMORY_SIZE],
             cache: if verbose {
-                vec![CacheLine::default(); CACHE_LINE]
+                vec![CacheLine::default(); config.lines()]
             } else {
                 // Empty vector when not needed
                 Vec::new()
             },
+            config,
+            line_bits,
+            set_mask: config.sets - 1,
+            tag_shift: line_bits + config.sets.trailing_zeros(),
             stat: CacheStat::default(),
             verbose,
+            observed: false,
+            watches: Vec::new(),
+            watch_hit: None,
+            last_access: None,
+            shadow: None,
+            heatmap: None,
+        }
+    }
+
+    #[inline(always)]
+    fn observe(&mut self, addr: usize, value: u32, write: bool) {
+        if !self.observed {
+            return;
+        }
+
+        let record = MemoryAccessRecord { addr, value, write };
+        self.last_access = Some(record);
+
+        if let Some(shadow) = self.shadow.as_mut() {
+            shadow.access(addr, write);
+        }
+
+        if self
+            .watches
+            .iter()
+            .any(|w| w.range.contains(&addr) && if write { w.write } else { w.read })
+        {
+            self.watch_hit = Some(record);
         }
     }
 
-    #[cfg(feature = "safe")]
-    pub fn read(
+    /// Reads the word at `addr`, returning it and whether the access hit the cache.
+    /// Only `CHECKED` reads verify that `addr` is inside the memory. The cache is only
+    /// simulated when `VERBOSE`, and observers and the heatmap only see `EXTRAS` reads.
+    #[inline(always)]
+    pub fn read<const CHECKED: bool, const VERBOSE: bool, const EXTRAS: bool>(
         &mut self,
         addr: usize,
         #[cfg(feature = "conflict_pair")] pc: u32,
     ) -> Result<(u32, bool), SimulatorV4HaltKind> {
-        if addr >= MEMORY_SIZE {
+        if CHECKED && addr >= MEMORY_SIZE {
             return Err(SimulatorV4HaltKind::MemoryAccess {
                 bound: MEMORY_SIZE,
                 index: addr,
@@ -130,115 +376,220 @@ impl MemoryV4 {
         }
 
         let value = unsafe { *self.m.get_unchecked(addr) };
+        if EXTRAS {
+            self.observe(addr, value, false);
+        }
 
-        if !self.verbose {
+        if !VERBOSE {
             return Ok((value, false));
         }
 
-        let idx = (addr >> 2) & CACHE_MASK;
-        let entry = unsafe { self.cache.get_unchecked_mut(idx) };
+        let hit = self.touch(
+            addr,
+            false,
+            #[cfg(feature = "conflict_pair")]
+            pc,
+        );
+        if let Some(heatmap) = self.heatmap.as_mut().filter(|_| EXTRAS) {
+            heatmap.record(addr, false, hit);
+        }
+        Ok((value, hit))
+    }
 
-        if !entry.tag == 0 {
-            self.stat.first_miss += 1;
+    /// Writes `val` to `addr` and returns whether the access hit the cache. Only
+    /// `CHECKED` writes verify that `addr` is inside the memory; the rest is like
+    /// [`Self::read`].
+    #[inline(always)]
+    pub fn write<const CHECKED: bool, const VERBOSE: bool, const EXTRAS: bool>(
+        &mut self,
+        addr: usize,
+        val: u32,
+        #[cfg(feature = "conflict_pair")] pc: u32,
+    ) -> Result<bool, SimulatorV4HaltKind> {
+        if CHECKED && addr >= MEMORY_SIZE {
+            return Err(SimulatorV4HaltKind::MemoryAccess {
+                bound: MEMORY_SIZE,
+                index: addr,
+            });
         }
 
-        #[cfg(feature = "conflict_pair")]
-        {
-            let (hit, prev) = entry.replace(addr, pc);
-            if !prev != 0 {
-                let map_idx = if prev < pc {
-                    prev << 16 | pc
-                } else {
-                    pc << 16 | prev
-                };
-                *self.stat.conflict_pair.entry(map_idx).or_insert(0) += 1;
-            }
-            Ok((value, hit))
+        unsafe { *self.m.get_unchecked_mut(addr) = val };
+        if EXTRAS {
+            self.observe(addr, val, true);
         }
 
-        #[cfg(not(feature = "conflict_pair"))]
-        {
-            let hit = entry.replace(addr);
-            Ok((value, hit))
+        if !VERBOSE {
+            return Ok(true);
         }
+
+        let hit = self.touch(
+            addr,
+            true,
+            #[cfg(feature = "conflict_pair")]
+            pc,
+        );
+        if let Some(heatmap) = self.heatmap.as_mut().filter(|_| EXTRAS) {
+            heatmap.record(addr, true, hit);
+        }
+        Ok(hit)
     }
 
-    #[cfg(not(feature = "safe"))]
-    pub fn read(&mut self, addr: usize) -> (u32, bool) {
-        let value = unsafe { *self.m.get_unchecked(addr) };
-        if !self.verbose {
-            return (value, false);
+    /// Looks up `addr` in the cache and installs its line. Direct-mapped caches take a
+    /// fast path.
+    #[inline(always)]
+    fn touch(
+        &mut self,
+        addr: usize,
+        write: bool,
+        #[cfg(feature = "conflict_pair")] pc: u32,
+    ) -> bool {
+        let set = (addr >> self.line_bits) & self.set_mask;
+        let tag = (addr >> self.tag_shift) as u32;
+
+        if self.config.ways != 1 {
+            return self.touch_set(
+                set,
+                tag,
+                write,
+                #[cfg(feature = "conflict_pair")]
+                pc,
+            );
         }
 
-        let entry = unsafe { self.cache.get_unchecked_mut((addr >> 2) & CACHE_MASK) };
+        let entry = unsafe { self.cache.get_unchecked_mut(set) };
 
-        if !entry.tag == 0 {
-            self.stat.first_miss += 1;
+        if entry.tag != tag && write && !self.config.write_allocate {
+            self.stat.memory_write += 1;
+            return false;
         }
 
-        let hit = entry.replace(addr);
-        (value, hit)
+        self.stat.access(
+            &self.config,
+            entry,
+            tag,
+            write,
+            #[cfg(feature = "conflict_pair")]
+            pc,
+        )
     }
 
-    #[cfg(feature = "safe")]
-    pub fn write(
+    /// `touch` for set-associative caches. Invalid lines always sit at the end of a set,
+    /// so a miss evicts the last line once the policy has reordered the set.
+    fn touch_set(
         &mut self,
-        addr: usize,
-        val: u32,
+        set: usize,
+        tag: u32,
+        write: bool,
         #[cfg(feature = "conflict_pair")] pc: u32,
-    ) -> Result<bool, SimulatorV4HaltKind> {
-        if addr >= MEMORY_SIZE {
-            return Err(SimulatorV4HaltKind::MemoryAccess {
-                bound: MEMORY_SIZE,
-                index: addr,
-            });
+    ) -> bool {
+        let ways = self.config.ways;
+        let lines = &mut self.cache[set * ways..(set + 1) * ways];
+
+        if let Some(mut i) = lines.iter().position(|l| l.tag == tag) {
+            match self.config.replacement {
+                Replacement::Lru => {
+                    lines[..=i].rotate_right(1);
+                    i = 0;
+                }
+                Replacement::Fifo => {}
+                Replacement::Sc => lines[i].referenced = true,
+            }
+
+            return self.stat.access(
+                &self.config,
+                &mut lines[i],
+                tag,
+                write,
+                #[cfg(feature = "conflict_pair")]
+                pc,
+            );
         }
 
-        unsafe { *self.m.get_unchecked_mut(addr) = val };
+        if write && !self.config.write_allocate {
+            self.stat.memory_write += 1;
+            return false;
+        }
 
-        if !self.verbose {
-            return Ok(true);
+        if self.config.replacement == Replacement::Sc {
+            for _ in 0..ways {
+                let last = &mut lines[ways - 1];
+                if !last.referenced {
+                    break;
+                }
+                last.referenced = false;
+                lines.rotate_right(1);
+            }
         }
 
-        let idx = (addr >> 2) & CACHE_MASK;
-        let entry = &mut unsafe { self.cache.get_unchecked_mut(idx) };
+        lines.rotate_right(1);
+        self.stat.access(
+            &self.config,
+            &mut lines[0],
+            tag,
+            write,
+            #[cfg(feature = "conflict_pair")]
+            pc,
+        )
+    }
+}
+
+impl CacheStat {
+    /// Accesses `line`, which either holds `tag` or is the victim to replace with it,
+    /// and counts the resulting memory traffic. Returns whether the access hit.
+    #[inline(always)]
+    fn access(
+        &mut self,
+        config: &CacheConfig,
+        line: &mut CacheLine,
+        tag: u32,
+        write: bool,
+        #[cfg(feature = "conflict_pair")] pc: u32,
+    ) -> bool {
+        let hit = line.tag == tag;
+
+        if !hit {
+            if !line.is_valid() {
+                self.first_miss += 1;
+            }
+            if line.dirty {
+                self.write_back += 1;
+                self.memory_write += config.line_words as u64;
+            }
+            self.memory_read += config.line_words as u64;
 
-        if !entry.tag == 0 {
-            self.stat.first_miss += 1;
+            line.tag = tag;
+            line.referenced = false;
+            line.dirty = false;
         }
 
-        #[cfg(feature = "conflict_pair")]
-        {
-            let (hit, prev) = entry.replace(addr, pc);
-            if !prev != 0 {
-                let map_idx = if prev < pc {
-                    prev << 16 | pc
-                } else {
-                    pc << 16 | prev
-                };
-                *self.stat.conflict_pair.entry(map_idx).or_insert(0) += 1;
+        if write {
+            match config.write {
+                WritePolicy::Back => line.dirty = true,
+                WritePolicy::Through => self.memory_write += 1,
             }
-            Ok(hit)
         }
 
-        #[cfg(not(feature = "conflict_pair"))]
+        #[cfg(feature = "conflict_pair")]
         {
-            let hit = entry.replace(addr);
-            Ok(hit)
+            let prev = std::mem::replace(&mut line.pc, pc);
+            self.record_conflict(prev, pc);
         }
+
+        hit
     }
+}
 
-    #[cfg(not(feature = "safe"))]
-    pub fn write(&mut self, addr: usize, val: u32) -> bool {
-        unsafe { *self.m.get_unchecked_mut(addr) = val };
-        if !self.verbose {
-            return true;
-        }
-        let entry = unsafe { self.cache.get_unchecked_mut((addr >> 2) & CACHE_MASK) };
-        if !entry.tag == 0 {
-            self.stat.first_miss += 1;
+#[cfg(feature = "conflict_pair")]
+impl CacheStat {
+    fn record_conflict(&mut self, prev: u32, pc: u32) {
+        if !prev != 0 {
+            let map_idx = if prev < pc {
+                prev << 16 | pc
+            } else {
+                pc << 16 | prev
+            };
+            *self.conflict_pair.entry(map_idx).or_insert(0) += 1;
         }
-        entry.replace(addr)
     }
 }
 
@@ -247,3 +598,89 @@ impl Default for MemoryV4 {
         Self::new(false)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn read(memory: &mut MemoryV4, addr: usize) -> bool {
+        let (_, hit) = memory
+            .read::<true, true, false>(
+                addr,
+                #[cfg(feature = "conflict_pair")]
+                0,
+            )
+            .unwrap();
+        hit
+    }
+
+    fn write(memory: &mut MemoryV4, addr: usize) -> bool {
+        memory
+            .write::<true, true, false>(
+                addr,
+                0,
+                #[cfg(feature = "conflict_pair")]
+                0,
+            )
+            .unwrap()
+    }
+
+    #[test]
+    pub fn cache_config_test() {
+        // Words 0, 8 and 16 share set 0 of a 4-set cache with 2-word lines.
+        let run = |cache: &str| {
+            let mut memory = MemoryV4::with_cache(true, cache.parse().unwrap());
+            let hits = (0..4)
+                .flat_map(|_| [0, 8, 1, 16])
+                .filter(|&addr| read(&mut memory, addr))
+                .count();
+            (hits, memory.stat.first_miss)
+        };
+
+        assert_eq!(run("sets=4,line=2"), (0, 1));
+        assert_eq!(run("sets=4,line=2,ways=2,policy=lru"), (7, 2));
+        assert_eq!(run("sets=4,line=2,ways=2,policy=sc"), (7, 2));
+        assert_eq!(run("sets=4,line=2,ways=2,policy=fifo"), (4, 2));
+        assert_eq!(run("sets=4,line=2,ways=4"), (13, 3));
+        assert_eq!(run("sets=1,line=16,ways=2"), (14, 2));
+
+        assert!("sets=3".parse::<CacheConfig>().is_err());
+        assert!("ways=2,policy=random".parse::<CacheConfig>().is_err());
+        assert_eq!(
+            CacheConfig::default().to_string(),
+            "16384 lines x 4 words direct-mapped write-back cache"
+        );
+    }
+
+    #[test]
+    pub fn write_policy_test() {
+        // Words 0 and 8 share the only line of set 0; word 1 is in the same line as 0.
+        let run = |cache: &str| {
+            let config = format!("sets=4,line=2,{}", cache).parse().unwrap();
+            let mut memory = MemoryV4::with_cache(true, config);
+            let mut write_hits = write(&mut memory, 0) as u64 + write(&mut memory, 8) as u64;
+            read(&mut memory, 0);
+            write_hits += write(&mut memory, 1) as u64;
+            let stat = &memory.stat;
+            (
+                write_hits,
+                stat.write_back,
+                stat.memory_read,
+                stat.memory_write,
+            )
+        };
+
+        assert_eq!(run("write=back"), (1, 2, 6, 4));
+        assert_eq!(run("write=through"), (1, 0, 6, 3));
+        assert_eq!(run("write=through,allocate=false"), (1, 0, 2, 3));
+        assert_eq!(run("write=back,allocate=false"), (1, 0, 2, 2));
+
+        assert!(CacheConfig::default().hardware_write_policy());
+        for cache in ["write=through", "allocate=false"] {
+            assert!(!cache
+                .parse::<CacheConfig>()
+                .unwrap()
+                .hardware_write_policy());
+        }
+    }
+}
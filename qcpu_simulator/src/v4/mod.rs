@@ -1,90 +1,254 @@
+pub mod accuracy;
+pub mod blocks;
 pub mod bp;
+pub mod checkpoint;
 mod decode;
 pub mod execute;
+pub mod gdb;
+pub mod heatmap;
 pub mod log;
+pub mod machine;
 pub mod memory;
+pub mod pointers;
+pub mod profile;
+pub mod shadow;
 pub mod stat;
 pub mod syntax;
-mod table;
+pub mod timing;
+pub mod trace;
+pub mod watch;
 
 use std::{
+    collections::BTreeSet,
+    fmt::{Debug, Display},
     fs::File,
-    io::{BufReader, BufWriter, Read},
+    io::{BufReader, BufWriter, Read, Write},
     path::PathBuf,
 };
 
-use bp::BranchPredictor;
+use crate::fpu::FpuModel;
+use accuracy::FpuAccuracy;
+use blocks::BlockProfile;
+use bp::{BranchPredictor, PredictorConfig};
 use decode::decode;
-use memory::MemoryV4;
+use machine::Machine;
+use memory::{CacheConfig, MemoryV4};
+use pointers::{PointerStat, GP, SP};
+use profile::CallProfile;
+use qcpu_syntax::ParsingContext;
 use serde::Serialize;
+use shadow::{ShadowMemory, UninitCheck};
 use stat::Statistics;
 use syntax::{OpName, OpV4, Reg};
+use timing::{Timing, TimingConfig};
+use trace::TraceWriter;
 
-#[derive(Debug, Default, Clone)]
-pub struct SimulatorV4Builder {
+/// Builds a [`SimulatorV4`] either from files on disk (`bin`, `input`, `output`, `log`)
+/// or from in-memory sources set with the `with_*` methods, which take precedence.
+///
+/// When the program comes from `bin`, missing paths fall back to the files next to it
+/// (`contest`, `<bin>.ppm`, a timestamped log). When the program is given in memory,
+/// missing streams fall back to an empty input and discarding sinks instead.
+#[derive(Default)]
+pub struct SimulatorV4Builder<'a> {
     pub input: Option<PathBuf>,
     pub output: Option<PathBuf>,
     pub bin: PathBuf,
     pub verbose: bool,
+    /// Halt on out-of-bounds memory accesses instead of leaving them unchecked.
+    pub checked: bool,
     pub log: Option<PathBuf>,
+    /// Labels used to annotate halts.
+    pub ctx: Option<ParsingContext>,
+    pub fpu: FpuModel,
+    /// Only simulated when `verbose` is set.
+    pub cache: CacheConfig,
+    /// Runs the in-loop timing model. Implies `verbose`.
+    pub timing: Option<TimingConfig>,
+    /// Latencies and penalties for the cycle estimate and the timing model.
+    pub machine: Machine,
+    /// Only simulated when `verbose` is set, like `cache`.
+    pub branch_predictor: PredictorConfig,
+    /// Predictors simulated alongside `branch_predictor` for comparison.
+    pub compare_predictors: Vec<PredictorConfig>,
+    /// Return address stack entries for every predictor, 0 for none.
+    pub ras_depth: usize,
+    /// Tracks written memory words to catch loads of never-written ones.
+    pub uninit: Option<UninitCheck>,
+    /// Halt once the values `sp` and `gp` have taken overlap.
+    pub collision_check: bool,
+
+    pub program: Option<Vec<u32>>,
+    pub input_reader: Option<Box<dyn Read + 'a>>,
+    pub output_writer: Option<Box<dyn Write + 'a>>,
+    pub log_writer: Option<Box<dyn Write + 'a>>,
 }
 
-impl SimulatorV4Builder {
-    pub fn build(self) -> SimulatorV4 {
-        let mut program_unchunked = Vec::<u8>::with_capacity(131072);
-        File::open(&self.bin)
-            .unwrap()
-            .read_to_end(&mut program_unchunked)
-            .unwrap();
-        let program: Vec<u32> = program_unchunked
-            .chunks_exact(4)
-            .map(|c| u32::from_le_bytes(unsafe { *(c.as_ptr() as *const [_; 4]) }))
-            .collect();
+/// Reads a little-endian machine code binary into instruction words.
+pub fn read_program(mut reader: impl Read) -> std::io::Result<Vec<u32>> {
+    let mut program_unchunked = Vec::<u8>::with_capacity(131072);
+    reader.read_to_end(&mut program_unchunked)?;
+    Ok(program_unchunked
+        .chunks_exact(4)
+        .map(|c| u32::from_le_bytes(unsafe { *(c.as_ptr() as *const [_; 4]) }))
+        .collect())
+}
 
-        let output = self
-            .output
-            .unwrap_or_else(|| self.bin.with_extension("ppm"));
-
-        let log = self.log.unwrap_or_else(|| {
-            self.bin.parent().unwrap().join(format!(
-                "{}-{}{}.log",
-                self.bin.file_stem().unwrap().to_string_lossy(),
-                chrono::Local::now().format("%Y-%m-%d_%H-%M-%S"),
-                if self.verbose { "-verbose" } else { "" }
-            ))
-        });
+impl<'a> SimulatorV4Builder<'a> {
+    pub fn with_program(mut self, program: Vec<u32>) -> Self {
+        self.program = Some(program);
+        self
+    }
 
-        let input_target = File::options()
-            .read(true)
-            .open(self.input.unwrap_or_else(|| {
-                let parent = self.bin.parent().unwrap();
-                parent.join("contest")
-            }))
-            .expect("Input file not found");
+    pub fn with_program_reader(mut self, reader: impl Read) -> std::io::Result<Self> {
+        self.program = Some(read_program(reader)?);
+        Ok(self)
+    }
 
-        let output_target = File::options()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&output)
-            .expect("Output file not found");
+    pub fn with_input(mut self, input: impl Read + 'a) -> Self {
+        self.input_reader = Some(Box::new(input));
+        self
+    }
 
-        let log_target = File::options()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&log)
-            .expect("Log file not found");
+    pub fn with_output(mut self, output: impl Write + 'a) -> Self {
+        self.output_writer = Some(Box::new(output));
+        self
+    }
+
+    pub fn with_log(mut self, log: impl Write + 'a) -> Self {
+        self.log_writer = Some(Box::new(log));
+        self
+    }
+
+    pub fn verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    pub fn checked(mut self, checked: bool) -> Self {
+        self.checked = checked;
+        self
+    }
+
+    pub fn with_context(mut self, ctx: ParsingContext) -> Self {
+        self.ctx = Some(ctx);
+        self
+    }
+
+    pub fn with_fpu(mut self, fpu: FpuModel) -> Self {
+        self.fpu = fpu;
+        self
+    }
+
+    pub fn with_cache(mut self, cache: CacheConfig) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    pub fn with_timing(mut self, timing: TimingConfig) -> Self {
+        self.timing = Some(timing);
+        self
+    }
+
+    pub fn with_machine(mut self, machine: Machine) -> Self {
+        self.machine = machine;
+        self
+    }
+
+    pub fn with_branch_predictor(mut self, config: PredictorConfig) -> Self {
+        self.branch_predictor = config;
+        self
+    }
 
-        let input_reader = BufReader::new(input_target);
-        let output_writer = BufWriter::new(output_target);
-        let log_writer = BufWriter::new(log_target);
+    pub fn with_compare_predictors(mut self, configs: Vec<PredictorConfig>) -> Self {
+        self.compare_predictors = configs;
+        self
+    }
+
+    pub fn with_ras(mut self, depth: usize) -> Self {
+        self.ras_depth = depth;
+        self
+    }
+
+    pub fn with_uninit_check(mut self, check: UninitCheck) -> Self {
+        self.uninit = Some(check);
+        self
+    }
+
+    pub fn collision_check(mut self, check: bool) -> Self {
+        self.collision_check = check;
+        self
+    }
+
+    pub fn build(mut self) -> SimulatorV4<'a> {
+        self.verbose |= self.timing.is_some();
+        let in_memory = self.program.is_some();
+
+        let program = self
+            .program
+            .unwrap_or_else(|| read_program(File::open(&self.bin).unwrap()).unwrap());
+
+        let output_file = match self.output_writer {
+            Some(_) => None,
+            None if in_memory => self.output,
+            None => Some(
+                self.output
+                    .unwrap_or_else(|| self.bin.with_extension("ppm")),
+            ),
+        };
+
+        let log_file = match self.log_writer {
+            Some(_) => None,
+            None if in_memory => self.log,
+            None => Some(self.log.unwrap_or_else(|| {
+                self.bin.parent().unwrap().join(format!(
+                    "{}-{}{}.log",
+                    self.bin.file_stem().unwrap().to_string_lossy(),
+                    chrono::Local::now().format("%Y-%m-%d_%H-%M-%S"),
+                    if self.verbose { "-verbose" } else { "" }
+                ))
+            })),
+        };
+
+        let input_reader: Box<dyn Read + 'a> = match self.input_reader {
+            Some(reader) => reader,
+            None => match self.input {
+                Some(input) => Box::new(BufReader::new(
+                    File::options()
+                        .read(true)
+                        .open(input)
+                        .expect("Input file not found"),
+                )),
+                None if in_memory => Box::new(std::io::empty()),
+                None => Box::new(BufReader::new(
+                    File::options()
+                        .read(true)
+                        .open(self.bin.parent().unwrap().join("contest"))
+                        .expect("Input file not found"),
+                )),
+            },
+        };
+
+        let output_writer = BufWriter::new(match (self.output_writer, &output_file) {
+            (Some(writer), _) => writer,
+            (None, Some(output)) => create_file(output).expect("Output file not found"),
+            (None, None) => Box::new(std::io::sink()),
+        });
+
+        let log_writer = BufWriter::new(match (self.log_writer, &log_file) {
+            (Some(writer), _) => writer,
+            (None, Some(log)) => create_file(log).expect("Log file not found"),
+            (None, None) => Box::new(std::io::sink()),
+        });
 
         let decoded = program.iter().map(|&p| decode(p)).collect::<Vec<_>>();
 
         let decoded_len = decoded.len();
 
-        SimulatorV4 {
+        let mut memory = MemoryV4::with_cache(self.verbose, self.cache);
+        memory.shadow = self.uninit.map(|check| Box::new(ShadowMemory::new(check)));
+
+        let mut sim = SimulatorV4 {
             // program,
             input: input_reader,
             per_instruction_stat: if self.verbose {
@@ -92,25 +256,66 @@ impl SimulatorV4Builder {
             } else {
                 Vec::new()
             },
+            per_branch_stat: if self.verbose {
+                vec![BranchStat::default(); decoded_len]
+            } else {
+                Vec::new()
+            },
             output: output_writer,
-            output_file: output,
-            log_file: log,
+            output_file,
+            log_file,
             log: log_writer,
             decoded_len,
             instructions: decoded,
+            ctx: self.ctx,
             verbose: self.verbose,
+            checked: self.checked,
+            fpu: self.fpu,
             reg: [0; 64],
             pc: 0,
             next_pc: 0,
-            memory: MemoryV4::new(self.verbose),
+            input_offset: 0,
+            output_offset: 0,
+            memory,
             stat: Statistics::default(),
-            bp: BranchPredictor::new(),
+            bp: BranchPredictor::with_config(self.branch_predictor).with_ras(self.ras_depth),
+            compare_bp: self
+                .compare_predictors
+                .iter()
+                .map(|&config| BranchPredictor::with_config(config).with_ras(self.ras_depth))
+                .collect(),
             cache_hit: false,
             op: OpV4::default(),
-        }
+            breakpoints: BTreeSet::new(),
+            watched_registers: 0,
+            pointers: PointerStat::default(),
+            collision_check: self.collision_check,
+            observing: false,
+            skip_breakpoint: None,
+            trace: None,
+            fpu_accuracy: None,
+            call_profile: None,
+            block_profile: None,
+            timing: self
+                .timing
+                .map(|config| Box::new(Timing::new(config, self.machine))),
+            machine: self.machine,
+        };
+        sim.update_observing();
+        sim
     }
 }
 
+fn create_file<'a>(path: &PathBuf) -> std::io::Result<Box<dyn Write + 'a>> {
+    Ok(Box::new(
+        File::options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?,
+    ))
+}
+
 #[derive(Debug, Default, Clone, Serialize)]
 pub struct Instat {
     pub hit: u64,
@@ -118,8 +323,16 @@ pub struct Instat {
     pub prev_ma: u64,
 }
 
-#[derive(Debug)]
-pub struct SimulatorV4 {
+/// Outcomes of a branch or `jalr` under the primary branch predictor, indexed like
+/// [`Instat`].
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct BranchStat {
+    pub call: u64,
+    pub taken: u64,
+    pub flush: u64,
+}
+
+pub struct SimulatorV4<'a> {
     // Reorder fields for better cache locality - group frequently accessed fields together
     pub pc: u32,         // Hot: accessed every iteration
     pub next_pc: u32,    // Hot: accessed every iteration
@@ -130,32 +343,252 @@ pub struct SimulatorV4 {
     // Less frequently accessed fields
     pub memory: MemoryV4,
     pub bp: BranchPredictor,
+    /// Not part of checkpoints, like `timing`.
+    pub compare_bp: Vec<BranchPredictor>,
     pub stat: Statistics,
     pub instructions: Vec<OpV4>,
     pub per_instruction_stat: Vec<Instat>,
-    pub input: BufReader<File>,
-    pub output: BufWriter<File>,
-    pub log: BufWriter<File>,
-    pub output_file: PathBuf,
-    pub log_file: PathBuf,
+    pub per_branch_stat: Vec<BranchStat>,
+    pub input: Box<dyn Read + 'a>,
+    pub output: BufWriter<Box<dyn Write + 'a>>,
+    pub log: BufWriter<Box<dyn Write + 'a>>,
+    pub output_file: Option<PathBuf>,
+    pub log_file: Option<PathBuf>,
     pub decoded_len: usize,
+    pub ctx: Option<ParsingContext>,
     pub verbose: bool,
+    pub checked: bool,
+    pub fpu: FpuModel,
+    pub machine: Machine,
+    /// Bytes consumed from `input` and written to `output` so far.
+    pub input_offset: u64,
+    pub output_offset: u64,
+    /// Always tracked, so `collision_check` works even without statistics.
+    pub pointers: PointerStat,
+    pub collision_check: bool,
+
+    // Debugging
+    pub breakpoints: BTreeSet<u32>,
+    pub watched_registers: u64,
+    observing: bool,
+    skip_breakpoint: Option<u32>,
+    pub trace: Option<TraceWriter<'a>>,
+    pub fpu_accuracy: Option<Box<FpuAccuracy>>,
+    /// Only recorded when statistics are enabled. Not part of checkpoints.
+    pub call_profile: Option<Box<CallProfile>>,
+    /// Like `call_profile`.
+    pub block_profile: Option<Box<BlockProfile>>,
+    /// Not part of checkpoints; a restored simulator times from the restore point on.
+    pub timing: Option<Box<Timing>>,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Debug for SimulatorV4<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SimulatorV4")
+            .field("pc", &self.pc)
+            .field("next_pc", &self.next_pc)
+            .field("reg", &self.reg)
+            .field("op", &self.op)
+            .field("stat", &self.stat)
+            .field("output_file", &self.output_file)
+            .field("log_file", &self.log_file)
+            .field("decoded_len", &self.decoded_len)
+            .field("verbose", &self.verbose)
+            .field("fpu", &self.fpu)
+            .field("breakpoints", &self.breakpoints)
+            .field("watched_registers", &self.watched_registers)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Why and where the simulator stopped. `pc` and `line` point at the instruction that
+/// caused the halt, or at the next one for halts that stop in front of an instruction.
+#[derive(Debug, Clone)]
 pub struct SimulatorV4HaltDetail {
     pub op: OpV4,
     pub line: usize,
+    pub pc: u32,
+    /// The nearest label at or before `pc`, when the simulator has a `ParsingContext`.
+    pub label: Option<String>,
     pub kind: SimulatorV4HaltKind,
 }
 
-#[derive(Debug, Clone, Copy)]
+impl Display for SimulatorV4HaltDetail {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at 0x{:05x}", self.kind, self.pc)?;
+        if let Some(label) = &self.label {
+            write!(f, " <{}>", label)?;
+        }
+        write!(f, " ({})", self.op)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SimulatorV4HaltKind {
-    MemoryAccess { bound: usize, index: usize },
+    /// The program ran past its last instruction.
     Complete,
+    /// The word at `pc` does not encode a supported instruction.
+    IllegalInstruction {
+        word: u32,
+    },
+    /// `inw` ran out of input.
+    InputExhausted,
+    /// Reading the input or writing the output failed.
+    Io {
+        kind: std::io::ErrorKind,
+    },
+    MemoryAccess {
+        bound: usize,
+        index: usize,
+    },
+    /// A jump or branch to a misaligned address or out of the program; the jump has not
+    /// been executed.
+    JumpOutOfBounds {
+        target: u32,
+    },
+    /// `run_for` executed the requested number of instructions.
+    InstructionLimit {
+        count: u64,
+    },
+    /// The estimated cycle count reached the limit in [`RunLimits`].
+    CycleLimit {
+        cycles: u64,
+    },
+    /// `run_until` reached the requested PC; the instruction there has not run yet.
+    ReachedPc {
+        pc: u32,
+    },
+    /// A breakpoint at `pc` was reached; the instruction there has not run yet.
+    Breakpoint {
+        pc: u32,
+    },
+    /// The halted instruction accessed a watched memory word.
+    MemoryWatch {
+        addr: usize,
+        value: u32,
+        write: bool,
+    },
+    /// The halted instruction changed a watched register.
+    RegisterWatch {
+        reg: Reg,
+        old: u32,
+        new: u32,
+    },
+    /// The halted instruction moved `sp` or `gp` so that the ranges of values the stack
+    /// and the heap pointer have taken overlap.
+    StackHeapCollision {
+        stack: (u32, u32),
+        heap: (u32, u32),
+    },
+    /// The halted instruction loaded the word at `addr`, which was never written.
+    UninitializedRead {
+        addr: usize,
+    },
 }
 
-impl SimulatorV4 {
+impl SimulatorV4HaltKind {
+    /// The process exit code `qcpu sim` reports for this halt. Exit code 1 is left for
+    /// usage errors.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            SimulatorV4HaltKind::Complete => 0,
+            SimulatorV4HaltKind::IllegalInstruction { .. } => 2,
+            SimulatorV4HaltKind::InputExhausted => 3,
+            SimulatorV4HaltKind::Io { .. } => 4,
+            SimulatorV4HaltKind::MemoryAccess { .. } => 5,
+            SimulatorV4HaltKind::JumpOutOfBounds { .. } => 6,
+            SimulatorV4HaltKind::InstructionLimit { .. } => 7,
+            SimulatorV4HaltKind::CycleLimit { .. } => 8,
+            SimulatorV4HaltKind::ReachedPc { .. } => 9,
+            SimulatorV4HaltKind::Breakpoint { .. } => 10,
+            SimulatorV4HaltKind::MemoryWatch { .. } => 11,
+            SimulatorV4HaltKind::RegisterWatch { .. } => 12,
+            SimulatorV4HaltKind::StackHeapCollision { .. } => 13,
+            SimulatorV4HaltKind::UninitializedRead { .. } => 14,
+        }
+    }
+}
+
+impl Display for SimulatorV4HaltKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SimulatorV4HaltKind::Complete => write!(f, "Complete"),
+            SimulatorV4HaltKind::IllegalInstruction { word } => {
+                write!(f, "Illegal instruction 0x{:08x}", word)
+            }
+            SimulatorV4HaltKind::InputExhausted => write!(f, "Input exhausted"),
+            SimulatorV4HaltKind::Io { kind } => write!(f, "I/O error: {}", kind),
+            SimulatorV4HaltKind::MemoryAccess { bound, index } => {
+                write!(f, "Memory access 0x{:x} out of bounds 0x{:x}", index, bound)
+            }
+            SimulatorV4HaltKind::JumpOutOfBounds { target } => {
+                write!(f, "Jump to 0x{:x} outside the program", target)
+            }
+            SimulatorV4HaltKind::InstructionLimit { count } => {
+                write!(f, "Instruction limit of {} reached", count)
+            }
+            SimulatorV4HaltKind::CycleLimit { cycles } => {
+                write!(f, "Cycle limit reached after {} cycles", cycles)
+            }
+            SimulatorV4HaltKind::ReachedPc { pc } => write!(f, "Reached 0x{:05x}", pc),
+            SimulatorV4HaltKind::Breakpoint { pc } => write!(f, "Breakpoint at 0x{:05x}", pc),
+            SimulatorV4HaltKind::MemoryWatch { addr, value, write } => write!(
+                f,
+                "Watched memory 0x{:x} {} 0x{:08x}",
+                addr,
+                if *write { "written with" } else { "read as" },
+                value
+            ),
+            SimulatorV4HaltKind::RegisterWatch { reg, old, new } => write!(
+                f,
+                "Watched register {} changed from 0x{:08x} to 0x{:08x}",
+                syntax::get_reg_name(*reg),
+                old,
+                new
+            ),
+            SimulatorV4HaltKind::StackHeapCollision { stack, heap } => write!(
+                f,
+                "Stack 0x{:x}..0x{:x} and heap 0x{:x}..0x{:x} collide",
+                stack.0, stack.1, heap.0, heap.1
+            ),
+            SimulatorV4HaltKind::UninitializedRead { addr } => {
+                write!(f, "Load from uninitialized memory 0x{:x}", addr)
+            }
+        }
+    }
+}
+
+/// Stop conditions for [`SimulatorV4::run_with`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RunLimits {
+    pub max_instructions: Option<u64>,
+    /// Checked every [`CYCLE_CHECK_INTERVAL`] instructions against the same estimate as
+    /// [`SimulatorV4::tally`], so it needs a verbose simulator.
+    pub max_cycles: Option<u64>,
+    /// Stop in front of this PC, after executing at least one instruction.
+    pub until_pc: Option<u32>,
+}
+
+pub const CYCLE_CHECK_INTERVAL: u64 = 1 << 16;
+
+/// Calls `$sim.$f::<CHECKED, VERBOSE, EXTRAS>($args)` with the parameters taken from
+/// the simulator's `checked` and `verbose` and from `$extras`.
+macro_rules! monomorphize {
+    ($sim:ident, $extras:expr, $f:ident($($arg:expr),*)) => {
+        match ($sim.checked, $sim.verbose, $extras) {
+            (false, false, false) => $sim.$f::<false, false, false>($($arg),*),
+            (false, false, true) => $sim.$f::<false, false, true>($($arg),*),
+            (false, true, false) => $sim.$f::<false, true, false>($($arg),*),
+            (false, true, true) => $sim.$f::<false, true, true>($($arg),*),
+            (true, false, false) => $sim.$f::<true, false, false>($($arg),*),
+            (true, false, true) => $sim.$f::<true, false, true>($($arg),*),
+            (true, true, false) => $sim.$f::<true, true, false>($($arg),*),
+            (true, true, true) => $sim.$f::<true, true, true>($($arg),*),
+        }
+    };
+}
+
+impl SimulatorV4<'_> {
     #[inline(always)]
     pub fn get_reg(&self, reg: Reg) -> u32 {
         unsafe { *self.reg.get_unchecked(reg as usize) }
@@ -176,84 +609,265 @@ impl SimulatorV4 {
         unsafe { self.reg.get_unchecked_mut(reg as usize) }
     }
 
-    pub fn run(&mut self) -> Result<(), SimulatorV4HaltDetail> {
+    /// Runs until the program falls off the end or something else halts it.
+    pub fn run(&mut self) -> SimulatorV4HaltDetail {
+        monomorphize!(self, self.has_extras(), run_loop())
+    }
+
+    /// Whether anything beyond the statistics of `verbose` is enabled: observers,
+    /// the collision check, the timing model, profiles, predictor comparisons or a
+    /// heatmap. Runs without any of them take loops that leave out their checks.
+    fn has_extras(&self) -> bool {
+        self.observing
+            || self.collision_check
+            || self.timing.is_some()
+            || self.call_profile.is_some()
+            || self.block_profile.is_some()
+            || !self.compare_bp.is_empty()
+            || self.memory.heatmap.is_some()
+    }
+
+    fn run_loop<const CHECKED: bool, const VERBOSE: bool, const EXTRAS: bool>(
+        &mut self,
+    ) -> SimulatorV4HaltDetail {
         loop {
-            let index = (self.pc >> 2) as usize;
-
-            if index >= self.decoded_len {
-                return Err(SimulatorV4HaltDetail {
-                    op: self.op,
-                    line: index - 1,
-                    kind: SimulatorV4HaltKind::Complete,
-                });
+            if let Err(halt) = self.step_with::<CHECKED, VERBOSE, EXTRAS>() {
+                return halt;
             }
-            #[cfg(feature = "full_ops")]
-            if self.verbose {
-                match self.op.opname {
-                    OpName::Lwr
-                    | OpName::Lw
-                    | OpName::Sw
-                    | OpName::Inw
-                    | OpName::Swi
-                    | OpName::Lwi => {
-                        let stat = unsafe { self.per_instruction_stat.get_unchecked_mut(index) };
-                        stat.prev_ma += 1;
-                    }
-                    _ => {}
+        }
+    }
+
+    /// Runs at most `count` instructions. Halting early (e.g. on completion) takes
+    /// precedence over `InstructionLimit`.
+    pub fn run_for(&mut self, count: u64) -> SimulatorV4HaltDetail {
+        self.run_with(RunLimits {
+            max_instructions: Some(count),
+            ..Default::default()
+        })
+    }
+
+    /// Runs until the PC reaches `pc`. At least one instruction is executed, so calling
+    /// this again while stopped at `pc` resumes to the next time `pc` is reached.
+    pub fn run_until(&mut self, pc: u32) -> SimulatorV4HaltDetail {
+        self.run_with(RunLimits {
+            until_pc: Some(pc),
+            ..Default::default()
+        })
+    }
+
+    /// Runs until one of `limits` is reached or the program halts by itself.
+    pub fn run_with(&mut self, limits: RunLimits) -> SimulatorV4HaltDetail {
+        monomorphize!(self, self.has_extras(), run_with_limits(limits))
+    }
+
+    fn run_with_limits<const CHECKED: bool, const VERBOSE: bool, const EXTRAS: bool>(
+        &mut self,
+        limits: RunLimits,
+    ) -> SimulatorV4HaltDetail {
+        let mut executed = 0;
+
+        loop {
+            let mut chunk = limits.max_instructions.map_or(u64::MAX, |m| m - executed);
+            if limits.max_cycles.is_some() {
+                chunk = chunk.min(CYCLE_CHECK_INTERVAL);
+            }
+
+            if chunk == 0 {
+                return self
+                    .halt_before_next(SimulatorV4HaltKind::InstructionLimit { count: executed });
+            }
+
+            for _ in 0..chunk {
+                if let Err(halt) = self.step_with::<CHECKED, VERBOSE, EXTRAS>() {
+                    return halt;
+                }
+
+                if limits.until_pc == Some(self.pc) {
+                    return self.halt_before_next(SimulatorV4HaltKind::ReachedPc { pc: self.pc });
                 }
             }
+            executed += chunk;
 
-            #[cfg(not(feature = "full_ops"))]
-            if self.verbose {
-                match self.op.opname {
-                    OpName::Lwr | OpName::Lw | OpName::Sw | OpName::Inw => {
-                        let stat = unsafe { self.per_instruction_stat.get_unchecked_mut(index) };
-                        stat.prev_ma += 1;
-                    }
-                    _ => {}
+            if let Some(max) = limits.max_cycles {
+                let cycles = self.estimate_cycles();
+                if cycles >= max {
+                    return self.halt_before_next(SimulatorV4HaltKind::CycleLimit { cycles });
                 }
             }
+        }
+    }
 
-            self.op = unsafe { *self.instructions.get_unchecked(index) };
-            self.next_pc = self.pc + 4;
+    /// Executes the instruction at the current PC. Once the program has halted by
+    /// itself, every further call returns the same kind of halt again.
+    pub fn step(&mut self) -> Result<(), SimulatorV4HaltDetail> {
+        monomorphize!(self, true, step_with())
+    }
 
-            #[cfg(feature = "safe")]
-            self.execute().map_err(|kind| SimulatorV4HaltDetail {
-                op: self.op,
-                line: index,
-                kind,
-            })?;
+    /// `step`, monomorphized over whether memory accesses are bounds checked, whether
+    /// the simulator is `verbose` and whether it [has extras](Self::has_extras), so
+    /// that a loop carries no checks for what it cannot need. With `EXTRAS`, each extra
+    /// is still checked for at runtime.
+    #[inline(always)]
+    fn step_with<const CHECKED: bool, const VERBOSE: bool, const EXTRAS: bool>(
+        &mut self,
+    ) -> Result<(), SimulatorV4HaltDetail> {
+        // A misaligned PC rotates out of range too.
+        let index = self.pc.rotate_right(2) as usize;
+
+        if index >= self.decoded_len {
+            return Err(self.halt_outside_program());
+        }
+
+        if EXTRAS && self.observing && self.hit_breakpoint() {
+            return Err(self.halt_before_next(SimulatorV4HaltKind::Breakpoint { pc: self.pc }));
+        }
+
+        #[cfg(feature = "full_ops")]
+        if VERBOSE {
+            match self.op.opname {
+                OpName::Lwr | OpName::Lw | OpName::Sw | OpName::Inw | OpName::Swi | OpName::Lwi => {
+                    let stat = unsafe { self.per_instruction_stat.get_unchecked_mut(index) };
+                    stat.prev_ma += 1;
+                }
+                _ => {}
+            }
+        }
+
+        #[cfg(not(feature = "full_ops"))]
+        if VERBOSE {
+            match self.op.opname {
+                OpName::Lwr | OpName::Lw | OpName::Sw | OpName::Inw => {
+                    let stat = unsafe { self.per_instruction_stat.get_unchecked_mut(index) };
+                    stat.prev_ma += 1;
+                }
+                _ => {}
+            }
+        }
 
-            #[cfg(not(feature = "safe"))]
-            self.execute();
+        self.op = unsafe { *self.instructions.get_unchecked(index) };
+        self.next_pc = self.pc + 4;
 
-            if self.verbose {
-                self.update_statistics(index);
+        let rd_before = if EXTRAS && self.observing {
+            if self.fpu_accuracy.is_some() {
+                self.record_fpu_accuracy(index);
             }
+            self.get_reg(self.op.rd)
+        } else {
+            0
+        };
 
-            self.pc = self.next_pc;
+        if let Err(kind) = self.execute::<CHECKED, VERBOSE, EXTRAS>() {
+            return Err(self.halt(index, self.op, kind));
         }
+
+        if VERBOSE {
+            self.update_statistics::<EXTRAS>(index);
+        }
+
+        self.pc = self.next_pc;
+
+        if EXTRAS && self.observing {
+            self.observe(index, rd_before)?;
+        }
+
+        if (VERBOSE || EXTRAS && self.collision_check) && matches!(self.op.rd, SP | GP) {
+            self.update_pointers(index)?;
+        }
+
+        Ok(())
+    }
+
+    /// The PC is outside the program. Reaching the word right after the last instruction
+    /// is a normal completion; anything else is a PC set from outside, such as by a
+    /// debugger.
+    #[cold]
+    fn halt_outside_program(&self) -> SimulatorV4HaltDetail {
+        let index = (self.pc >> 2) as usize;
+        if index == self.decoded_len && self.pc & 3 == 0 {
+            return self.halt(
+                index.saturating_sub(1),
+                self.op,
+                SimulatorV4HaltKind::Complete,
+            );
+        }
+
+        self.halt(
+            index,
+            OpV4::default(),
+            SimulatorV4HaltKind::JumpOutOfBounds { target: self.pc },
+        )
+    }
+
+    /// Builds a halt for the instruction at `line`.
+    pub(crate) fn halt(
+        &self,
+        line: usize,
+        op: OpV4,
+        kind: SimulatorV4HaltKind,
+    ) -> SimulatorV4HaltDetail {
+        SimulatorV4HaltDetail {
+            op,
+            line,
+            pc: (line as u32) << 2,
+            label: self
+                .ctx
+                .as_ref()
+                .map(|ctx| ctx.reverse_lookup_floor(line).to_string()),
+            kind,
+        }
+    }
+
+    /// Describes a stop in front of the instruction at the current PC.
+    fn halt_before_next(&self, kind: SimulatorV4HaltKind) -> SimulatorV4HaltDetail {
+        let line = (self.pc >> 2) as usize;
+        self.halt(
+            line,
+            self.instructions.get(line).copied().unwrap_or_default(),
+            kind,
+        )
     }
 
     #[inline(always)]
-    fn update_statistics(&mut self, index: usize) {
+    fn update_statistics<const EXTRAS: bool>(&mut self, index: usize) {
         let stat = unsafe { self.per_instruction_stat.get_unchecked_mut(index) };
         stat.call += 1;
+        let mut mispredicted = false;
+        let mut miss = false;
         match self.op.opname {
-            OpName::Jalr | OpName::Beq | OpName::Bne | OpName::Blt | OpName::Bge => {
-                self.bp
-                    .update_taken(&self.op, self.pc as usize, self.next_pc as usize);
+            OpName::Jal | OpName::Jalr | OpName::Beq | OpName::Bne | OpName::Blt | OpName::Bge => {
+                mispredicted =
+                    self.bp
+                        .update_taken(&self.op, self.pc as usize, self.next_pc as usize);
+                if EXTRAS {
+                    for bp in self.compare_bp.iter_mut() {
+                        bp.update_taken(&self.op, self.pc as usize, self.next_pc as usize);
+                    }
+                }
+
+                if !matches!(self.op.opname, OpName::Jal) {
+                    let branch = unsafe { self.per_branch_stat.get_unchecked_mut(index) };
+                    branch.call += 1;
+                    branch.taken += (self.next_pc != self.pc.wrapping_add(4)) as u64;
+                    branch.flush += mispredicted as u64;
+                }
             }
             #[cfg(feature = "full_ops")]
             OpName::Lw | OpName::Lwr | OpName::Lwi | OpName::Sw | OpName::Swi => {
                 stat.hit += self.cache_hit as u64;
+                miss = !self.cache_hit;
             }
             #[cfg(not(feature = "full_ops"))]
             OpName::Lw | OpName::Lwr | OpName::Sw => {
                 stat.hit += self.cache_hit as u64;
+                miss = !self.cache_hit;
             }
             _ => {}
         }
+        if EXTRAS {
+            self.update_timing(mispredicted);
+            self.record_call_profile(miss);
+            self.record_block_profile(index, miss);
+        }
         self.cache_hit = false;
     }
 }
@@ -264,6 +878,365 @@ mod test {
 
     use super::*;
 
+    #[test]
+    pub fn in_memory_test() {
+        let code = r#"
+_min_caml_start:
+    inw     a0
+    addi    a0, a0, 1
+    outb    a0
+    inw     a1
+    outb    a1
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let input = [b'A' as u32, b'z' as u32]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut output = Vec::new();
+
+        {
+            let mut sim = SimulatorV4Builder::default()
+                .with_program(mc)
+                .with_input(input.as_slice())
+                .with_output(&mut output)
+                .build();
+
+            assert_eq!(sim.run().kind, SimulatorV4HaltKind::Complete);
+            assert_eq!(sim.get_reg(10), b'B' as u32);
+            assert!(sim.output_file.is_none());
+            assert!(sim.log_file.is_none());
+        }
+
+        assert_eq!(output, b"Bz");
+    }
+
+    #[test]
+    pub fn step_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 0
+    addi    a1, zero, 5
+loop:
+    addi    a0, a0, 1
+    blt     a0, a1, loop
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let mut sim = SimulatorV4Builder::default().with_program(mc).build();
+
+        let halt = sim.run_for(2);
+        assert!(matches!(
+            halt.kind,
+            SimulatorV4HaltKind::InstructionLimit { count: 2 }
+        ));
+        assert_eq!((halt.line, sim.pc, sim.get_reg(11)), (2, 8, 5));
+
+        for expected in 1..=2 {
+            let halt = sim.run_until(8);
+            assert!(matches!(
+                halt.kind,
+                SimulatorV4HaltKind::ReachedPc { pc: 8 }
+            ));
+            assert_eq!(sim.get_reg(10), expected);
+        }
+
+        sim.step().unwrap();
+        assert_eq!((sim.pc, sim.get_reg(10)), (12, 3));
+
+        let halt = sim.run();
+        assert!(matches!(halt.kind, SimulatorV4HaltKind::Complete));
+        assert_eq!(sim.get_reg(10), 5);
+        assert!(matches!(
+            sim.step().unwrap_err().kind,
+            SimulatorV4HaltKind::Complete
+        ));
+    }
+
+    #[test]
+    pub fn watch_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 0
+    addi    a1, zero, 3
+loop:
+    addi    a0, a0, 1
+    sw      a0, 16(zero)
+    blt     a0, a1, loop
+done:
+    lw      a2, 16(zero)
+        "#;
+        let (mc, ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let mut sim = SimulatorV4Builder::default().with_program(mc).build();
+
+        assert_eq!(sim.add_label_breakpoint(&ctx, "loop"), Some(8));
+        assert_eq!(sim.add_label_breakpoint(&ctx, "nowhere"), None);
+
+        for expected in 0..3 {
+            let halt = sim.run();
+            assert!(matches!(
+                halt.kind,
+                SimulatorV4HaltKind::Breakpoint { pc: 8 }
+            ));
+            assert_eq!(sim.get_reg(10), expected);
+        }
+
+        sim.clear_watches();
+        sim.watch_register(12);
+        sim.watch_memory(16..17, true, false);
+
+        let halt = sim.run();
+        assert!(matches!(
+            halt.kind,
+            SimulatorV4HaltKind::MemoryWatch {
+                addr: 16,
+                value: 3,
+                write: false
+            }
+        ));
+        assert_eq!(halt.line, 5);
+
+        sim.memory.watches.clear();
+        sim.pc = 20;
+        sim.set_reg(12, 0);
+        let halt = sim.run();
+        assert!(matches!(
+            halt.kind,
+            SimulatorV4HaltKind::RegisterWatch {
+                reg: 12,
+                old: 0,
+                new: 3
+            }
+        ));
+    }
+
+    #[test]
+    pub fn halt_test() {
+        let code = r#"
+_min_caml_start:
+    inw     a0
+    inw     a1
+escape:
+    jalr    zero, a0, 0
+        "#;
+        let (mc, ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let input = 0x100u32.to_le_bytes();
+
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .with_input(input.as_slice())
+            .with_context(ctx)
+            .build();
+        let halt = sim.run();
+        assert_eq!(halt.kind, SimulatorV4HaltKind::InputExhausted);
+        assert_eq!(
+            (halt.pc, halt.label.as_deref()),
+            (4, Some("_min_caml_start"))
+        );
+        assert_eq!(halt.kind.exit_code(), 3);
+
+        let input = [12u32, 0]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .with_input(input.as_slice())
+            .build();
+        let halt = sim.run_for(3);
+        assert!(matches!(
+            halt.kind,
+            SimulatorV4HaltKind::InstructionLimit { count: 3 }
+        ));
+        assert_eq!(sim.run().kind, SimulatorV4HaltKind::Complete);
+
+        let input = [0x40u32, 0]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .with_input(input.as_slice())
+            .build();
+        let halt = sim.run();
+        assert_eq!(
+            halt.kind,
+            SimulatorV4HaltKind::JumpOutOfBounds { target: 0x40 }
+        );
+        assert_eq!(halt.pc, 8);
+        assert_eq!(sim.run().kind, halt.kind);
+
+        let input = [6u32, 0]
+            .iter()
+            .flat_map(|w| w.to_le_bytes())
+            .collect::<Vec<_>>();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .with_input(input.as_slice())
+            .build();
+        let halt = sim.run();
+        assert_eq!(
+            halt.kind,
+            SimulatorV4HaltKind::JumpOutOfBounds { target: 6 }
+        );
+        assert_eq!((halt.pc, sim.pc), (8, 8));
+        assert_eq!(sim.run().kind, halt.kind);
+
+        sim.pc = 2;
+        assert_eq!(
+            sim.run().kind,
+            SimulatorV4HaltKind::JumpOutOfBounds { target: 2 }
+        );
+
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(vec![0xffff_ffff])
+            .build();
+        let halt = sim.run();
+        assert_eq!(
+            halt.kind,
+            SimulatorV4HaltKind::IllegalInstruction { word: 0xffff_ffff }
+        );
+        assert_eq!(halt.label, None);
+
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(
+            r#"
+_min_caml_start:
+    jal     zero, _min_caml_start
+        "#,
+            false,
+        )
+        .unwrap();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .verbose(true)
+            .build();
+        let halt = sim.run_with(RunLimits {
+            max_cycles: Some(1000),
+            ..Default::default()
+        });
+        assert!(matches!(
+            halt.kind,
+            SimulatorV4HaltKind::CycleLimit { cycles } if cycles >= 1000
+        ));
+    }
+
+    #[test]
+    pub fn checked_test() {
+        let code = r#"
+_min_caml_start:
+    lui     a0, 128
+    sw      a0, -4(a0)
+    lw      a1, 0(a0)
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .checked(true)
+            .build();
+        let halt = sim.run();
+        assert_eq!(
+            halt.kind,
+            SimulatorV4HaltKind::MemoryAccess {
+                bound: memory::MEMORY_SIZE,
+                index: memory::MEMORY_SIZE,
+            }
+        );
+        assert_eq!(halt.pc, 8);
+        assert_eq!(sim.memory.m[memory::MEMORY_SIZE - 4], 1 << 19);
+    }
+
+    #[test]
+    pub fn fpu_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 1
+    addi    a1, zero, 3
+    itof    fa0, a0
+    itof    fa1, a1
+    fdiv    fa2, fa0, fa1
+    fsqrt   fa3, fa1
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let (one, three) = (1.0f32.to_bits(), 3.0f32.to_bits());
+
+        for spec in [
+            "hardware",
+            "native",
+            "native,fdiv=hw",
+            "fsqrt=ieee,fdiv=hardware",
+        ] {
+            let fpu: FpuModel = spec.parse().unwrap();
+            let mut sim = SimulatorV4Builder::default()
+                .with_program(mc.clone())
+                .with_fpu(fpu)
+                .build();
+            assert_eq!(sim.run().kind, SimulatorV4HaltKind::Complete);
+            assert_eq!(sim.get_reg(44), fpu.fdiv(one, three), "{}", spec);
+            assert_eq!(sim.get_reg(45), fpu.fsqrt(three), "{}", spec);
+        }
+
+        let native = FpuModel::all(crate::fpu::FpuImpl::Native);
+        assert_eq!(native.fdiv(one, three), (1.0f32 / 3.0).to_bits());
+        assert_eq!(native.fsqrt(three), 3.0f32.sqrt().to_bits());
+
+        let mixed: FpuModel = "native,fdiv=hw".parse().unwrap();
+        assert_eq!(mixed.fdiv, crate::fpu::FpuImpl::Hardware);
+        assert_eq!(mixed.fsqrt, crate::fpu::FpuImpl::Native);
+        assert_eq!(mixed.to_string().parse::<FpuModel>(), Ok(mixed));
+        assert!("fadd=fast".parse::<FpuModel>().is_err());
+        assert!("fexp=native".parse::<FpuModel>().is_err());
+    }
+
+    #[test]
+    pub fn fpu_accuracy_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 10
+    addi    a1, zero, 3
+    itof    fa0, a0
+    itof    fa1, a1
+loop:
+    fdiv    fa2, fa0, fa1
+    fadd    fa0, fa0, fa2
+    addi    a0, a0, -1
+    bne     a0, zero, loop
+        "#;
+        let (mc, ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .with_context(ctx.clone())
+            .build();
+
+        sim.start_fpu_accuracy();
+        assert_eq!(sim.run().kind, SimulatorV4HaltKind::Complete);
+        let accuracy = sim.finish_fpu_accuracy().unwrap();
+        assert!(sim.fpu_accuracy.is_none());
+
+        assert_eq!(accuracy.per_op[6].count, 2);
+        assert_eq!(accuracy.per_op[3].count, 10);
+        assert_eq!(accuracy.per_op[3].histogram.iter().sum::<u64>(), 10);
+        let fdiv = &accuracy.per_pc[&16];
+        assert_eq!(fdiv.count, 10);
+        assert!(matches!(fdiv.op.opname, OpName::Fdiv));
+
+        assert_eq!(
+            accuracy::ulp_distance(OpName::Fadd, 0x3f800000, 0x3f800001),
+            1
+        );
+        assert_eq!(
+            accuracy::ulp_distance(OpName::Fadd, 0x80000001, 0x00000001),
+            2
+        );
+        assert_eq!(accuracy::ulp_distance(OpName::Ftoi, 3, -2i32 as u32), 5);
+        assert_eq!(accuracy::ulp_bucket(5), 3);
+
+        let mut report = Vec::new();
+        accuracy.write_report(&mut report, Some(&ctx), 5).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("fdiv: 10 executions"));
+    }
+
     #[test]
     pub fn decode_test() {
         let dir = std::env::current_dir().unwrap();
@@ -0,0 +1,219 @@
+//! Extents of the stack pointer and the heap pointer. The MinCaml runtime allocates by
+//! bumping `gp` and never frees, so once the values either pointer has taken overlap,
+//! the stack and the heap overwrite each other.
+
+use std::io;
+
+use qcpu_syntax::ParsingContext;
+use serde::Serialize;
+
+use super::{syntax::Reg, SimulatorV4, SimulatorV4HaltDetail, SimulatorV4HaltKind};
+
+pub const SP: Reg = 2;
+pub const GP: Reg = 3;
+
+/// The values a pointer register has been written with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Extent {
+    /// The first value written.
+    pub init: u32,
+    pub low: u32,
+    pub high: u32,
+    /// Instruction indices of the writes that reached `low` and `high`.
+    pub low_line: u32,
+    pub high_line: u32,
+}
+
+impl Extent {
+    fn new(value: u32, line: u32) -> Self {
+        Self {
+            init: value,
+            low: value,
+            high: value,
+            low_line: line,
+            high_line: line,
+        }
+    }
+
+    fn update(&mut self, value: u32, line: u32) {
+        if value < self.low {
+            self.low = value;
+            self.low_line = line;
+        }
+        if value > self.high {
+            self.high = value;
+            self.high_line = line;
+        }
+    }
+
+    /// Whether a value in one half-open range `low..high` lies in the other.
+    fn overlaps(&self, other: &Extent) -> bool {
+        self.low < other.high && other.low < self.high
+    }
+}
+
+/// Only tracked by verbose simulators and those checking for collisions. Not part of
+/// checkpoints; a restored simulator starts from the next write.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct PointerStat {
+    pub stack: Option<Extent>,
+    pub heap: Option<Extent>,
+}
+
+impl PointerStat {
+    /// Records a write of `value` to `reg` by the instruction at `line`.
+    #[inline(always)]
+    pub fn update(&mut self, reg: Reg, value: u32, line: u32) {
+        let extent = if reg == SP {
+            &mut self.stack
+        } else {
+            &mut self.heap
+        };
+        match extent {
+            Some(extent) => extent.update(value, line),
+            None => *extent = Some(Extent::new(value, line)),
+        }
+    }
+
+    /// Whether the stack and the heap overlap.
+    pub fn overlap(&self) -> bool {
+        matches!((&self.stack, &self.heap), (Some(stack), Some(heap)) if stack.overlaps(heap))
+    }
+
+    /// Writes where each pointer started and which instructions moved it the furthest.
+    pub fn write_report(
+        &self,
+        mut w: impl io::Write,
+        ctx: Option<&ParsingContext>,
+    ) -> io::Result<()> {
+        let at = |line: u32| match ctx {
+            Some(ctx) => format!(
+                "0x{:05x} <{}>",
+                line << 2,
+                ctx.reverse_lookup_floor(line as usize)
+            ),
+            None => format!("0x{:05x}", line << 2),
+        };
+
+        for (name, extent) in [("Stack", self.stack), ("Heap", self.heap)] {
+            match extent {
+                Some(e) => writeln!(
+                    w,
+                    "{}: starts at 0x{:x}, lowest 0x{:x} at {}, highest 0x{:x} at {}",
+                    name,
+                    e.init,
+                    e.low,
+                    at(e.low_line),
+                    e.high,
+                    at(e.high_line)
+                )?,
+                None => writeln!(w, "{}: pointer never set", name)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl SimulatorV4<'_> {
+    /// Called after every write to `sp` or `gp`. Halts on a collision if
+    /// `collision_check` is set.
+    #[inline(always)]
+    pub(super) fn update_pointers(&mut self, line: usize) -> Result<(), SimulatorV4HaltDetail> {
+        let reg = self.op.rd;
+        self.pointers.update(reg, self.get_reg(reg), line as u32);
+        if !self.collision_check || !self.pointers.overlap() {
+            return Ok(());
+        }
+
+        let (stack, heap) = (self.pointers.stack.unwrap(), self.pointers.heap.unwrap());
+        Err(self.halt(
+            line,
+            self.op,
+            SimulatorV4HaltKind::StackHeapCollision {
+                stack: (stack.low, stack.high),
+                heap: (heap.low, heap.high),
+            },
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::SimulatorV4Builder;
+
+    #[test]
+    pub fn extent_test() {
+        let mut pointers = PointerStat::default();
+        for (reg, value, line) in [(SP, 32, 0), (GP, 0, 1), (SP, 36, 2), (SP, 32, 3)] {
+            pointers.update(reg, value, line);
+        }
+        for (line, value) in [(5, 12), (5, 24)] {
+            pointers.update(GP, value, line);
+            assert!(!pointers.overlap());
+        }
+
+        let stack = pointers.stack.unwrap();
+        assert_eq!(
+            (stack.init, stack.low, stack.high, stack.high_line),
+            (32, 32, 36, 2)
+        );
+        let heap = pointers.heap.unwrap();
+        assert_eq!((heap.init, heap.high, heap.high_line), (0, 24, 5));
+
+        let mut report = Vec::new();
+        pointers.write_report(&mut report, None).unwrap();
+        assert_eq!(
+            String::from_utf8(report).unwrap(),
+            "Stack: starts at 0x20, lowest 0x20 at 0x00000, highest 0x24 at 0x00008\n\
+             Heap: starts at 0x0, lowest 0x0 at 0x00004, highest 0x18 at 0x00014\n"
+        );
+
+        // The next allocation reaches the stack.
+        pointers.update(GP, 36, 5);
+        assert!(pointers.overlap());
+    }
+
+    #[test]
+    pub fn collision_test() {
+        let code = r#"
+_min_caml_start:
+    addi    sp, zero, 32
+    addi    gp, zero, 0
+    addi    sp, sp, 4
+    addi    sp, sp, -4
+    addi    a0, zero, 3
+alloc:
+    addi    gp, gp, 12
+    addi    a0, a0, -1
+    blt     zero, a0, alloc
+        "#;
+        let (mc, ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        // The third allocation reaches the stack.
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc.clone())
+            .with_context(ctx)
+            .collision_check(true)
+            .build();
+        let halt = sim.run();
+        assert_eq!(
+            halt.kind,
+            SimulatorV4HaltKind::StackHeapCollision {
+                stack: (32, 36),
+                heap: (0, 36),
+            }
+        );
+        assert_eq!((halt.pc, halt.label.as_deref()), (20, Some("alloc")));
+        assert_eq!(halt.kind.exit_code(), 13);
+        assert_eq!(sim.reg[10], 1);
+
+        // Without the check, the collision is only recorded.
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .verbose(true)
+            .build();
+        assert_eq!(sim.run().kind, SimulatorV4HaltKind::Complete);
+        assert_eq!(sim.pointers.heap.unwrap().high, 36);
+    }
+}
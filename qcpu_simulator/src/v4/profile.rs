@@ -0,0 +1,484 @@
+//! A dynamic call graph built from `jal ra`/`jalr ra` calls, `jal zero` tail calls and
+//! `jalr zero, ra` returns. Costs are recorded per calling context, so the same tree gives
+//! per-function totals and folded stacks for flamegraph tools.
+
+use std::{
+    collections::HashMap,
+    io,
+    ops::{Add, AddAssign},
+};
+
+use qcpu_syntax::ParsingContext;
+use serde::Serialize;
+
+use super::{
+    syntax::{OpName, OpV4, Reg},
+    SimulatorV4,
+};
+
+const RA: Reg = 1;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Cost {
+    pub instructions: u64,
+    pub cycles: u64,
+    pub misses: u64,
+}
+
+impl Add for Cost {
+    type Output = Cost;
+
+    fn add(self, rhs: Self) -> Self::Output {
+        Cost {
+            instructions: self.instructions + rhs.instructions,
+            cycles: self.cycles + rhs.cycles,
+            misses: self.misses + rhs.misses,
+        }
+    }
+}
+
+impl AddAssign for Cost {
+    fn add_assign(&mut self, rhs: Self) {
+        *self = *self + rhs;
+    }
+}
+
+/// A function in one calling context.
+#[derive(Debug, Clone)]
+struct Node {
+    /// Instruction index of the function's entry.
+    entry: u32,
+    parent: usize,
+    calls: u64,
+    /// Spent in this context, excluding callees.
+    cost: Cost,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FunctionProfile {
+    pub entry: u32,
+    pub calls: u64,
+    /// Including callees. Recursive calls are only counted once.
+    pub inclusive: Cost,
+    pub exclusive: Cost,
+}
+
+#[derive(Debug, Clone)]
+pub struct CallProfile {
+    /// Node 0 is the program entry. Children always come after their parent.
+    nodes: Vec<Node>,
+    children: HashMap<(usize, u32), usize>,
+    /// Per instruction, whether it is a function entry, see [`function_entries`].
+    /// `jalr ra` calls add their targets as they happen.
+    entries: Vec<bool>,
+    current: usize,
+    last_cycles: u64,
+}
+
+/// Marks the targets of `jal ra` in `instructions`. A `jal zero` to one of them is a tail
+/// call rather than a jump within a function.
+pub fn function_entries(instructions: &[OpV4]) -> Vec<bool> {
+    let mut entries = vec![false; instructions.len()];
+    for (i, op) in instructions.iter().enumerate() {
+        if matches!(op.opname, OpName::Jal) && op.rd == RA {
+            let target = (((i as u32) << 2).wrapping_add(op.imm) >> 2) as usize;
+            if let Some(entry) = entries.get_mut(target) {
+                *entry = true;
+            }
+        }
+    }
+    entries
+}
+
+impl CallProfile {
+    /// Starts in the function at instruction index `entry` of `instructions`, with the
+    /// cycle counter at `cycles`.
+    pub fn new(instructions: &[OpV4], entry: u32, cycles: u64) -> Self {
+        Self {
+            nodes: vec![Node {
+                entry,
+                parent: 0,
+                calls: 1,
+                cost: Cost::default(),
+            }],
+            children: HashMap::new(),
+            entries: function_entries(instructions),
+            current: 0,
+            last_cycles: cycles,
+        }
+    }
+
+    /// Enters the function at `entry` as a callee of node `parent`.
+    fn enter(&mut self, parent: usize, entry: u32) {
+        let next = self.nodes.len();
+        let child = *self.children.entry((parent, entry)).or_insert(next);
+        if child == next {
+            self.nodes.push(Node {
+                entry,
+                parent,
+                calls: 0,
+                cost: Cost::default(),
+            });
+        }
+        self.nodes[child].calls += 1;
+        self.current = child;
+    }
+
+    /// Charges the instruction that just executed to the current function, then follows
+    /// the call or return it made. `cycles` is the running cycle count, if there is one;
+    /// otherwise the instruction takes a cycle.
+    #[inline(always)]
+    pub fn record(&mut self, op: &OpV4, next_pc: u32, cycles: Option<u64>, miss: bool) {
+        let cycles = cycles.unwrap_or(self.last_cycles + 1);
+        let cost = &mut self.nodes[self.current].cost;
+        cost.instructions += 1;
+        cost.cycles += cycles - self.last_cycles;
+        cost.misses += miss as u64;
+        self.last_cycles = cycles;
+
+        let target = next_pc >> 2;
+        match op.opname {
+            OpName::Jal | OpName::Jalr if op.rd == RA => {
+                if let Some(entry) = self.entries.get_mut(target as usize) {
+                    *entry = true;
+                }
+                self.enter(self.current, target);
+            }
+            // A tail call returns to the caller's caller, so the callee replaces the
+            // current function. The entry function has no caller to return to.
+            OpName::Jal
+                if op.rd == 0
+                    && self.current != 0
+                    && self.entries.get(target as usize) == Some(&true) =>
+            {
+                self.enter(self.nodes[self.current].parent, target);
+            }
+            // Returning from the entry function ends the program, so there is nowhere to go.
+            OpName::Jalr if op.rd == 0 && op.rs1 == RA && self.current != 0 => {
+                self.current = self.nodes[self.current].parent;
+            }
+            _ => {}
+        }
+    }
+
+    /// Costs of each calling context including its callees.
+    fn subtree_costs(&self) -> Vec<Cost> {
+        let mut totals: Vec<_> = self.nodes.iter().map(|n| n.cost).collect();
+        for i in (1..self.nodes.len()).rev() {
+            let total = totals[i];
+            totals[self.nodes[i].parent] += total;
+        }
+        totals
+    }
+
+    pub fn total(&self) -> Cost {
+        self.subtree_costs()[0]
+    }
+
+    /// Per-function totals, sorted by inclusive cycles.
+    pub fn functions(&self) -> Vec<FunctionProfile> {
+        let totals = self.subtree_costs();
+        let mut functions = HashMap::<u32, FunctionProfile>::new();
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let function = functions
+                .entry(node.entry)
+                .or_insert_with(|| FunctionProfile {
+                    entry: node.entry,
+                    calls: 0,
+                    inclusive: Cost::default(),
+                    exclusive: Cost::default(),
+                });
+            function.calls += node.calls;
+            function.exclusive += node.cost;
+
+            let mut ancestor = i;
+            let recursive = loop {
+                if ancestor == 0 {
+                    break false;
+                }
+                ancestor = self.nodes[ancestor].parent;
+                if self.nodes[ancestor].entry == node.entry {
+                    break true;
+                }
+            };
+            if !recursive {
+                function.inclusive += totals[i];
+            }
+        }
+
+        let mut functions: Vec<_> = functions.into_values().collect();
+        functions.sort_by(|a, b| {
+            b.inclusive
+                .cycles
+                .cmp(&a.inclusive.cycles)
+                .then(a.entry.cmp(&b.entry))
+        });
+        functions
+    }
+
+    /// Writes the per-function report.
+    pub fn write_report(
+        &self,
+        mut w: impl io::Write,
+        ctx: Option<&ParsingContext>,
+    ) -> io::Result<()> {
+        let total = self.total();
+        let percent = |n: u64, total: u64| n as f64 / total as f64 * 100.0;
+
+        writeln!(
+            w,
+            "Call profile: {} instructions, {} cycles, {} cache misses",
+            total.instructions, total.cycles, total.misses
+        )?;
+        writeln!(
+            w,
+            "{:40} {:>10} {:>14} {:>7} {:>14} {:>7} {:>14} {:>14} {:>10} {:>10}",
+            "Function",
+            "Calls",
+            "Incl. cycles",
+            "%",
+            "Excl. cycles",
+            "%",
+            "Incl. instr",
+            "Excl. instr",
+            "Incl. miss",
+            "Excl. miss",
+        )?;
+        for function in self.functions() {
+            writeln!(
+                w,
+                "{:40} {:>10} {:>14} {:>6.02}% {:>14} {:>6.02}% {:>14} {:>14} {:>10} {:>10}",
+                function_name(function.entry, ctx),
+                function.calls,
+                function.inclusive.cycles,
+                percent(function.inclusive.cycles, total.cycles),
+                function.exclusive.cycles,
+                percent(function.exclusive.cycles, total.cycles),
+                function.inclusive.instructions,
+                function.exclusive.instructions,
+                function.inclusive.misses,
+                function.exclusive.misses,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes one `caller;callee count` line per calling context, weighted by exclusive
+    /// cycles, for `flamegraph.pl` and compatible tools.
+    pub fn write_folded(
+        &self,
+        mut w: impl io::Write,
+        ctx: Option<&ParsingContext>,
+    ) -> io::Result<()> {
+        let mut names = HashMap::new();
+        let mut stacks = vec![String::new(); self.nodes.len()];
+
+        for (i, node) in self.nodes.iter().enumerate() {
+            let name = names
+                .entry(node.entry)
+                .or_insert_with(|| function_name(node.entry, ctx).replace([';', ' '], "_"));
+            stacks[i] = if i == 0 {
+                name.clone()
+            } else {
+                format!("{};{}", stacks[node.parent], name)
+            };
+
+            if node.cost.cycles > 0 {
+                writeln!(w, "{} {}", stacks[i], node.cost.cycles)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn function_name(entry: u32, ctx: Option<&ParsingContext>) -> String {
+    match ctx {
+        Some(ctx) => ctx.reverse_lookup_floor(entry as usize).to_string(),
+        None => format!("0x{:05x}", entry << 2),
+    }
+}
+
+impl SimulatorV4<'_> {
+    /// Starts building the call graph from the current PC. Statistics must be enabled.
+    /// Cycles come from the timing model; without it every instruction counts as one.
+    pub fn start_call_profile(&mut self) {
+        let cycles = self.timing.as_ref().map_or(0, |t| t.stat.cycles);
+        self.call_profile = Some(Box::new(CallProfile::new(
+            &self.instructions,
+            self.pc >> 2,
+            cycles,
+        )));
+    }
+
+    pub fn finish_call_profile(&mut self) -> Option<CallProfile> {
+        self.call_profile.take().map(|p| *p)
+    }
+
+    #[inline(always)]
+    pub(super) fn record_call_profile(&mut self, miss: bool) {
+        let cycles = self.timing.as_ref().map(|t| t.stat.cycles);
+        if let Some(profile) = self.call_profile.as_mut() {
+            profile.record(&self.op, self.next_pc, cycles, miss);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::{timing::TimingConfig, SimulatorV4Builder};
+
+    fn op(opname: OpName, rd: Reg, rs1: Reg) -> OpV4 {
+        OpV4 {
+            opname,
+            rd,
+            rs1,
+            ..Default::default()
+        }
+    }
+
+    /// A `jal rd` from instruction index `from` to `to`.
+    fn jal(rd: Reg, from: u32, to: u32) -> OpV4 {
+        OpV4 {
+            imm: (to.wrapping_sub(from)) << 2,
+            ..op(OpName::Jal, rd, 0)
+        }
+    }
+
+    fn ret() -> OpV4 {
+        op(OpName::Jalr, 0, RA)
+    }
+
+    /// Profiles the instructions at the indices in `path`, in order.
+    fn profile(instructions: &[OpV4], path: &[u32]) -> CallProfile {
+        let mut profile = CallProfile::new(instructions, path[0], 0);
+        for (i, &index) in path.iter().enumerate() {
+            let next_pc = path.get(i + 1).map_or(0, |next| next << 2);
+            profile.record(&instructions[index as usize], next_pc, None, false);
+        }
+        profile
+    }
+
+    fn folded(profile: &CallProfile) -> String {
+        let mut folded = Vec::new();
+        profile.write_folded(&mut folded, None).unwrap();
+        String::from_utf8(folded).unwrap()
+    }
+
+    #[test]
+    pub fn call_graph_test() {
+        let addi = op(OpName::Addi, 10, 10);
+        let (f, g, r) = (5, 7, 8);
+        let instructions = [
+            jal(RA, 0, f),
+            jal(RA, 1, f),
+            jal(RA, 2, r),
+            addi,
+            addi,
+            // f
+            jal(RA, 5, g),
+            ret(),
+            // g
+            ret(),
+            // r, calling itself once
+            addi,
+            op(OpName::Blt, 0, 0),
+            ret(),
+            jal(RA, 11, r),
+            ret(),
+        ];
+        let profile = profile(
+            &instructions,
+            &[0, 5, 7, 6, 1, 5, 7, 6, 2, 8, 9, 11, 8, 9, 10, 12, 3],
+        );
+        assert_eq!(profile.total().instructions, 17);
+
+        let functions = profile.functions();
+        let summary = |entry| {
+            let function = functions.iter().find(|p| p.entry == entry).unwrap();
+            (
+                function.calls,
+                function.inclusive.instructions,
+                function.exclusive.instructions,
+            )
+        };
+        assert_eq!(summary(0), (1, 17, 4));
+        assert_eq!(summary(f), (2, 6, 4));
+        assert_eq!(summary(g), (2, 2, 2));
+        // The recursive call is part of the outer one.
+        assert_eq!(summary(r), (2, 7, 7));
+
+        assert_eq!(
+            folded(&profile),
+            "0x00000 4\n\
+             0x00000;0x00014 4\n\
+             0x00000;0x00014;0x0001c 2\n\
+             0x00000;0x00020 4\n\
+             0x00000;0x00020;0x00020 3\n"
+        );
+    }
+
+    #[test]
+    pub fn tail_call_test() {
+        let addi = op(OpName::Addi, 10, 10);
+        let instructions = [
+            jal(RA, 0, 3),
+            jal(RA, 1, 5),
+            jal(0, 2, 7),
+            // f
+            addi,
+            jal(0, 4, 5),
+            // g
+            addi,
+            ret(),
+            addi,
+        ];
+        let profile = profile(&instructions, &[0, 3, 4, 5, 6, 1, 5, 6, 2, 7]);
+
+        // `g` replaces `f` and returns straight to the entry function, while the jump
+        // to a label that is not called stays within the function.
+        assert_eq!(
+            folded(&profile),
+            "0x00000 4\n\
+             0x00000;0x0000c 2\n\
+             0x00000;0x00014 4\n"
+        );
+        let calls = profile
+            .functions()
+            .iter()
+            .find(|p| p.entry == 5)
+            .unwrap()
+            .calls;
+        assert_eq!(calls, 2);
+    }
+
+    #[test]
+    pub fn timing_test() {
+        let code = r#"
+_min_caml_start:
+    jal     ra, f
+    lw      a0, 0(zero)
+    jal     zero, end
+f:
+    lw      a0, 64(zero)
+    addi    a1, a0, 1
+    jalr    zero, ra, 0
+end:
+    addi    a0, zero, 0
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        // With the timing model, the profile accounts for every cycle.
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(mc)
+            .with_timing(TimingConfig::default())
+            .build();
+        sim.start_call_profile();
+        sim.run();
+        let profile = sim.finish_call_profile().unwrap();
+        let cycles = sim.timing.as_ref().unwrap().stat.cycles;
+        assert!(cycles > profile.total().instructions);
+        assert_eq!(profile.total().cycles, cycles);
+    }
+}
@@ -0,0 +1,242 @@
+//! Shadow memory for finding loads of words that were never stored. The simulator
+//! starts with zeroed memory, so such loads often work here and fail on the board.
+
+use std::{collections::HashMap, io};
+
+use qcpu_syntax::ParsingContext;
+use serde::Serialize;
+use strum_macros::{Display, EnumString};
+
+use super::{memory::MEMORY_SIZE, SimulatorV4, SimulatorV4HaltDetail, SimulatorV4HaltKind};
+
+/// What to do on a load from a word that was never written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, EnumString, Display, Serialize)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum UninitCheck {
+    /// Collect the loads for [`ShadowMemory::write_report`].
+    Report,
+    /// Also stop the simulator at the first one.
+    Halt,
+}
+
+/// Loads from never-written words by one instruction.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct UninitRead {
+    pub line: u32,
+    pub count: u64,
+    /// The word of the first such load.
+    pub addr: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct ShadowMemory {
+    pub check: UninitCheck,
+    /// One bit per word of `MemoryV4::m`, set once the word is stored to or loaded as
+    /// initial data.
+    pub(super) written: Vec<u64>,
+    reads: HashMap<u32, UninitRead>,
+    /// The word the current instruction loaded without it being written.
+    pub(super) pending: Option<usize>,
+}
+
+impl ShadowMemory {
+    pub fn new(check: UninitCheck) -> Self {
+        Self {
+            check,
+            written: vec![0; MEMORY_SIZE.div_ceil(64)],
+            reads: HashMap::new(),
+            pending: None,
+        }
+    }
+
+    #[inline(always)]
+    pub fn is_written(&self, addr: usize) -> bool {
+        self.written[addr >> 6] & (1 << (addr & 63)) != 0
+    }
+
+    #[inline(always)]
+    pub fn mark(&mut self, addr: usize) {
+        self.written[addr >> 6] |= 1 << (addr & 63);
+    }
+
+    pub fn mark_all(&mut self) {
+        self.written.fill(u64::MAX);
+    }
+
+    /// Called for every load and store of `addr`.
+    #[inline(always)]
+    pub(super) fn access(&mut self, addr: usize, write: bool) {
+        if write {
+            self.mark(addr);
+        } else if !self.is_written(addr) {
+            self.pending = Some(addr);
+        }
+    }
+
+    /// Instructions that loaded never-written words, in program order.
+    pub fn reads(&self) -> Vec<UninitRead> {
+        let mut reads: Vec<_> = self.reads.values().copied().collect();
+        reads.sort_by_key(|r| r.line);
+        reads
+    }
+
+    pub fn write_report(
+        &self,
+        mut w: impl io::Write,
+        ctx: Option<&ParsingContext>,
+    ) -> io::Result<()> {
+        let reads = self.reads();
+        writeln!(w, "Uninitialized reads: {} instructions", reads.len())?;
+        for read in reads {
+            writeln!(
+                w,
+                "0x{:05x} {:32} {:>10} times, first from 0x{:x}",
+                read.line << 2,
+                ctx.map_or(String::new(), |ctx| ctx
+                    .reverse_lookup_floor(read.line as usize)
+                    .to_string()),
+                read.count,
+                read.addr
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl SimulatorV4<'_> {
+    /// Copies `words` into memory at word address `addr` and marks them as written.
+    /// Panics if they do not fit in the memory.
+    pub fn load_memory(&mut self, addr: usize, words: &[u32]) {
+        assert!(
+            addr.checked_add(words.len())
+                .is_some_and(|end| end <= MEMORY_SIZE),
+            "{} words at 0x{:x} do not fit in memory",
+            words.len(),
+            addr
+        );
+        self.memory.m[addr..addr + words.len()].copy_from_slice(words);
+        if let Some(shadow) = self.memory.shadow.as_mut() {
+            for a in addr..addr + words.len() {
+                shadow.mark(a);
+            }
+        }
+    }
+
+    /// Records the uninitialized load of the instruction at `line`, if it made one.
+    #[inline(always)]
+    pub(super) fn check_uninit_read(&mut self, line: usize) -> Result<(), SimulatorV4HaltDetail> {
+        let Some(shadow) = self.memory.shadow.as_mut() else {
+            return Ok(());
+        };
+        let Some(addr) = shadow.pending.take() else {
+            return Ok(());
+        };
+
+        let read = shadow.reads.entry(line as u32).or_insert(UninitRead {
+            line: line as u32,
+            count: 0,
+            addr,
+        });
+        read.count += 1;
+
+        match shadow.check {
+            UninitCheck::Report => Ok(()),
+            UninitCheck::Halt => Err(self.halt(
+                line,
+                self.op,
+                SimulatorV4HaltKind::UninitializedRead { addr },
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::SimulatorV4Builder;
+
+    #[test]
+    fn access_test() {
+        let mut shadow = ShadowMemory::new(UninitCheck::Report);
+        shadow.access(8, true);
+        shadow.access(8, false);
+        assert_eq!(shadow.pending, None);
+        shadow.access(70, false);
+        assert_eq!(shadow.pending, Some(70));
+        assert!(shadow.is_written(8) && !shadow.is_written(70));
+
+        shadow.mark_all();
+        assert!(shadow.is_written(MEMORY_SIZE - 1));
+    }
+
+    #[test]
+    fn uninit_read_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 7
+    sw      a0, 8(zero)
+    lw      a1, 8(zero)
+    lw      a2, 12(zero)
+    lw      a3, 16(zero)
+    addi    a4, zero, 2
+loop:
+    lw      a5, 20(zero)
+    addi    a4, a4, -1
+    blt     zero, a4, loop
+        "#;
+        let (mc, ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+        let build = |check| {
+            SimulatorV4Builder::default()
+                .with_program(mc.clone())
+                .with_context(ctx.clone())
+                .with_uninit_check(check)
+                .build()
+        };
+
+        let mut sim = build(UninitCheck::Report);
+        sim.load_memory(16, &[5]);
+        assert_eq!(sim.run().kind, SimulatorV4HaltKind::Complete);
+        assert_eq!(sim.reg[13], 5);
+        let shadow = sim.memory.shadow.as_ref().unwrap();
+        let reads: Vec<_> = shadow
+            .reads()
+            .iter()
+            .map(|r| (r.line, r.count, r.addr))
+            .collect();
+        assert_eq!(reads, [(3, 1, 12), (6, 2, 20)]);
+
+        let mut report = Vec::new();
+        shadow.write_report(&mut report, None).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("Uninitialized reads: 2 instructions\n"));
+        assert!(report.contains("2 times, first from 0x14"), "{}", report);
+
+        let mut sim = build(UninitCheck::Halt);
+        sim.load_memory(16, &[5]);
+        let halt = sim.run();
+        assert_eq!(
+            halt.kind,
+            SimulatorV4HaltKind::UninitializedRead { addr: 12 }
+        );
+        assert_eq!(
+            (halt.pc, halt.label.as_deref()),
+            (12, Some("_min_caml_start"))
+        );
+        assert_eq!(halt.kind.exit_code(), 14);
+        let halt = sim.run();
+        assert_eq!(
+            (halt.kind, halt.pc),
+            (SimulatorV4HaltKind::UninitializedRead { addr: 20 }, 24)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "do not fit in memory")]
+    fn load_memory_out_of_bounds_test() {
+        let mut sim = SimulatorV4Builder::default()
+            .with_program(Vec::new())
+            .build();
+        sim.load_memory(MEMORY_SIZE - 1, &[1, 2]);
+    }
+}
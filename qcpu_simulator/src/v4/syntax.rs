@@ -1,6 +1,7 @@
 use std::fmt::{Debug, Display};
 
 use serde::{ser::SerializeStruct, Serialize};
+use strum_macros::FromRepr;
 
 pub type Reg = u8;
 
@@ -151,7 +152,7 @@ pub const FCMP_FUNC7: u32 = 0b1010000;
 pub const FSGN_FUNC7: u32 = 0b0010000;
 
 #[repr(u8)]
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, FromRepr)]
 pub enum OpName {
     #[default]
     Raw,
@@ -326,3 +327,38 @@ impl Debug for OpV4 {
         }
     }
 }
+
+impl Display for OpV4 {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (rd, rs1, rs2) = (
+            get_reg_name(self.rd),
+            get_reg_name(self.rs1),
+            get_reg_name(self.rs2),
+        );
+        let imm = self.imm as i32;
+
+        match self.opname {
+            OpName::Addi | OpName::Slli | OpName::Srli | OpName::Jalr => {
+                write!(f, "{} {}, {}, {}", self.opname, rd, rs1, imm)
+            }
+            OpName::Lw => write!(f, "{} {}, {}({})", self.opname, rd, imm, rs1),
+            OpName::Sw => write!(f, "{} {}, {}({})", self.opname, rs2, imm, rs1),
+            #[cfg(feature = "full_ops")]
+            OpName::Lwi => write!(f, "{} {}, {}", self.opname, rd, imm),
+            #[cfg(feature = "full_ops")]
+            OpName::Swi => write!(f, "{} {}, {}", self.opname, rs2, imm),
+            OpName::Beq | OpName::Bne | OpName::Blt | OpName::Bge => {
+                write!(f, "{} {}, {}, {}", self.opname, rs1, rs2, imm)
+            }
+            OpName::Jal => write!(f, "{} {}, {}", self.opname, rd, imm),
+            OpName::Lui => write!(f, "{} {}, 0x{:08x}", self.opname, rd, self.imm),
+            OpName::Inw => write!(f, "{} {}", self.opname, rd),
+            OpName::Outb => write!(f, "{} {}", self.opname, rs2),
+            OpName::Fsqrt | OpName::Ftoi | OpName::Fitof => {
+                write!(f, "{} {}, {}", self.opname, rd, rs1)
+            }
+            OpName::Raw => write!(f, "{}", self.opname),
+            _ => write!(f, "{} {}, {}, {}", self.opname, rd, rs1, rs2),
+        }
+    }
+}
@@ -0,0 +1,493 @@
+//! An optional timing model that runs inside the main loop: an in-order, single-issue
+//! pipeline with a register scoreboard. Unlike `tally`, which works from aggregate
+//! per-instruction counts afterwards, it sees the dynamic instruction stream.
+
+use std::{fmt::Display, str::FromStr};
+
+use serde::Serialize;
+
+use super::{
+    machine::Machine,
+    memory::{CacheConfig, CacheStat},
+    syntax::{OpName, OpV4},
+    SimulatorV4,
+};
+
+/// The modeled pipeline. Results are forwarded to the next instruction as soon as they
+/// are computed; a disabled forwarding path makes consumers wait `regfile_delay` more
+/// cycles for the register file instead. Latencies and penalties come from the
+/// [`Machine`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct TimingConfig {
+    pub forward_alu: bool,
+    pub forward_load: bool,
+    pub forward_fpu: bool,
+    pub regfile_delay: u64,
+    /// Whether hazard detection compares the register type. Without it only the low 5
+    /// bits are compared, so `x5` and `f5` depend on each other.
+    pub type_check: bool,
+}
+
+impl Default for TimingConfig {
+    fn default() -> Self {
+        Self {
+            forward_alu: true,
+            forward_load: true,
+            forward_fpu: true,
+            regfile_delay: 2,
+            type_check: true,
+        }
+    }
+}
+
+/// Parses comma-separated settings: `forward=` (`all`, `none` or a `+`-separated list of
+/// `alu`, `load` and `fpu`), `regfile-delay=` and `type-check=` (`true` or `false`), e.g.
+/// `forward=alu+fpu,type-check=false`. Settings that are not mentioned keep their
+/// default.
+impl FromStr for TimingConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = TimingConfig::default();
+
+        for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Expected <key>=<value>: {}", part))?;
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = || format!("Invalid {}: {}", key, value);
+
+            match key {
+                "forward" => {
+                    let paths: Vec<_> = match value {
+                        "all" => vec!["alu", "load", "fpu"],
+                        "none" => vec![],
+                        _ => value.split('+').map(str::trim).collect(),
+                    };
+                    config.forward_alu = false;
+                    config.forward_load = false;
+                    config.forward_fpu = false;
+                    for path in paths {
+                        match path {
+                            "alu" => config.forward_alu = true,
+                            "load" => config.forward_load = true,
+                            "fpu" => config.forward_fpu = true,
+                            _ => return Err(format!("Unknown forwarding path: {}", path)),
+                        }
+                    }
+                }
+                "regfile-delay" => config.regfile_delay = value.parse().map_err(|_| invalid())?,
+                "type-check" => config.type_check = value.parse().map_err(|_| invalid())?,
+                _ => return Err(format!("Unknown timing setting: {}", key)),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// Where a register's pending value comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Producer {
+    Alu,
+    Load,
+    Fpu,
+}
+
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct TimingStat {
+    pub cycles: u64,
+    pub instructions: u64,
+    /// Instructions that waited for an operand.
+    pub hazards: u64,
+    /// Cycles spent waiting for operands, by producer.
+    pub alu_stall: u64,
+    pub load_stall: u64,
+    pub fpu_stall: u64,
+    /// The part of the operand stalls caused by disabled forwarding paths.
+    pub forwarding_stall: u64,
+    /// The part of the operand stalls caused by the missing register type check.
+    pub false_dependency_stall: u64,
+    /// Cache fills, write-backs, written-through words and first misses.
+    pub memory_stall: u64,
+    pub input_stall: u64,
+    pub branch_stall: u64,
+}
+
+impl Display for TimingStat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let percent = |n: u64| n as f64 / self.cycles as f64 * 100.0;
+
+        writeln!(
+            f,
+            "Cycles: {} (CPI {:.03})",
+            self.cycles,
+            self.cycles as f64 / self.instructions as f64
+        )?;
+        writeln!(f, "Hazards: {}", self.hazards)?;
+        for (name, n) in [
+            ("ALU operand stall", self.alu_stall),
+            ("Load-use stall", self.load_stall),
+            ("FPU operand stall", self.fpu_stall),
+            ("  of which forwarding", self.forwarding_stall),
+            ("  of which false dependencies", self.false_dependency_stall),
+            ("Memory stall", self.memory_stall),
+            ("Input stall", self.input_stall),
+            ("Branch stall", self.branch_stall),
+        ] {
+            writeln!(f, "{}: {} ({:.02}% of cycles)", name, n, percent(n))?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Timing {
+    pub config: TimingConfig,
+    pub machine: Machine,
+    pub stat: TimingStat,
+    /// The earliest cycle the next instruction can issue.
+    next_issue: u64,
+    /// Per register: the cycle its pending value can be read, and the same with every
+    /// forwarding path enabled.
+    ready: [u64; 64],
+    ready_forwarded: [u64; 64],
+    producer: [Producer; 64],
+    /// `CacheStat` counters at the previous instruction.
+    first_miss: u64,
+    write_back: u64,
+    memory_read: u64,
+    memory_write: u64,
+}
+
+impl Timing {
+    pub fn new(config: TimingConfig, machine: Machine) -> Self {
+        Self {
+            config,
+            machine,
+            stat: TimingStat::default(),
+            next_issue: 0,
+            ready: [0; 64],
+            ready_forwarded: [0; 64],
+            producer: [Producer::Alu; 64],
+            first_miss: 0,
+            write_back: 0,
+            memory_read: 0,
+            memory_write: 0,
+        }
+    }
+
+    /// The cycle `reg` can be read, optionally ignoring the register type and disabled
+    /// forwarding paths.
+    fn operand_ready(&self, reg: u8, type_check: bool, forwarded: bool) -> u64 {
+        if reg == 0 {
+            return 0;
+        }
+
+        let ready = if forwarded {
+            &self.ready_forwarded
+        } else {
+            &self.ready
+        };
+        let own = ready[reg as usize];
+        if type_check {
+            own
+        } else {
+            own.max(ready[(reg ^ 32) as usize])
+        }
+    }
+
+    /// Cycles the memory access that just happened stalls the pipeline, from the change
+    /// in the cache counters.
+    fn memory_stall(&mut self, stat: &CacheStat, config: &CacheConfig) -> u64 {
+        let first_miss = stat.first_miss - self.first_miss;
+        let write_back = stat.write_back - self.write_back;
+        let fill = stat.memory_read != self.memory_read;
+        let written_through =
+            stat.memory_write - self.memory_write - write_back * config.line_words as u64;
+
+        self.first_miss = stat.first_miss;
+        self.write_back = stat.write_back;
+        self.memory_read = stat.memory_read;
+        self.memory_write = stat.memory_write;
+
+        let machine = &self.machine;
+        let stall =
+            fill as u64 * machine.cache_miss_penalty + first_miss * machine.first_miss_penalty;
+        if config.hardware_write_policy() {
+            return stall;
+        }
+
+        stall
+            + write_back * machine.write_back_penalty
+            + written_through * machine.write_through_penalty
+    }
+
+    /// The cycle all of `op`'s operands can be read.
+    fn operands_ready(&self, op: &OpV4, type_check: bool, forwarded: bool) -> u64 {
+        self.operand_ready(op.rs1, type_check, forwarded)
+            .max(self.operand_ready(op.rs2, type_check, forwarded))
+    }
+
+    /// Who produces the value `reg` waits for at `limit`.
+    fn producer_of(&self, reg: u8, limit: u64) -> Producer {
+        if self.config.type_check || self.ready[reg as usize] >= limit {
+            self.producer[reg as usize]
+        } else {
+            self.producer[(reg ^ 32) as usize]
+        }
+    }
+
+    /// Issues `op`. `mispredicted` is set for branches and `jalr`s the predictor missed.
+    pub fn issue(
+        &mut self,
+        op: &OpV4,
+        mispredicted: bool,
+        cache_stat: &CacheStat,
+        cache_config: &CacheConfig,
+    ) {
+        let config = self.config;
+        self.stat.instructions += 1;
+
+        let earliest = self.next_issue;
+        let (limit, reg) = [op.rs1, op.rs2]
+            .into_iter()
+            .map(|r| (self.operand_ready(r, config.type_check, false), r))
+            .max()
+            .unwrap_or_default();
+
+        let issue = earliest.max(limit);
+        if issue > earliest {
+            let stall = issue - earliest;
+            let typed = self
+                .operands_ready(op, true, false)
+                .saturating_sub(earliest);
+            let forwarded = self
+                .operands_ready(op, true, true)
+                .saturating_sub(earliest)
+                .min(typed);
+            let producer = self.producer_of(reg, limit);
+
+            let stat = &mut self.stat;
+            stat.hazards += 1;
+            stat.false_dependency_stall += stall - typed;
+            stat.forwarding_stall += typed - forwarded;
+            match producer {
+                Producer::Alu => stat.alu_stall += stall,
+                Producer::Load => stat.load_stall += stall,
+                Producer::Fpu => stat.fpu_stall += stall,
+            }
+        }
+
+        let mut memory = 0;
+        let mut input = 0;
+        let (producer, latency) = match op.opname {
+            #[cfg(feature = "full_ops")]
+            OpName::Lw | OpName::Lwr | OpName::Lwi => {
+                memory = self.memory_stall(cache_stat, cache_config);
+                (Producer::Load, self.machine.cache_hit_penalty)
+            }
+            #[cfg(not(feature = "full_ops"))]
+            OpName::Lw | OpName::Lwr => {
+                memory = self.memory_stall(cache_stat, cache_config);
+                (Producer::Load, self.machine.cache_hit_penalty)
+            }
+            #[cfg(feature = "full_ops")]
+            OpName::Sw | OpName::Swi => {
+                memory = self.memory_stall(cache_stat, cache_config);
+                (Producer::Alu, 1)
+            }
+            #[cfg(not(feature = "full_ops"))]
+            OpName::Sw => {
+                memory = self.memory_stall(cache_stat, cache_config);
+                (Producer::Alu, 1)
+            }
+            OpName::Inw => {
+                input = self.machine.inw_delay;
+                (Producer::Alu, 1)
+            }
+            opname if self.machine.delay(opname) > 1 => (Producer::Fpu, self.machine.delay(opname)),
+            _ => (Producer::Alu, 1),
+        };
+        let branch = if mispredicted {
+            self.machine.branch_penalty
+        } else {
+            0
+        };
+        let blocked = memory + input;
+
+        if op.rd != 0 {
+            let forwarded = match producer {
+                Producer::Alu => config.forward_alu,
+                Producer::Load => config.forward_load,
+                Producer::Fpu => config.forward_fpu,
+            };
+            let ready = issue + blocked + latency;
+            let rd = op.rd as usize;
+            self.ready_forwarded[rd] = ready;
+            self.ready[rd] = if forwarded {
+                ready
+            } else {
+                ready + config.regfile_delay
+            };
+            self.producer[rd] = producer;
+        }
+
+        self.next_issue = issue + 1 + blocked + branch;
+
+        let stat = &mut self.stat;
+        stat.memory_stall += memory;
+        stat.input_stall += input;
+        stat.branch_stall += branch;
+        stat.cycles = self.next_issue;
+    }
+}
+
+impl SimulatorV4<'_> {
+    #[inline(always)]
+    pub(super) fn update_timing(&mut self, mispredicted: bool) {
+        if let Some(timing) = self.timing.as_mut() {
+            timing.issue(
+                &self.op,
+                mispredicted,
+                &self.memory.stat,
+                &self.memory.config,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::v4::log::{CACHE_HIT_PENALTY, CACHE_MISS_PENALTY, FIRST_MISS_PENALTY};
+
+    const A0: u8 = 10;
+    const A1: u8 = 11;
+    const FA0: u8 = 32 + A0;
+    const FA1: u8 = 32 + A1;
+    const FA2: u8 = 32 + 12;
+
+    fn op(opname: OpName, rd: u8, rs1: u8, rs2: u8) -> OpV4 {
+        OpV4 {
+            opname,
+            rd,
+            rs1,
+            rs2,
+            ..Default::default()
+        }
+    }
+
+    /// Issues `ops` as correctly predicted cache hits.
+    fn run_on(ops: &[OpV4], config: &str, machine: Machine) -> TimingStat {
+        let mut timing = Timing::new(config.parse().unwrap(), machine);
+        for op in ops {
+            timing.issue(op, false, &CacheStat::default(), &CacheConfig::default());
+        }
+        timing.stat
+    }
+
+    fn run(ops: &[OpV4], config: &str) -> TimingStat {
+        run_on(ops, config, Machine::default())
+    }
+
+    #[test]
+    pub fn operand_stall_test() {
+        let independent = run(
+            &[op(OpName::Addi, A0, 0, 0), op(OpName::Addi, A1, 0, 0)],
+            "",
+        );
+        assert_eq!((independent.cycles, independent.hazards), (2, 0));
+
+        // ALU results are forwarded unless the path is disabled.
+        let alu = [op(OpName::Addi, A0, 0, 0), op(OpName::Addi, A1, A0, 0)];
+        assert_eq!(run(&alu, "").cycles, 2);
+        let unforwarded = run(&alu, "forward=load+fpu,regfile-delay=2");
+        assert_eq!(
+            (
+                unforwarded.cycles,
+                unforwarded.alu_stall,
+                unforwarded.forwarding_stall
+            ),
+            (4, 2, 2)
+        );
+
+        // FADD takes 4 cycles.
+        let fadd = [
+            op(OpName::Fadd, FA0, FA1, FA2),
+            op(OpName::Fadd, FA1, FA0, FA0),
+        ];
+        let fpu = run(&fadd, "");
+        assert_eq!((fpu.cycles, fpu.hazards, fpu.fpu_stall), (5, 1, 3));
+
+        let machine: Machine = serde_json::from_str(r#"{ "latency": { "fadd": 6 } }"#).unwrap();
+        let fpu = run_on(&fadd, "", machine);
+        assert_eq!((fpu.cycles, fpu.fpu_stall), (7, 5));
+
+        // Without the type check, a0 waits for fa0.
+        let hybrid = [op(OpName::Fadd, FA0, FA1, FA2), op(OpName::Addi, A1, A0, 0)];
+        assert_eq!(run(&hybrid, "").cycles, 2);
+        let hybrid = run(&hybrid, "type-check=false");
+        assert_eq!((hybrid.cycles, hybrid.false_dependency_stall), (5, 3));
+    }
+
+    #[test]
+    pub fn load_test() {
+        let miss = CacheStat {
+            first_miss: 1,
+            memory_read: 4,
+            ..Default::default()
+        };
+        let run = |consumer: OpV4| {
+            let mut timing = Timing::new(TimingConfig::default(), Machine::default());
+            timing.issue(
+                &op(OpName::Lw, A0, 0, 0),
+                false,
+                &miss,
+                &CacheConfig::default(),
+            );
+            timing.issue(&consumer, false, &miss, &CacheConfig::default());
+            timing.stat
+        };
+
+        // A load result arrives CACHE_HIT_PENALTY cycles after its memory stall.
+        let load_use = run(op(OpName::Addi, A1, A0, 0));
+        let load = run(op(OpName::Addi, A1, 0, 0));
+        assert_eq!(load_use.load_stall, CACHE_HIT_PENALTY - 1);
+        assert_eq!(load_use.cycles, load.cycles + CACHE_HIT_PENALTY - 1);
+        assert_eq!(load.memory_stall, CACHE_MISS_PENALTY + FIRST_MISS_PENALTY);
+        assert_eq!(load.cycles, 2 + load.memory_stall);
+    }
+
+    #[test]
+    pub fn branch_test() {
+        let mut timing = Timing::new(
+            TimingConfig::default(),
+            Machine {
+                branch_penalty: 3,
+                ..Default::default()
+            },
+        );
+        let bne = op(OpName::Bne, 0, A0, 0);
+        timing.issue(&bne, false, &CacheStat::default(), &CacheConfig::default());
+        timing.issue(&bne, true, &CacheStat::default(), &CacheConfig::default());
+        timing.issue(&bne, false, &CacheStat::default(), &CacheConfig::default());
+        assert_eq!((timing.stat.cycles, timing.stat.branch_stall), (6, 3));
+    }
+
+    #[test]
+    pub fn config_test() {
+        assert_eq!(
+            "forward=alu+fpu,type-check=false"
+                .parse::<TimingConfig>()
+                .unwrap(),
+            TimingConfig {
+                forward_load: false,
+                type_check: false,
+                ..Default::default()
+            }
+        );
+        assert!("forward=mul".parse::<TimingConfig>().is_err());
+        assert!("branch-penalty=3".parse::<TimingConfig>().is_err());
+    }
+}
@@ -0,0 +1,416 @@
+use std::{
+    io::{self, BufWriter, Read, Write},
+    ops::Range,
+};
+
+use qcpu_syntax::ParsingContext;
+
+use super::{
+    memory::MemoryAccessRecord,
+    syntax::{OpName, OpV4},
+    SimulatorV4,
+};
+
+pub const TRACE_MAGIC: &[u8; 4] = b"QTRC";
+const TRACE_VERSION: u8 = 1;
+
+const FLAG_READ: u8 = 1;
+const FLAG_WRITE: u8 = 2;
+const FLAG_INPUT: u8 = 4;
+const FLAG_OUTPUT: u8 = 8;
+
+/// Header of a record: index, pc, imm, rd value, opname, rd, rs1, rs2, flags.
+const RECORD_HEAD: usize = 8 + 4 + 4 + 4 + 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceAccess {
+    Read { addr: u32, value: u32 },
+    Write { addr: u32, value: u32 },
+    Input(u32),
+    Output(u8),
+}
+
+impl From<MemoryAccessRecord> for TraceAccess {
+    fn from(r: MemoryAccessRecord) -> Self {
+        if r.write {
+            TraceAccess::Write {
+                addr: r.addr as u32,
+                value: r.value,
+            }
+        } else {
+            TraceAccess::Read {
+                addr: r.addr as u32,
+                value: r.value,
+            }
+        }
+    }
+}
+
+/// One retired instruction. `index` counts retired instructions since tracing started,
+/// including the ones the filter skipped.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceRecord {
+    pub index: u64,
+    pub pc: u32,
+    pub op: OpV4,
+    pub rd_value: u32,
+    pub access: Option<TraceAccess>,
+}
+
+/// Selects which instructions get recorded. An empty `pc_ranges` matches every PC.
+#[derive(Debug, Default, Clone)]
+pub struct TraceFilter {
+    pub pc_ranges: Vec<Range<u32>>,
+    pub window: Option<Range<u64>>,
+}
+
+impl TraceFilter {
+    /// Adds the PCs from `label` up to the next label and returns them, or `None` if the
+    /// label is unknown.
+    pub fn add_label(&mut self, ctx: &ParsingContext, label: &str) -> Option<Range<u32>> {
+        let start = *ctx.label_map.get(label)?;
+        let end = ctx
+            .label_map
+            .values()
+            .copied()
+            .filter(|&i| i > start)
+            .min()
+            .map_or(u32::MAX, |i| (i as u32) << 2);
+        let range = (start as u32) << 2..end;
+        self.pc_ranges.push(range.clone());
+        Some(range)
+    }
+
+    #[inline(always)]
+    pub fn matches(&self, index: u64, pc: u32) -> bool {
+        self.window.as_ref().is_none_or(|w| w.contains(&index))
+            && (self.pc_ranges.is_empty() || self.pc_ranges.iter().any(|r| r.contains(&pc)))
+    }
+}
+
+pub struct TraceWriter<'a> {
+    writer: BufWriter<Box<dyn Write + 'a>>,
+    pub filter: TraceFilter,
+    retired: u64,
+    /// The first write error. Recording stops there and [`Self::finish`] returns it.
+    error: Option<io::Error>,
+}
+
+impl<'a> TraceWriter<'a> {
+    pub fn new(writer: impl Write + 'a, filter: TraceFilter) -> io::Result<Self> {
+        let mut writer = BufWriter::new(Box::new(writer) as Box<dyn Write + 'a>);
+        writer.write_all(TRACE_MAGIC)?;
+        writer.write_all(&[TRACE_VERSION, cfg!(feature = "full_ops") as u8])?;
+
+        Ok(Self {
+            writer,
+            filter,
+            retired: 0,
+            error: None,
+        })
+    }
+
+    #[inline(always)]
+    pub fn record(&mut self, pc: u32, op: OpV4, rd_value: u32, access: Option<TraceAccess>) {
+        let index = self.retired;
+        self.retired += 1;
+
+        if self.error.is_some() || !self.filter.matches(index, pc) {
+            return;
+        }
+
+        let (flags, addr, value) = match access {
+            None => (0, 0, 0),
+            Some(TraceAccess::Read { addr, value }) => (FLAG_READ, addr, value),
+            Some(TraceAccess::Write { addr, value }) => (FLAG_WRITE, addr, value),
+            Some(TraceAccess::Input(value)) => (FLAG_INPUT, 0, value),
+            Some(TraceAccess::Output(byte)) => (FLAG_OUTPUT, 0, byte as u32),
+        };
+
+        let mut buf = [0u8; RECORD_HEAD + 8];
+        buf[0..8].copy_from_slice(&index.to_le_bytes());
+        buf[8..12].copy_from_slice(&pc.to_le_bytes());
+        buf[12..16].copy_from_slice(&op.imm.to_le_bytes());
+        buf[16..20].copy_from_slice(&rd_value.to_le_bytes());
+        buf[20..25].copy_from_slice(&[op.opname as u8, op.rd, op.rs1, op.rs2, flags]);
+        buf[25..29].copy_from_slice(&addr.to_le_bytes());
+        buf[29..33].copy_from_slice(&value.to_le_bytes());
+
+        let len = if flags == 0 { RECORD_HEAD } else { buf.len() };
+        if let Err(e) = self.writer.write_all(&buf[..len]) {
+            self.error = Some(e);
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(e) => Err(e),
+            None => self.writer.flush(),
+        }
+    }
+}
+
+pub struct TraceReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TraceReader<R> {
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0u8; 6];
+        reader.read_exact(&mut header)?;
+
+        if &header[0..4] != TRACE_MAGIC || header[4] != TRACE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a qcpu trace file",
+            ));
+        }
+
+        if header[5] != cfg!(feature = "full_ops") as u8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Trace was recorded with a different `full_ops` setting",
+            ));
+        }
+
+        Ok(Self { reader })
+    }
+
+    fn read_record(&mut self) -> io::Result<Option<TraceRecord>> {
+        let mut buf = [0u8; RECORD_HEAD + 8];
+
+        // Only running out of data between records is the end of the trace.
+        let first = loop {
+            match self.reader.read(&mut buf[..1]) {
+                Ok(n) => break n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        };
+        if first == 0 {
+            return Ok(None);
+        }
+        self.read_rest(&mut buf[1..RECORD_HEAD])?;
+
+        let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+        let flags = buf[24];
+
+        let opname = OpName::from_repr(buf[20])
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Unknown opname"))?;
+
+        // The `debug` feature adds fields that are not part of the trace.
+        #[allow(clippy::needless_update)]
+        let op = OpV4 {
+            imm: word(12),
+            opname,
+            rd: buf[21],
+            rs1: buf[22],
+            rs2: buf[23],
+            ..Default::default()
+        };
+
+        let index = u64::from_le_bytes(buf[0..8].try_into().unwrap());
+        let pc = word(8);
+        let rd_value = word(16);
+
+        let access = if flags == 0 {
+            None
+        } else {
+            self.read_rest(&mut buf[RECORD_HEAD..])?;
+            let word = |i: usize| u32::from_le_bytes([buf[i], buf[i + 1], buf[i + 2], buf[i + 3]]);
+            let (addr, value) = (word(25), word(29));
+            Some(match flags {
+                FLAG_READ => TraceAccess::Read { addr, value },
+                FLAG_WRITE => TraceAccess::Write { addr, value },
+                FLAG_INPUT => TraceAccess::Input(value),
+                FLAG_OUTPUT => TraceAccess::Output(value as u8),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unknown access flags",
+                    ))
+                }
+            })
+        };
+
+        Ok(Some(TraceRecord {
+            index,
+            pc,
+            op,
+            rd_value,
+            access,
+        }))
+    }
+
+    fn read_rest(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(buf).map_err(|e| {
+            if e.kind() == io::ErrorKind::UnexpectedEof {
+                io::Error::new(e.kind(), "Trace ends in the middle of a record")
+            } else {
+                e
+            }
+        })
+    }
+}
+
+impl<R: Read> Iterator for TraceReader<R> {
+    type Item = io::Result<TraceRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_record().transpose()
+    }
+}
+
+impl<'a> SimulatorV4<'a> {
+    /// Records every retired instruction that passes `filter` to `writer` from now on.
+    pub fn start_trace(&mut self, writer: impl Write + 'a, filter: TraceFilter) -> io::Result<()> {
+        self.trace = Some(TraceWriter::new(writer, filter)?);
+        self.update_observing();
+        Ok(())
+    }
+
+    /// Stops tracing and flushes the trace. Returns the first error writing it, if
+    /// recording failed earlier.
+    pub fn finish_trace(&mut self) -> io::Result<()> {
+        let trace = self.trace.take();
+        self.update_observing();
+        trace.map_or(Ok(()), TraceWriter::finish)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::{SimulatorV4Builder, SimulatorV4HaltKind};
+    use super::*;
+
+    fn read_all(data: &[u8]) -> Vec<TraceRecord> {
+        TraceReader::new(data)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap()
+    }
+
+    #[test]
+    pub fn trace_test() {
+        let code = r#"
+_min_caml_start:
+    inw     a0
+    sw      a0, 16(zero)
+    lw      a1, 16(zero)
+    addi    a1, a1, 1
+    outb    a1
+        "#;
+        let (mc, _ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let input = (b'A' as u32).to_le_bytes();
+        let mut trace = Vec::new();
+        {
+            let mut sim = SimulatorV4Builder::default()
+                .with_program(mc)
+                .with_input(input.as_slice())
+                .build();
+            sim.start_trace(&mut trace, TraceFilter::default()).unwrap();
+            assert!(matches!(sim.run().kind, SimulatorV4HaltKind::Complete));
+            sim.finish_trace().unwrap();
+        }
+
+        let records = read_all(&trace);
+        let accesses = records.iter().map(|r| r.access).collect::<Vec<_>>();
+        assert_eq!(
+            accesses,
+            [
+                Some(TraceAccess::Input(65)),
+                Some(TraceAccess::Write {
+                    addr: 16,
+                    value: 65
+                }),
+                Some(TraceAccess::Read {
+                    addr: 16,
+                    value: 65
+                }),
+                None,
+                Some(TraceAccess::Output(66)),
+            ]
+        );
+        assert_eq!((records[3].pc, records[3].rd_value), (12, 66));
+    }
+
+    #[test]
+    pub fn filter_test() {
+        let code = r#"
+_min_caml_start:
+    addi    a0, zero, 1
+    addi    a0, a0, 1
+loop:
+    addi    a0, a0, 1
+    addi    a0, a0, 1
+done:
+    addi    a0, a0, 1
+        "#;
+        let (_mc, ctx) = qcpu_assembler::v2::assemble(code, false).unwrap();
+
+        let mut filter = TraceFilter::default();
+        assert_eq!(filter.add_label(&ctx, "loop"), Some(8..16));
+        assert_eq!(filter.add_label(&ctx, "nowhere"), None);
+        filter.window = Some(0..3);
+
+        let mut data = Vec::new();
+        let mut writer = TraceWriter::new(&mut data, filter).unwrap();
+        for pc in (0..20).step_by(4) {
+            writer.record(pc, OpV4::default(), pc, None);
+        }
+        writer.finish().unwrap();
+
+        let records = read_all(&data);
+        assert_eq!(records.len(), 1);
+        assert_eq!((records[0].index, records[0].pc), (2, 8));
+    }
+
+    #[test]
+    pub fn write_error_test() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                Err(io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // Enough records to fill the write buffer.
+        let mut writer = TraceWriter::new(Broken, TraceFilter::default()).unwrap();
+        for pc in 0..1000 {
+            writer.record(pc << 2, OpV4::default(), 0, None);
+        }
+        assert_eq!(
+            writer.finish().unwrap_err().kind(),
+            io::ErrorKind::BrokenPipe
+        );
+    }
+
+    #[test]
+    pub fn truncated_trace_test() {
+        let op = OpV4 {
+            opname: OpName::Sw,
+            ..Default::default()
+        };
+        let mut data = vec![];
+        let mut writer = TraceWriter::new(&mut data, TraceFilter::default()).unwrap();
+        writer.record(0, op, 0, None);
+        writer.record(4, op, 0, Some(TraceAccess::Write { addr: 8, value: 1 }));
+        writer.finish().unwrap();
+
+        let records = TraceReader::new(&data[..]).unwrap();
+        assert_eq!(records.map(Result::unwrap).count(), 2);
+
+        // Cut inside the access of the second record, then inside its header.
+        for len in [data.len() - 1, 6 + RECORD_HEAD + 1] {
+            let mut records = TraceReader::new(&data[..len]).unwrap();
+            assert!(records.next().unwrap().is_ok());
+            let err = records.next().unwrap().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+            assert!(records.next().is_none());
+        }
+    }
+}
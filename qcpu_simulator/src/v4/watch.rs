@@ -0,0 +1,126 @@
+use std::ops::Range;
+
+use qcpu_syntax::ParsingContext;
+
+use super::{
+    memory::MemoryWatch,
+    syntax::{OpName, Reg},
+    trace::TraceAccess,
+    SimulatorV4, SimulatorV4HaltDetail, SimulatorV4HaltKind,
+};
+
+impl SimulatorV4<'_> {
+    pub fn add_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.insert(pc);
+        self.update_observing();
+    }
+
+    /// Sets a breakpoint on `label` and returns its PC, or `None` if the label is unknown.
+    pub fn add_label_breakpoint(&mut self, ctx: &ParsingContext, label: &str) -> Option<u32> {
+        let pc = (*ctx.label_map.get(label)? as u32) << 2;
+        self.add_breakpoint(pc);
+        Some(pc)
+    }
+
+    pub fn remove_breakpoint(&mut self, pc: u32) -> bool {
+        let removed = self.breakpoints.remove(&pc);
+        self.update_observing();
+        removed
+    }
+
+    pub fn watch_memory(&mut self, range: Range<usize>, read: bool, write: bool) {
+        self.memory.watches.push(MemoryWatch { range, read, write });
+        self.update_observing();
+    }
+
+    pub fn watch_register(&mut self, reg: Reg) {
+        self.watched_registers |= 1 << reg;
+        self.update_observing();
+    }
+
+    pub fn unwatch_register(&mut self, reg: Reg) {
+        self.watched_registers &= !(1 << reg);
+        self.update_observing();
+    }
+
+    pub fn clear_watches(&mut self) {
+        self.breakpoints.clear();
+        self.memory.watches.clear();
+        self.memory.watch_hit = None;
+        self.memory.last_access = None;
+        self.watched_registers = 0;
+        self.update_observing();
+    }
+
+    pub(super) fn update_observing(&mut self) {
+        self.memory.observed =
+            self.trace.is_some() || !self.memory.watches.is_empty() || self.memory.shadow.is_some();
+        self.observing = self.memory.observed
+            || !self.breakpoints.is_empty()
+            || self.watched_registers != 0
+            || self.fpu_accuracy.is_some();
+    }
+
+    /// Checked before executing the instruction at the current PC. After a breakpoint
+    /// stops the simulator, the next step at the same PC goes through so it can resume.
+    #[inline(always)]
+    pub(super) fn hit_breakpoint(&mut self) -> bool {
+        let resuming = self.skip_breakpoint.take() == Some(self.pc);
+
+        if !resuming && self.breakpoints.contains(&self.pc) {
+            self.skip_breakpoint = Some(self.pc);
+            return true;
+        }
+
+        false
+    }
+
+    /// Checked after executing `self.op`, whose destination held `rd_before`.
+    #[inline(always)]
+    pub(super) fn observe(
+        &mut self,
+        line: usize,
+        rd_before: u32,
+    ) -> Result<(), SimulatorV4HaltDetail> {
+        let access = self.memory.last_access.take();
+        if let Some(trace) = self.trace.as_mut() {
+            let rd_value = self.reg[self.op.rd as usize];
+            let access = match self.op.opname {
+                OpName::Inw => Some(TraceAccess::Input(rd_value)),
+                OpName::Outb => Some(TraceAccess::Output(self.reg[self.op.rs2 as usize] as u8)),
+                _ => access.map(TraceAccess::from),
+            };
+            trace.record((line as u32) << 2, self.op, rd_value, access);
+        }
+
+        let uninit = self.check_uninit_read(line);
+
+        if let Some(hit) = self.memory.watch_hit.take() {
+            return Err(self.halt(
+                line,
+                self.op,
+                SimulatorV4HaltKind::MemoryWatch {
+                    addr: hit.addr,
+                    value: hit.value,
+                    write: hit.write,
+                },
+            ));
+        }
+
+        let rd = self.op.rd;
+        let rd_after = self.get_reg(rd);
+        if self.watched_registers & (1 << rd) != 0 && rd_after != rd_before {
+            return Err(self.halt(
+                line,
+                self.op,
+                SimulatorV4HaltKind::RegisterWatch {
+                    reg: rd,
+                    old: rd_before,
+                    new: rd_after,
+                },
+            ));
+        }
+
+        uninit
+    }
+}
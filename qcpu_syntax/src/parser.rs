@@ -148,7 +148,7 @@ impl JumpTarget {
         self.offset().or_else(|| {
             ctx.label_map
                 .get(self.label.as_ref().unwrap())
-                .map(|x| (*x as i32))
+                .map(|x| *x as i32)
         })
     }
     pub fn parse(input: &str) -> IResult<&str, Self> {
@@ -169,7 +169,7 @@ impl std::fmt::Display for JumpTarget {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ParsingContext {
     pub label_map: LabelMap,
     pub main_label: String,
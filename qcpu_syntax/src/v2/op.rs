@@ -73,7 +73,7 @@ impl Immediate {
         self.raw().or_else(|| {
             ctx.label_map
                 .get(self.label.as_ref().unwrap())
-                .map(|x| (*x as i32))
+                .map(|x| *x as i32)
         })
     }
 